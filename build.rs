@@ -0,0 +1,171 @@
+//! Generates the kernel symbol table and the program archive.
+//!
+//! Symbols are only known once the kernel is linked, so the table is taken from
+//! the symbol list of a previous build, named by the `RXINU_KSYMS` environment
+//! variable and produced by `nm -n -S -C --defined-only`. With the `ksyms`
+//! feature the table is padded to a fixed size, so embedding it does not move
+//! any symbol and both builds must enable the feature. Without the feature the
+//! table is just an empty header, and `RXINU_KSYMS` is rejected.
+//!
+//! The program archive bundles every file in the directory named by
+//! `RXINU_PROGRAMS`, under its file name. Without `RXINU_PROGRAMS` the archive
+//! is empty.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Size the table is padded to with the `ksyms` feature
+const KSYMS_SIZE: usize = 512 * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+const ENTRY_SIZE: usize = 24;
+
+const PROGRAMS_MAGIC: &[u8; 4] = b"PROG";
+const PROGRAM_ENTRY_SIZE: usize = 16;
+
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+/// Parse a line of `nm -n -S -C` output, keeping only code symbols
+fn parse_line(line: &str) -> Option<Symbol> {
+    let mut fields = line.splitn(2, ' ');
+    let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let rest = fields.next()?;
+
+    // the size column is only present if nm knows the size
+    let (size, rest) = match rest.splitn(2, ' ').collect::<Vec<_>>().as_slice() {
+        [size, rest] if size.len() > 1 => (u64::from_str_radix(size, 16).ok()?, *rest),
+        _ => (0, rest),
+    };
+
+    let mut fields = rest.splitn(2, ' ');
+    let kind = fields.next()?;
+    let name = fields.next()?.trim();
+    if !matches!(kind, "T" | "t" | "W" | "w") || name.is_empty() {
+        return None;
+    }
+
+    Some(Symbol {
+        addr,
+        size,
+        name: name.to_string(),
+    })
+}
+
+/// Build the table, padded to `size` bytes if given
+fn build_table(symbols: &[Symbol], size: Option<usize>) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+
+    let mut name_offset = 8 + symbols.len() * ENTRY_SIZE;
+    for symbol in symbols {
+        table.extend_from_slice(&symbol.addr.to_le_bytes());
+        table.extend_from_slice(&symbol.size.to_le_bytes());
+        table.extend_from_slice(&(name_offset as u32).to_le_bytes());
+        table.extend_from_slice(&(symbol.name.len() as u32).to_le_bytes());
+        name_offset += symbol.name.len();
+    }
+    for symbol in symbols {
+        table.extend_from_slice(symbol.name.as_bytes());
+    }
+
+    if let Some(size) = size {
+        assert!(
+            table.len() <= size,
+            "kernel symbol table needs {} bytes, only {} are reserved",
+            table.len(),
+            size
+        );
+        table.resize(size, 0);
+    }
+    table
+}
+
+/// Pack programs as a header of magic and program count, one entry of name
+/// offset, name length, data offset and data length per program, the names,
+/// and the data of each program at an offset that is a multiple of 8
+fn build_archive(programs: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    archive.extend_from_slice(PROGRAMS_MAGIC);
+    archive.extend_from_slice(&(programs.len() as u32).to_le_bytes());
+
+    let names_len: usize = programs.iter().map(|(name, _)| name.len()).sum();
+    let mut name_offset = 8 + programs.len() * PROGRAM_ENTRY_SIZE;
+    let mut data_offset = name_offset + names_len;
+    for (name, data) in programs {
+        data_offset = (data_offset + 7) & !7;
+        for field in &[name_offset, name.len(), data_offset, data.len()] {
+            archive.extend_from_slice(&(*field as u32).to_le_bytes());
+        }
+        name_offset += name.len();
+        data_offset += data.len();
+    }
+    for (name, _) in programs {
+        archive.extend_from_slice(name.as_bytes());
+    }
+    for (_, data) in programs {
+        archive.resize((archive.len() + 7) & !7, 0);
+        archive.extend_from_slice(data);
+    }
+    archive
+}
+
+fn read_programs(dir: &str) -> Vec<(String, Vec<u8>)> {
+    let entries = fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("failed to read RXINU_PROGRAMS directory {}: {}", dir, err));
+
+    let mut programs = Vec::new();
+    for entry in entries {
+        let path = entry.expect("failed to read RXINU_PROGRAMS entry").path();
+        if !path.is_file() {
+            continue;
+        }
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_else(|| panic!("program {} has no UTF-8 name", path.display()))
+            .to_string();
+        let data = fs::read(&path)
+            .unwrap_or_else(|err| panic!("failed to read program {}: {}", path.display(), err));
+        programs.push((name, data));
+    }
+    programs.sort();
+    programs
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=RXINU_KSYMS");
+
+    let reserved = env::var_os("CARGO_FEATURE_KSYMS").map(|_| KSYMS_SIZE);
+    let mut symbols = Vec::new();
+    if let Ok(path) = env::var("RXINU_KSYMS") {
+        assert!(
+            reserved.is_some(),
+            "RXINU_KSYMS is set but the `ksyms` feature is not enabled"
+        );
+        println!("cargo:rerun-if-changed={}", path);
+        let list = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("failed to read RXINU_KSYMS file {}: {}", path, err));
+        symbols.extend(list.lines().filter_map(parse_line));
+        symbols.sort_by_key(|symbol| symbol.addr);
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out.join("ksyms.bin"), build_table(&symbols, reserved))
+        .expect("failed to write the kernel symbol table");
+
+    println!("cargo:rerun-if-env-changed=RXINU_PROGRAMS");
+    let mut programs = Vec::new();
+    if let Ok(dir) = env::var("RXINU_PROGRAMS") {
+        println!("cargo:rerun-if-changed={}", dir);
+        programs = read_programs(&dir);
+    }
+    fs::write(out.join("programs.bin"), build_archive(&programs))
+        .expect("failed to write the program archive");
+}
@@ -0,0 +1,254 @@
+//! Register dumps and backtraces for crash reports.
+//!
+//! The kernel is built with frame pointers, so every frame starts with the
+//! caller's `rbp` followed by the return address, and the chain of saved
+//! `rbp` values is walked to find the callers. Return addresses are resolved
+//! with the embedded symbol table. Reports go to both the VGA console and the
+//! serial port.
+
+use crate::arch::memory;
+use crate::{crash_print, crash_println, ksyms};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::MapperAllSizes;
+use x86_64::VirtAddr;
+
+/// Backtraces stop after this many frames
+pub const MAX_FRAMES: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+// Written with RIP-relative stores, so saving does not clobber any register
+#[no_mangle]
+static mut RXINU_SAVED_REGISTERS: Registers = Registers {
+    rax: 0,
+    rbx: 0,
+    rcx: 0,
+    rdx: 0,
+    rsi: 0,
+    rdi: 0,
+    rbp: 0,
+    rsp: 0,
+    r8: 0,
+    r9: 0,
+    r10: 0,
+    r11: 0,
+    r12: 0,
+    r13: 0,
+    r14: 0,
+    r15: 0,
+    rip: 0,
+    rflags: 0,
+};
+
+/// Save the general purpose registers for `saved_registers`.
+///
+/// Exception handlers call this first, so the registers are close to their
+/// values at the time of the exception.
+#[inline(always)]
+pub fn save_registers() {
+    unsafe {
+        llvm_asm!("
+            mov %rax, RXINU_SAVED_REGISTERS+0x00(%rip)
+            mov %rbx, RXINU_SAVED_REGISTERS+0x08(%rip)
+            mov %rcx, RXINU_SAVED_REGISTERS+0x10(%rip)
+            mov %rdx, RXINU_SAVED_REGISTERS+0x18(%rip)
+            mov %rsi, RXINU_SAVED_REGISTERS+0x20(%rip)
+            mov %rdi, RXINU_SAVED_REGISTERS+0x28(%rip)
+            mov %rbp, RXINU_SAVED_REGISTERS+0x30(%rip)
+            mov %rsp, RXINU_SAVED_REGISTERS+0x38(%rip)
+            mov %r8, RXINU_SAVED_REGISTERS+0x40(%rip)
+            mov %r9, RXINU_SAVED_REGISTERS+0x48(%rip)
+            mov %r10, RXINU_SAVED_REGISTERS+0x50(%rip)
+            mov %r11, RXINU_SAVED_REGISTERS+0x58(%rip)
+            mov %r12, RXINU_SAVED_REGISTERS+0x60(%rip)
+            mov %r13, RXINU_SAVED_REGISTERS+0x68(%rip)
+            mov %r14, RXINU_SAVED_REGISTERS+0x70(%rip)
+            mov %r15, RXINU_SAVED_REGISTERS+0x78(%rip)
+            lea 0(%rip), %rax
+            mov %rax, RXINU_SAVED_REGISTERS+0x80(%rip)
+            pushfq
+            popq RXINU_SAVED_REGISTERS+0x88(%rip)
+            mov RXINU_SAVED_REGISTERS+0x00(%rip), %rax
+        " ::: "memory" : "volatile");
+    }
+}
+
+/// Returns the registers stored by the last `save_registers`
+pub fn saved_registers() -> Registers {
+    unsafe { ptr::read_volatile(&RXINU_SAVED_REGISTERS) }
+}
+
+/// Returns true if the 16 bytes of a frame record at `addr` can be read without faulting
+fn is_readable(addr: u64) -> bool {
+    let mapped = |addr: u64| match VirtAddr::try_new(addr) {
+        Ok(addr) => memory::try_with_page_table(|mapper| mapper.translate_addr(addr).is_some())
+            .unwrap_or(false),
+        Err(_) => false,
+    };
+    addr % 8 == 0 && mapped(addr) && mapped(addr + 15)
+}
+
+/// Return addresses found by following the frame pointer chain
+pub struct Backtrace {
+    rbp: u64,
+    frames: usize,
+}
+
+impl Backtrace {
+    /// Walk the frames starting with the one `rbp` points to
+    pub fn new(rbp: u64) -> Self {
+        Backtrace { rbp, frames: 0 }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.frames >= MAX_FRAMES || self.rbp == 0 || !is_readable(self.rbp) {
+            return None;
+        }
+
+        let (caller_rbp, return_addr) = unsafe {
+            let frame = self.rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        // the stack grows down, so callers' frames must lie above
+        self.rbp = if caller_rbp > self.rbp { caller_rbp } else { 0 };
+        self.frames += 1;
+
+        if return_addr == 0 {
+            None
+        } else {
+            Some(return_addr)
+        }
+    }
+}
+
+pub fn print_registers(registers: &Registers) {
+    let r = registers;
+    crash_println!("Registers:");
+    crash_println!(
+        "  rax {:#018x}  rbx {:#018x}  rcx {:#018x}",
+        r.rax,
+        r.rbx,
+        r.rcx
+    );
+    crash_println!(
+        "  rdx {:#018x}  rsi {:#018x}  rdi {:#018x}",
+        r.rdx,
+        r.rsi,
+        r.rdi
+    );
+    crash_println!(
+        "  rbp {:#018x}  rsp {:#018x}  r8  {:#018x}",
+        r.rbp,
+        r.rsp,
+        r.r8
+    );
+    crash_println!(
+        "  r9  {:#018x}  r10 {:#018x}  r11 {:#018x}",
+        r.r9,
+        r.r10,
+        r.r11
+    );
+    crash_println!(
+        "  r12 {:#018x}  r13 {:#018x}  r14 {:#018x}",
+        r.r12,
+        r.r13,
+        r.r14
+    );
+    crash_println!(
+        "  r15 {:#018x}  rip {:#018x}  rflags {:#x}",
+        r.r15,
+        r.rip,
+        r.rflags
+    );
+}
+
+fn print_frame(index: usize, addr: u64) {
+    crash_print!("  #{:<2} {:#018x}", index, addr);
+    match ksyms::lookup(addr) {
+        Some(symbol) => crash_println!(" {}+{:#x}", symbol.name, symbol.offset),
+        None => crash_println!(" <unknown>"),
+    }
+}
+
+/// Print the frames above `rbp`, preceded by `rip` if the innermost address is known
+pub fn print_backtrace(rip: Option<u64>, rbp: u64) {
+    crash_println!("Backtrace:");
+    let frames = rip.into_iter().chain(Backtrace::new(rbp));
+    for (index, addr) in frames.enumerate() {
+        // return addresses point after the call, which may be the next function
+        let addr = if index == 0 && rip.is_some() {
+            addr
+        } else {
+            addr - 1
+        };
+        print_frame(index, addr);
+    }
+}
+
+/// Set by exception handlers that dumped the exception before panicking
+static EXCEPTION_DUMPED: AtomicBool = AtomicBool::new(false);
+
+/// Dump the registers and backtrace of an exception.
+///
+/// The handler must have called `save_registers` on entry.
+pub fn dump_exception(stack: &InterruptStackFrame) {
+    let mut registers = saved_registers();
+    registers.rip = stack.instruction_pointer.as_u64();
+    registers.rsp = stack.stack_pointer.as_u64();
+    registers.rflags = stack.cpu_flags;
+
+    // the handler's frame starts with the interrupted code's rbp
+    if is_readable(registers.rbp) {
+        registers.rbp = unsafe { (registers.rbp as *const u64).read() };
+    }
+
+    print_registers(&registers);
+    print_backtrace(Some(registers.rip), registers.rbp);
+}
+
+/// Record that the exception was dumped by its handler, which is about to panic
+pub fn mark_exception_dumped() {
+    EXCEPTION_DUMPED.store(true, Ordering::SeqCst);
+}
+
+/// Dump the registers and backtrace of the caller, such as a panic handler.
+///
+/// Skipped once after `mark_exception_dumped`, as the exception dump already
+/// shows the code that failed and the handler's own frames add nothing.
+#[inline(always)]
+pub fn dump_current() {
+    if EXCEPTION_DUMPED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    save_registers();
+    let registers = saved_registers();
+    print_registers(&registers);
+    print_backtrace(None, registers.rbp);
+}
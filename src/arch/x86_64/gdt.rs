@@ -1,18 +1,42 @@
+use crate::arch::x86_64::memory::stack::KernelStack;
 use lazy_static::lazy_static;
-use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::gdt::{
+    Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector,
+};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+/// A stack overflow faults again while pushing the page fault frame onto the
+/// guard page, so it is reported from the double fault stack
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const IST_STACK_PAGES: u64 = 4;
+
+// `syscall` and `sysret` derive the selectors from two bases in the STAR MSR,
+// which requires the data segments to follow the kernel code segment and
+// precede the user code segment. The system call entry hard-codes them.
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.add_entry(kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
+
+        assert_eq!(code_selector.0, KERNEL_CODE_SELECTOR);
+        assert_eq!(data_selector.0, KERNEL_DATA_SELECTOR);
+        assert_eq!(user_data_selector.0, USER_DATA_SELECTOR);
+        assert_eq!(user_code_selector.0, USER_CODE_SELECTOR);
 
         let selectors = Selectors {
             code_selector,
+            data_selector,
             tss_selector,
         };
 
@@ -20,34 +44,53 @@ lazy_static! {
     };
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+fn kernel_data_segment() -> Descriptor {
+    let flags =
+        DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
+    Descriptor::UserSegment(flags.bits())
+}
+
+/// Mutable so that the stack for entries from user mode can change per task
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Allocate an interrupt stack, which lives for as long as the TSS
+fn ist_stack(name: &'static str) -> VirtAddr {
+    KernelStack::new(name, IST_STACK_PAGES)
+        .expect("failed to allocate interrupt stack")
+        .leak()
+        .top
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::segmentation::{load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            ist_stack("double fault stack");
+    }
+
     GDT.0.load();
 
     unsafe {
         set_cs(GDT.1.code_selector);
+        load_ss(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Set the stack the CPU switches to on interrupts and exceptions in user mode
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { TSS.privilege_stack_table[0] = top };
+}
+
+/// Returns the stack set with `set_kernel_stack`
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { TSS.privilege_stack_table[0] }
+}
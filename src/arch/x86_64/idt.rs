@@ -1,10 +1,10 @@
 use crate::arch::x86_64::gdt;
-use crate::arch::x86_64::interrupts::{exception, irq};
+use crate::arch::x86_64::interrupts::{exception, irq, syscall};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::PrivilegeLevel;
 
 const IRQ_OFFSET: usize = 32;
-#[allow(dead_code)]
 const SYSCALL_OFFSET: usize = 0x80;
 
 lazy_static! {
@@ -41,8 +41,9 @@ lazy_static! {
         idt[IRQ_OFFSET + 3].set_handler_fn(irq::com2);
         idt[IRQ_OFFSET + 4].set_handler_fn(irq::com1);
 
-        // TODO: Syscall
-        //idt[SYSCALL_OFFSET] = syscall_handler_entry(syscall::syscall);
+        idt[SYSCALL_OFFSET]
+            .set_handler_fn(syscall::int80_handler())
+            .set_privilege_level(PrivilegeLevel::Ring3);
 
         idt
     };
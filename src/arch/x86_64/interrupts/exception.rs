@@ -1,130 +1,213 @@
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 
-use crate::arch::x86_64::interrupts::halt;
-use crate::kprintln;
+use crate::arch::x86_64::debug;
+use crate::arch::x86_64::interrupts::fault::{self, Fault};
+use crate::arch::x86_64::memory::{self, address_space, vmm};
+use crate::crash_println;
 
 macro_rules! exception {
     ($x:ident, $stack:ident, $func:block) => {
         pub extern "x86-interrupt" fn $x($stack: &mut InterruptStackFrame) {
+            debug::save_registers();
+            let _handler = fault::enter_handler();
             $func;
         }
     };
     ($x:ident, $stack:ident, $err:ident, $func:block) => {
         pub extern "x86-interrupt" fn $x($stack: &mut InterruptStackFrame, $err: u64) {
+            debug::save_registers();
+            let _handler = fault::enter_handler();
             $func;
         }
     };
     ($x:ident, $stack:ident, $err:ident, $err_type:ty, $func:block) => {
         pub extern "x86-interrupt" fn $x($stack: &mut InterruptStackFrame, $err: $err_type) {
+            debug::save_registers();
+            let _handler = fault::enter_handler();
             $func;
         }
     };
 }
 
-// TODO: Implement actual error handling for each exception
-exception!(divide_error, _stack, {
-    kprintln!("\nDivide By Zero Fault");
+exception!(divide_error, stack, {
+    fault::handle(stack, Fault::new("divide error", stack, None));
 });
 
-exception!(debug, _stack, {
-    kprintln!("\nDebug Trap");
+exception!(debug, stack, {
+    fault::trap(stack, "Debug trap");
 });
 
-exception!(non_maskable_interrupt, _stack, {
-    kprintln!("\nNon-maskable Interrupt");
+exception!(non_maskable_interrupt, stack, {
+    fault::trap(stack, "Non-maskable interrupt");
 });
 
-exception!(breakpoint, _stack, {
-    kprintln!("\nBreakpoint trap");
+exception!(breakpoint, stack, {
+    fault::trap(stack, "Breakpoint trap");
 });
 
-exception!(overflow, _stack, {
-    kprintln!("\nOverflow trap");
+exception!(overflow, stack, {
+    fault::trap(stack, "Overflow trap");
 });
 
-exception!(bound_range_exceeded, _stack, {
-    kprintln!("\nBound Range Exceeded Fault");
+exception!(bound_range_exceeded, stack, {
+    fault::handle(stack, Fault::new("bound range exceeded", stack, None));
 });
 
-exception!(invalid_opcode, _stack, {
-    kprintln!("\nInvalid Opcode Fault");
-    loop {
-        unsafe {
-            halt();
-        }
-    }
+exception!(invalid_opcode, stack, {
+    fault::handle(stack, Fault::new("invalid opcode", stack, None));
 });
 
-exception!(device_not_available, _stack, {
-    kprintln!("\nDevice Not Available Fault");
+exception!(device_not_available, stack, {
+    fault::handle(stack, Fault::new("device not available", stack, None));
 });
 
 pub extern "x86-interrupt" fn double_fault(stack: &mut InterruptStackFrame, _error_code: u64) -> ! {
+    debug::save_registers();
+    debug::dump_exception(stack);
+    debug::mark_exception_dumped();
+    let addr = Cr2::read();
+    if let Some(overflowed) = memory::stack::guard_hit(addr) {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT after overflow of {} ({:#x}..{:#x}) at {:#x}\n{:#?}",
+            overflowed.name,
+            overflowed.bottom.as_u64(),
+            overflowed.top.as_u64(),
+            addr.as_u64(),
+            stack
+        );
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack);
 }
 
-exception!(invalid_tss, _stack, _error, {
-    kprintln!("\nInvalid TSS Fault");
+exception!(invalid_tss, stack, error, {
+    fault::handle(stack, Fault::new("invalid TSS", stack, Some(error)));
 });
 
 exception!(segment_not_present, stack, error, {
-    kprintln!("\nSegment Not Present Fault: 0x{:x}\n{:#?}", error, stack);
+    fault::handle(stack, Fault::new("segment not present", stack, Some(error)));
 });
 
-exception!(stack_segment_fault, _stack, _error, {
-    kprintln!("\nStack Segment Fault");
+exception!(stack_segment_fault, stack, error, {
+    fault::handle(stack, Fault::new("stack segment fault", stack, Some(error)));
 });
 
-exception!(general_protection_fault, _stack, _error, {
-    kprintln!("\nGeneral Protection Fault");
-    loop {
-        unsafe {
-            halt();
-        }
-    }
+exception!(general_protection_fault, stack, error, {
+    fault::handle(
+        stack,
+        Fault::new("general protection fault", stack, Some(error)),
+    );
 });
 
 exception!(page_fault, stack, err, PageFaultErrorCode, {
-    let cr2: u64 = unsafe {
-        let reg: u64;
-        llvm_asm!("mov %cr2, $0" : "=r"(reg));
-        reg
+    let addr = Cr2::read();
+    if vmm::handle_page_fault(addr, err) || address_space::handle_page_fault(addr, err) {
+        return;
+    }
+
+    if let Some(overflowed) = memory::stack::guard_hit(addr) {
+        crash_println!(
+            "\nKernel stack overflow: {} ({:#x}..{:#x}) hit its guard page at {:#x}\n{:#?}",
+            overflowed.name,
+            overflowed.bottom.as_u64(),
+            overflowed.top.as_u64(),
+            addr.as_u64(),
+            stack
+        );
+        let fault = Fault {
+            address: Some(addr),
+            ..Fault::new("kernel stack overflow", stack, Some(err.bits()))
+        };
+        fault::handle(stack, fault);
+        return;
+    }
+
+    let access = if err.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else if err.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else {
+        "read"
+    };
+    let mode = if err.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+    let cause = if !err.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "page not present"
+    } else if err.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "execution of non-executable memory"
+    } else if err.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write to read-only memory"
+    } else {
+        "protection violation"
     };
 
-    kprintln!(
-        "\nPage fault while accessing {:#x}\nError Code: {:?}\n{:#?}",
-        cr2,
-        err,
-        stack
+    crash_println!(
+        "\nPage fault: {} {} of {:#x} ({})\nError Code: {:?}",
+        mode,
+        access,
+        addr.as_u64(),
+        cause,
+        err
     );
-
-    loop {
-        unsafe {
-            halt();
-        }
+    if let Some(segment) = memory::image::segment_of(addr) {
+        crash_println!(
+            "Kernel image: {} at {:#x}..{:#x}",
+            segment.kind(),
+            segment.start.as_u64(),
+            segment.end.as_u64()
+        );
+    }
+    match vmm::try_region(addr) {
+        Some(region) => crash_println!(
+            "Region: {} at {:#x}..{:#x}, {:?}",
+            region.name,
+            region.start.as_u64(),
+            region.end().as_u64(),
+            region.state
+        ),
+        None => crash_println!("Region: none"),
     }
+    crash_println!("{:#?}", stack);
+
+    let fault = Fault {
+        address: Some(addr),
+        ..Fault::new("page fault", stack, Some(err.bits()))
+    };
+    fault::handle(stack, fault);
 });
 
-exception!(x87_floating_point, _stack, {
-    kprintln!("\nx87 Floating Point Exception");
+exception!(x87_floating_point, stack, {
+    fault::handle(
+        stack,
+        Fault::new("x87 floating point exception", stack, None),
+    );
 });
 
-exception!(alignment_check, _stack, _error, {
-    kprintln!("\nAlignment Check Fault");
+exception!(alignment_check, stack, error, {
+    fault::handle(stack, Fault::new("alignment check", stack, Some(error)));
 });
 
-pub extern "x86-interrupt" fn machine_check(_stack: &mut InterruptStackFrame) -> ! {
+pub extern "x86-interrupt" fn machine_check(stack: &mut InterruptStackFrame) -> ! {
+    debug::save_registers();
+    debug::dump_exception(stack);
+    debug::mark_exception_dumped();
     panic!("\nMachine Check Abort");
 }
 
-exception!(simd_floating_point, _stack, {
-    kprintln!("\nSIMD Floating Point Exception");
+exception!(simd_floating_point, stack, {
+    fault::handle(
+        stack,
+        Fault::new("SIMD floating point exception", stack, None),
+    );
 });
 
-exception!(virtualization, _stack, {
-    kprintln!("\nVirtualization Exception");
+exception!(virtualization, stack, {
+    fault::handle(stack, Fault::new("virtualization exception", stack, None));
 });
 
-exception!(security_exception, _stack, _error, {
-    kprintln!("\nSecurity Exception");
+exception!(security_exception, stack, error, {
+    fault::handle(stack, Fault::new("security exception", stack, Some(error)));
 });
@@ -0,0 +1,257 @@
+//! What to do about an exception.
+//!
+//! Traps such as breakpoints report and resume after the trapping instruction.
+//! Faults and aborts cannot resume, as the faulting instruction would just
+//! run again, so:
+//!
+//! * A fault raised while a task is being polled kills that task. The poll is
+//!   abandoned by returning from the `catch` that wraps it, and the fault is
+//!   recorded as the reason the task was killed.
+//! * A fault in user mode returns from the `usermode::run` that entered it.
+//! * A fault in kernel context, outside of any task, panics. So does a fault
+//!   raised by an interrupt or exception handler, even if it interrupted a
+//!   task: the task did not cause it, and the handler must not be abandoned.
+//!
+//! Abandoning a poll skips the rest of the task's code, so whatever the task
+//! owned at the time of the fault is leaked. A `Mutex` it held stays locked,
+//! but its entries in the deadlock graph are removed. A fault raised while
+//! the abandoned code holds an `IrqLock` or `IrqSpinLock` panics instead, as
+//! the data behind the lock may be half updated. User mode state changed by a
+//! `usermode::run` that is abandoned with the poll is restored.
+
+use crate::arch::x86_64::{debug, usermode};
+use crate::crash_println;
+use crate::sync;
+use core::fmt;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Description of an exception that ended a task
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub exception: &'static str,
+    /// Address of the faulting instruction
+    pub instruction: VirtAddr,
+    pub error_code: Option<u64>,
+    /// Accessed address, for page faults
+    pub address: Option<VirtAddr>,
+}
+
+impl Fault {
+    pub fn new(
+        exception: &'static str,
+        stack: &InterruptStackFrame,
+        error_code: Option<u64>,
+    ) -> Self {
+        Fault {
+            exception,
+            instruction: stack.instruction_pointer,
+            error_code,
+            address: None,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#x}", self.exception, self.instruction.as_u64())?;
+        if let Some(address) = self.address {
+            write!(f, " accessing {:#x}", address.as_u64())?;
+        }
+        if let Some(error_code) = self.error_code {
+            write!(f, " (error code {:#x})", error_code)?;
+        }
+        Ok(())
+    }
+}
+
+/// State saved by `rxinu_catch` to return from it a second time
+#[derive(Default)]
+#[repr(C)]
+struct JumpBuffer {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    /// Stack pointer pointing at the return address of `rxinu_catch`
+    rsp: u64,
+    rflags: u64,
+    /// Locks held when `catch` was called
+    held_locks: usize,
+    fault: Option<Fault>,
+}
+
+// `rxinu_catch(buffer, f, data)` saves the callee-saved registers to `buffer`
+// and calls `f(data)`, returning 0. A fault handler abandons `f` by returning
+// to `rxinu_catch_resume` with the buffer on top of the stack, which restores
+// the registers and returns 1 from `rxinu_catch` instead.
+global_asm!(
+    r#"
+.global rxinu_catch
+rxinu_catch:
+    mov %rbx, 0x00(%rdi)
+    mov %rbp, 0x08(%rdi)
+    mov %r12, 0x10(%rdi)
+    mov %r13, 0x18(%rdi)
+    mov %r14, 0x20(%rdi)
+    mov %r15, 0x28(%rdi)
+    mov %rsp, 0x30(%rdi)
+    pushfq
+    pop %rax
+    mov %rax, 0x38(%rdi)
+
+    sub $8, %rsp
+    mov %rdx, %rdi
+    call *%rsi
+    add $8, %rsp
+    xor %eax, %eax
+    ret
+
+.global rxinu_catch_resume
+rxinu_catch_resume:
+    pop %rdi
+    mov 0x00(%rdi), %rbx
+    mov 0x08(%rdi), %rbp
+    mov 0x10(%rdi), %r12
+    mov 0x18(%rdi), %r13
+    mov 0x20(%rdi), %r14
+    mov 0x28(%rdi), %r15
+    mov 0x30(%rdi), %rsp
+    mov $1, %eax
+    ret
+"#
+);
+
+extern "C" {
+    fn rxinu_catch(buffer: *mut JumpBuffer, f: extern "C" fn(*mut u8), data: *mut u8) -> u64;
+    fn rxinu_catch_resume();
+}
+
+/// The innermost `catch`, which faults return to
+static RECOVERY: AtomicPtr<JumpBuffer> = AtomicPtr::new(ptr::null_mut());
+
+/// Number of interrupt and exception handlers currently running
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks an interrupt or exception handler as running until dropped
+pub struct HandlerGuard(());
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        HANDLER_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Mark the calling handler as running, so faults it raises are not recovered from
+pub fn enter_handler() -> HandlerGuard {
+    HANDLER_DEPTH.fetch_add(1, Ordering::SeqCst);
+    HandlerGuard(())
+}
+
+/// Run `f`, returning the fault if an exception abandons it.
+///
+/// On a fault, whatever `f` owned is leaked rather than dropped. Faults while
+/// `f` holds an `IrqLock` or `IrqSpinLock` are not recovered from.
+pub fn catch<F, T>(f: F) -> Result<T, Fault>
+where
+    F: FnOnce() -> T,
+{
+    extern "C" fn call<F: FnOnce() -> T, T>(data: *mut u8) {
+        let data = unsafe { &mut *(data as *mut (Option<F>, Option<T>)) };
+        let f = data.0.take().expect("catch: closure called twice");
+        data.1 = Some(f());
+    }
+
+    let mut data: (Option<F>, Option<T>) = (Some(f), None);
+    let mut buffer = JumpBuffer {
+        held_locks: sync::held_locks(),
+        ..JumpBuffer::default()
+    };
+    let user = usermode::save();
+    let previous = RECOVERY.swap(&mut buffer, Ordering::SeqCst);
+    let faulted = unsafe {
+        rxinu_catch(
+            &mut buffer,
+            call::<F, T>,
+            &mut data as *mut (Option<F>, Option<T>) as *mut u8,
+        )
+    };
+    RECOVERY.store(previous, Ordering::SeqCst);
+
+    if faulted == 0 {
+        Ok(data.1.take().expect("catch: closure did not return"))
+    } else {
+        mem::forget(data);
+        usermode::restore(user);
+        Err(buffer.fault.expect("catch: resumed without a fault"))
+    }
+}
+
+/// Returns true if the exception interrupted kernel code
+fn from_kernel(stack: &InterruptStackFrame) -> bool {
+    stack.code_segment & 3 == 0
+}
+
+/// Stop the user code that faulted, or abandon the innermost `catch` if there
+/// is one, or panic.
+///
+/// Called after `enter_handler` by the handlers of exceptions that cannot
+/// resume. The registers and backtrace of the faulting code are dumped first.
+pub fn handle(stack: &mut InterruptStackFrame, fault: Fault) {
+    // the handler of this exception is counted as well
+    let nested = HANDLER_DEPTH.load(Ordering::SeqCst) > 1;
+
+    debug::dump_exception(stack);
+    let recovered = if nested {
+        false
+    } else if from_kernel(stack) {
+        recover(stack, fault)
+    } else {
+        usermode::recover(stack, fault)
+    };
+    if !recovered {
+        debug::mark_exception_dumped();
+        panic!("EXCEPTION: {}\n{:#?}", fault, stack);
+    }
+}
+
+/// Make the exception return to the innermost `catch`, which returns `fault`.
+///
+/// Returns false if there is no `catch` to return to, or if the code it would
+/// abandon holds a lock.
+pub fn recover(stack: &mut InterruptStackFrame, fault: Fault) -> bool {
+    let buffer = RECOVERY.load(Ordering::SeqCst);
+    if buffer.is_null() || !from_kernel(stack) {
+        return false;
+    }
+
+    let held = sync::held_locks().saturating_sub(unsafe { (*buffer).held_locks });
+    if held > 0 {
+        crash_println!("Not recovering, {} locks are held", held);
+        return false;
+    }
+
+    unsafe {
+        (*buffer).fault = Some(fault);
+
+        // the stack below the saved stack pointer belonged to the abandoned code
+        let resume_stack = (*buffer).rsp - 16;
+        *(resume_stack as *mut *mut JumpBuffer) = buffer;
+
+        let frame = stack.as_mut();
+        frame.instruction_pointer = VirtAddr::new(rxinu_catch_resume as usize as u64);
+        frame.stack_pointer = VirtAddr::new(resume_stack);
+        frame.cpu_flags = (*buffer).rflags;
+    }
+    true
+}
+
+/// Report a trap and resume after the trapping instruction
+pub fn trap(stack: &InterruptStackFrame, name: &str) {
+    crash_println!("\n{} at {:#x}", name, stack.instruction_pointer.as_u64());
+}
@@ -1,14 +1,21 @@
 use x86_64::structures::idt::InterruptStackFrame;
 
+use crate::arch::x86_64::interrupts::fault;
 use crate::device::{pic_8259 as pic, serial::uart_16550 as serial};
 
 pub extern "x86-interrupt" fn timer(_stack_frame: &mut InterruptStackFrame) {
+    let _handler = fault::enter_handler();
     pic::MAIN.lock().ack();
+
+    #[cfg(feature = "deadlock-detection")]
+    crate::sync::deadlock::tick();
+
     // TODO: Pre-empt tasks
 }
 
 pub extern "x86-interrupt" fn keyboard(_stack_frame: &mut InterruptStackFrame) {
     use crate::device::ps2_controller_8042;
+    let _handler = fault::enter_handler();
 
     // Read a single scancode off our keyboard port.
     let code = ps2_controller_8042::key_read();
@@ -19,10 +26,12 @@ pub extern "x86-interrupt" fn keyboard(_stack_frame: &mut InterruptStackFrame) {
 
 #[allow(unused_variables)]
 pub extern "x86-interrupt" fn cascade(_stack_frame: &mut InterruptStackFrame) {
+    let _handler = fault::enter_handler();
     pic::MAIN.lock().ack();
 }
 
 pub extern "x86-interrupt" fn com1(_stack_frame: &mut InterruptStackFrame) {
+    let _handler = fault::enter_handler();
     let mut com1 = serial::COM1.lock();
     while com1.line_sts().contains(serial::LineStsFlags::DATA_READY) {
         crate::device::serial::add_byte(com1.receive());
@@ -31,6 +40,7 @@ pub extern "x86-interrupt" fn com1(_stack_frame: &mut InterruptStackFrame) {
 }
 
 pub extern "x86-interrupt" fn com2(_stack_frame: &mut InterruptStackFrame) {
+    let _handler = fault::enter_handler();
     let mut com2 = serial::COM2.lock();
     while com2.line_sts().contains(serial::LineStsFlags::DATA_READY) {
         crate::device::serial::add_byte(com2.receive());
@@ -2,6 +2,7 @@ use crate::device::pic_8259::{MAIN, WORKER};
 use x86_64::registers::rflags::{self, RFlags};
 
 pub mod exception;
+pub mod fault;
 pub mod irq;
 pub mod syscall;
 
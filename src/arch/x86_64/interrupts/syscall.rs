@@ -1,22 +1,57 @@
-use crate::kprintln;
-use core::fmt;
-use x86_64::structures::idt::InterruptStackFrame;
+//! System calls through `int 0x80` and the `syscall` instruction.
+//!
+//! The system call number is passed in `rax` and up to three arguments in
+//! `rdi`, `rsi` and `rdx`. The result is returned in `rax`; errors are returned
+//! as the negated error code, so values from `-4095` to `-1` are errors. All
+//! other registers are preserved, except for `rcx` and `r11` which `syscall`
+//! overwrites with the return address and flags.
+//!
+//! Both entry stubs save the registers into a `SyscallStack` and hand it to
+//! the dispatcher, which looks the number up in a table of typed handlers.
+//!
+//! `syscall` does not switch stacks, so its entry loads the kernel stack from
+//! the per-CPU data that `swapgs` makes reachable through `gs`. The kernel does
+//! not use `gs` otherwise, so the entry swaps back right away and the user's
+//! `gs` base stays loaded in the kernel. Whether a call comes from user mode is
+//! recorded in the per-CPU data as well: `usermode::run` sets the flag, and the
+//! entry clears it until it returns to user mode. Calls from kernel code keep
+//! their stack and return with `iretq`, as `sysret` always returns to user mode.
+
+use crate::arch::memory::address_space;
+use crate::arch::memory::stack::KernelStack;
+use crate::arch::x86_64::{gdt, usermode};
+use crate::kprint;
+use crate::task;
+use core::{fmt, mem, ptr, slice, str};
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::HandlerFunc;
+use x86_64::VirtAddr;
+
+const STAR: u32 = 0xc000_0081;
+const LSTAR: u32 = 0xc000_0082;
+const SFMASK: u32 = 0xc000_0084;
+const KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+const SYSCALL_STACK_PAGES: u64 = 4;
 
 #[repr(C, packed)]
 /// Represents the syscall stack
 pub struct SyscallStack {
-    pub fs: u64,
-    pub r11: u64,
-    pub r10: u64,
-    pub r9: u64,
-    pub r8: u64,
-    pub rsi: u64,
+    pub rax: u64,
     pub rdi: u64,
+    pub rsi: u64,
     pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
     pub rcx: u64,
+    pub r11: u64,
     pub rip: u64,
     pub cs: u64,
     pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 
 impl fmt::Debug for SyscallStack {
@@ -28,23 +63,396 @@ impl fmt::Debug for SyscallStack {
             }
         }
         let mut s = f.debug_struct("SyscallStack");
-        s.field("fs", &StackHex(self.fs));
-        s.field("r11", &StackHex(self.r11));
-        s.field("r10", &StackHex(self.r10));
-        s.field("r9", &StackHex(self.r9));
-        s.field("r8", &StackHex(self.r8));
-        s.field("rsi", &StackHex(self.rsi));
+        s.field("rax", &StackHex(self.rax));
         s.field("rdi", &StackHex(self.rdi));
+        s.field("rsi", &StackHex(self.rsi));
         s.field("rdx", &StackHex(self.rdx));
+        s.field("r10", &StackHex(self.r10));
+        s.field("r8", &StackHex(self.r8));
+        s.field("r9", &StackHex(self.r9));
         s.field("rcx", &StackHex(self.rcx));
+        s.field("r11", &StackHex(self.r11));
         s.field("rip", &StackHex(self.rip));
         s.field("cs", &StackHex(self.cs));
         s.field("rflags", &StackHex(self.rflags));
+        s.field("rsp", &StackHex(self.rsp));
+        s.field("ss", &StackHex(self.ss));
         s.finish()
     }
 }
 
-pub extern "x86-interrupt" fn syscall(_stack_frame: &mut InterruptStackFrame) {
-    kprintln!("This is a syscall!");
-    // TODO: syscall::match_syscall(eax_register, stack_frame.rsp);
+/// System call numbers
+pub mod number {
+    /// `write(buf, len)`: print `len` bytes of UTF-8 text, returns `len`
+    pub const WRITE: u64 = 0;
+    /// `task_id()`: returns the id of the current task
+    pub const TASK_ID: u64 = 1;
+    /// `exit(status)`: return from `usermode::run` with `status`, user mode only
+    pub const EXIT: u64 = 2;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// No task is running
+    NoTask,
+    /// A pointer argument is not accessible to the caller
+    BadAddress,
+    InvalidArgument,
+    /// The system call number is unknown
+    NoSuchSyscall,
+}
+
+impl Error {
+    /// Returns the errno-style code of the error
+    pub fn code(self) -> u64 {
+        match self {
+            Error::NoTask => 3,
+            Error::BadAddress => 14,
+            Error::InvalidArgument => 22,
+            Error::NoSuchSyscall => 38,
+        }
+    }
+
+    fn from_code(code: u64) -> Option<Self> {
+        match code {
+            3 => Some(Error::NoTask),
+            14 => Some(Error::BadAddress),
+            22 => Some(Error::InvalidArgument),
+            38 => Some(Error::NoSuchSyscall),
+            _ => None,
+        }
+    }
+}
+
+pub type Result = core::result::Result<u64, Error>;
+
+/// Largest error code that can be returned in `rax`
+const MAX_ERROR: u64 = 4095;
+
+/// Returns the value of `rax` for `result`
+pub fn encode(result: Result) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(error) => error.code().wrapping_neg(),
+    }
+}
+
+/// Returns the result a system call returned in `rax`.
+///
+/// Unknown error codes are reported as `InvalidArgument`.
+pub fn decode(rax: u64) -> Result {
+    if rax.wrapping_neg() <= MAX_ERROR && rax != 0 {
+        Err(Error::from_code(rax.wrapping_neg()).unwrap_or(Error::InvalidArgument))
+    } else {
+        Ok(rax)
+    }
+}
+
+/// The issuer of a system call
+#[derive(Clone, Copy, Debug)]
+pub struct Caller {
+    /// True if the call came from user mode
+    pub user: bool,
+}
+
+impl Caller {
+    fn new(stack: &SyscallStack) -> Self {
+        Caller {
+            user: stack.cs & 3 != 0,
+        }
+    }
+
+    /// Returns the caller's buffer at `addr`.
+    ///
+    /// User callers may only pass pages of their address space that are
+    /// accessible to them, kernel callers are trusted.
+    fn buffer(self, addr: u64, len: u64) -> core::result::Result<&'static [u8], Error> {
+        let start = VirtAddr::try_new(addr).map_err(|_| Error::BadAddress)?;
+        if addr == 0 || addr.checked_add(len).is_none() {
+            return Err(Error::BadAddress);
+        }
+        if self.user && !address_space::is_user_accessible(start, len, false) {
+            return Err(Error::BadAddress);
+        }
+        Ok(unsafe { slice::from_raw_parts(addr as *const u8, len as usize) })
+    }
+}
+
+/// A system call implementation, by the number of arguments it takes
+#[derive(Clone, Copy)]
+pub enum Handler {
+    Args0(fn(Caller) -> Result),
+    Args1(fn(Caller, u64) -> Result),
+    Args2(fn(Caller, u64, u64) -> Result),
+    Args3(fn(Caller, u64, u64, u64) -> Result),
+    /// Takes one argument and may change where the call returns to
+    Stack1(fn(Caller, &mut SyscallStack, u64) -> Result),
+}
+
+/// Handlers indexed by system call number
+static TABLE: [Handler; 3] = [
+    Handler::Args2(sys_write),
+    Handler::Args0(sys_task_id),
+    Handler::Stack1(sys_exit),
+];
+
+fn sys_write(caller: Caller, buf: u64, len: u64) -> Result {
+    let text = str::from_utf8(caller.buffer(buf, len)?).map_err(|_| Error::InvalidArgument)?;
+    kprint!("{}", text);
+    Ok(len)
+}
+
+fn sys_task_id(_caller: Caller) -> Result {
+    task::current().map(|id| id.as_u64()).ok_or(Error::NoTask)
+}
+
+fn sys_exit(caller: Caller, stack: &mut SyscallStack, status: u64) -> Result {
+    if caller.user && usermode::exit(stack, status) {
+        Ok(0)
+    } else {
+        Err(Error::InvalidArgument)
+    }
+}
+
+/// Run the system call described by `stack`
+pub fn dispatch(stack: &mut SyscallStack) -> Result {
+    let caller = Caller::new(stack);
+    let handler = TABLE.get(stack.rax as usize).ok_or(Error::NoSuchSyscall)?;
+    let (arg0, arg1, arg2) = (stack.rdi, stack.rsi, stack.rdx);
+    match *handler {
+        Handler::Args0(f) => f(caller),
+        Handler::Args1(f) => f(caller, arg0),
+        Handler::Args2(f) => f(caller, arg0, arg1),
+        Handler::Args3(f) => f(caller, arg0, arg1, arg2),
+        Handler::Stack1(f) => f(caller, stack, arg0),
+    }
+}
+
+#[no_mangle]
+extern "C" fn rxinu_syscall_dispatch(stack: &mut SyscallStack) {
+    stack.rax = encode(dispatch(stack));
+}
+
+// The CPU aligns the stack before pushing the interrupt frame, so the nine
+// registers pushed here leave it aligned for the call.
+global_asm!(
+    r#"
+.global rxinu_syscall_int80
+rxinu_syscall_int80:
+    push %r11
+    push %rcx
+    push %r9
+    push %r8
+    push %r10
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rax
+
+    cld
+    mov %rsp, %rdi
+    call rxinu_syscall_dispatch
+
+    pop %rax
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %r10
+    pop %r8
+    pop %r9
+    pop %rcx
+    pop %r11
+    iretq
+"#
+);
+
+extern "C" {
+    fn rxinu_syscall_int80();
+}
+
+/// Returns the `int 0x80` entry stub as an IDT handler.
+///
+/// The stub saves the registers itself, it only has the type of an
+/// `x86-interrupt` function to fit into the IDT.
+pub fn int80_handler() -> HandlerFunc {
+    unsafe { mem::transmute(rxinu_syscall_int80 as unsafe extern "C" fn()) }
+}
+
+/// Issue a system call with `int 0x80` and return the raw value of `rax`.
+///
+/// This function is unsafe because the kernel trusts pointer arguments of
+/// kernel callers.
+pub unsafe fn int80(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let rax: u64;
+    llvm_asm!("int $$0x80"
+        : "={rax}"(rax)
+        : "{rax}"(number), "{rdi}"(arg0), "{rsi}"(arg1), "{rdx}"(arg2)
+        : "memory"
+        : "volatile");
+    rax
+}
+
+/// Data of the CPU that `syscall` entry reaches through `gs`
+#[repr(C)]
+struct PerCpu {
+    /// Stack pointer to enter the kernel with
+    kernel_stack: u64,
+    /// Scratch space for the user's stack pointer
+    user_stack: u64,
+    /// Bit 0 is set while the CPU runs user code
+    user_mode: u64,
+}
+
+static mut PER_CPU: PerCpu = PerCpu {
+    kernel_stack: 0,
+    user_stack: 0,
+    user_mode: 0,
+};
+
+/// Set the stack that system calls from user mode run on
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { PER_CPU.kernel_stack = top.as_u64() };
+}
+
+/// Returns the stack set with `set_kernel_stack`
+pub fn kernel_stack() -> VirtAddr {
+    VirtAddr::new(unsafe { PER_CPU.kernel_stack })
+}
+
+/// Record whether the CPU is about to run user code or is back in the kernel
+pub(crate) fn set_user_mode(user_mode: bool) {
+    unsafe { ptr::write_volatile(&mut PER_CPU.user_mode, user_mode as u64) };
+}
+
+/// Returns the flag set with `set_user_mode`
+pub(crate) fn user_mode() -> bool {
+    unsafe { ptr::read_volatile(&PER_CPU.user_mode) != 0 }
+}
+
+// The entry tests and clears the user mode flag to tell where a call comes
+// from, and sets it again before returning to user mode. The selectors are
+// those of `gdt`, and the frame pushed before the registers has the layout of
+// an interrupt frame.
+global_asm!(
+    r#"
+.global rxinu_syscall_entry
+rxinu_syscall_entry:
+    swapgs
+    btrq $0, %gs:16
+    jnc 1f
+
+    mov %rsp, %gs:8
+    mov %gs:0, %rsp
+    pushq $0x1b
+    pushq %gs:8
+    swapgs
+    push %r11
+    pushq $0x23
+    push %rcx
+    jmp 2f
+
+1:
+    swapgs
+    pushq $0x10
+    push %rsp
+    addq $8, (%rsp)
+    push %r11
+    pushq $0x08
+    push %rcx
+
+2:
+    push %r11
+    push %rcx
+    push %r9
+    push %r8
+    push %r10
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rax
+
+    mov %rsp, %rdi
+    push %rbp
+    mov %rsp, %rbp
+    and $-16, %rsp
+    cld
+    call rxinu_syscall_dispatch
+    mov %rbp, %rsp
+    pop %rbp
+
+    pop %rax
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %r10
+    pop %r8
+    pop %r9
+    pop %rcx
+    pop %r11
+
+    testb $3, 8(%rsp)
+    jz 3f
+    swapgs
+    movq $1, %gs:16
+    swapgs
+    mov (%rsp), %rcx
+    mov %rcx, %r11
+    sar $47, %r11
+    jnz 3f
+    mov 16(%rsp), %r11
+    mov 24(%rsp), %rsp
+    sysretq
+
+3:
+    iretq
+"#
+);
+
+extern "C" {
+    fn rxinu_syscall_entry();
+}
+
+/// Returns the address `syscall` enters the kernel at
+pub fn syscall_entry() -> VirtAddr {
+    VirtAddr::new(rxinu_syscall_entry as usize as u64)
+}
+
+/// Enable the `syscall` instruction.
+///
+/// Must be called after `gdt::init`, as the selectors are taken from there.
+pub fn init() {
+    let stack = KernelStack::new("syscall stack", SYSCALL_STACK_PAGES)
+        .expect("failed to allocate the syscall stack")
+        .leak();
+    set_kernel_stack(stack.top);
+
+    // `sysret` loads the user selectors relative to the kernel data selector
+    let star =
+        (u64::from(gdt::KERNEL_DATA_SELECTOR) << 48) | (u64::from(gdt::KERNEL_CODE_SELECTOR) << 32);
+    let masked = RFlags::INTERRUPT_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::TRAP_FLAG
+        | RFlags::ALIGNMENT_CHECK;
+
+    unsafe {
+        Msr::new(STAR).write(star);
+        Msr::new(LSTAR).write(syscall_entry().as_u64());
+        Msr::new(SFMASK).write(masked.bits());
+        Msr::new(KERNEL_GS_BASE).write(&PER_CPU as *const PerCpu as u64);
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Issue a system call with the `syscall` instruction and return the raw value of `rax`.
+///
+/// This function is unsafe because the kernel trusts pointer arguments of
+/// kernel callers.
+pub unsafe fn fast_syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let rax: u64;
+    llvm_asm!("syscall"
+        : "={rax}"(rax)
+        : "{rax}"(number), "{rdi}"(arg0), "{rsi}"(arg1), "{rdx}"(arg2)
+        : "rcx", "r11", "memory"
+        : "volatile");
+    rax
 }
@@ -0,0 +1,282 @@
+//! Loading ELF64 executables into user address spaces.
+//!
+//! The `PT_LOAD` segments are mapped as user regions of a fresh address space.
+//! Segments may share a page, so every page is mapped once, with the
+//! permissions of all segments on it: writable if any of them is, executable
+//! if any of them is, and never both. The part of a segment beyond its file
+//! data is left zeroed, as regions start out zeroed.
+//!
+//! The stack is set up as the System V ABI describes it for process entry: the
+//! stack pointer points to `argc`, followed by the `argv` pointers, an empty
+//! environment and the auxiliary vector. The argument strings live above.
+
+use crate::arch::memory::address_space::{self, AddressSpace, USER_END, USER_START};
+use crate::arch::memory::vmm::{self, PAGE_SIZE};
+use crate::arch::usermode::{self, Exit};
+use crate::elf::{self, Elf, ProgramHeader, ET_EXEC};
+use crate::programs;
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Number of usable stack pages
+pub const STACK_PAGES: u64 = 16;
+/// Initial top of the stack. The page above is left unmapped.
+pub const STACK_TOP: u64 = USER_END - PAGE_SIZE;
+
+/// Auxiliary vector entry types
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No bundled program has the requested name
+    NotFound,
+    /// The file is not a valid ELF file
+    Elf(elf::Error),
+    /// The file is not an executable
+    NotExecutable,
+    /// The file has no segments to load
+    NoLoadableSegments,
+    /// A segment's file data lies outside the file
+    SegmentOutOfFile,
+    /// A segment has more file data than memory
+    BadSegmentSize,
+    /// A segment lies outside the user range
+    BadSegmentAddress,
+    /// Segments overlap
+    OverlappingSegments,
+    /// A segment is both writable and executable, or shares a page with
+    /// segments that make the page both
+    WritableAndExecutable,
+    /// The entry point is not in an executable segment
+    BadEntry,
+    /// The arguments do not fit on the stack
+    ArgumentsTooLarge,
+    /// Mapping a segment or the stack failed
+    Memory(vmm::Error),
+}
+
+/// A program loaded into its own address space, ready to run
+pub struct Process {
+    pub space: AddressSpace,
+    pub entry: VirtAddr,
+    /// Initial stack pointer, pointing at `argc`
+    pub stack_pointer: VirtAddr,
+}
+
+impl Process {
+    /// Run the program in its address space until it exits or faults
+    pub fn run(&self) -> Result<Exit, usermode::Error> {
+        self.space.activate();
+        let exit = usermode::run(self.entry, self.stack_pointer, 0);
+        address_space::activate_kernel();
+        exit
+    }
+}
+
+/// Load the bundled program called `name`
+pub fn load_bundled(name: &str, args: &[&str]) -> Result<Process, Error> {
+    let program = programs::find(name).ok_or(Error::NotFound)?;
+    load(program.data, args)
+}
+
+/// Load an executable into a fresh address space, passing `args` as `argv`
+pub fn load(data: &[u8], args: &[&str]) -> Result<Process, Error> {
+    let elf = Elf::parse(data).map_err(Error::Elf)?;
+    if elf.header().file_type != ET_EXEC {
+        return Err(Error::NotExecutable);
+    }
+
+    let segments: Vec<ProgramHeader> = elf
+        .program_headers()
+        .filter(|header| header.is_load() && header.memsz > 0)
+        .collect();
+    if segments.is_empty() {
+        return Err(Error::NoLoadableSegments);
+    }
+
+    let entry = elf.header().entry;
+    let in_code = |segment: &ProgramHeader| {
+        segment.is_executable() && segment.vaddr <= entry && entry - segment.vaddr < segment.memsz
+    };
+    if !segments.iter().any(in_code) {
+        return Err(Error::BadEntry);
+    }
+
+    let data = segments
+        .iter()
+        .map(|segment| segment_data(&elf, segment))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut sorted = segments.clone();
+    sorted.sort_unstable_by_key(|segment| segment.vaddr);
+    if sorted
+        .windows(2)
+        .any(|pair| pair[0].vaddr + pair[0].memsz > pair[1].vaddr)
+    {
+        return Err(Error::OverlappingSegments);
+    }
+
+    let space = AddressSpace::new().map_err(Error::Memory)?;
+    map_segments(&space, &segments)?;
+    for (segment, data) in segments.iter().zip(data) {
+        space
+            .write(VirtAddr::new(segment.vaddr), data)
+            .map_err(Error::Memory)?;
+    }
+
+    let stack_bottom = VirtAddr::new(STACK_TOP - STACK_PAGES * PAGE_SIZE);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space
+        .map_region(stack_bottom, STACK_PAGES, flags)
+        .map_err(Error::Memory)?;
+
+    let auxv = [
+        (AT_PHDR, program_headers_addr(&elf, &segments)),
+        (AT_PHENT, mem::size_of::<ProgramHeader>() as u64),
+        (AT_PHNUM, elf.header().phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, entry),
+    ];
+    let stack_pointer = push_arguments(&space, args, &auxv)?;
+
+    Ok(Process {
+        space,
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// Check that `segment` can be loaded and return its file data
+fn segment_data<'a>(elf: &Elf<'a>, segment: &ProgramHeader) -> Result<&'a [u8], Error> {
+    if segment.is_writable() && segment.is_executable() {
+        return Err(Error::WritableAndExecutable);
+    }
+    if segment.filesz > segment.memsz {
+        return Err(Error::BadSegmentSize);
+    }
+    segment
+        .vaddr
+        .checked_add(segment.memsz)
+        .filter(|&end| segment.vaddr >= USER_START && end <= USER_END)
+        .ok_or(Error::BadSegmentAddress)?;
+    elf.segment_data(segment).ok_or(Error::SegmentOutOfFile)
+}
+
+/// Returns the first and the end page address of `segment`
+fn page_span(segment: &ProgramHeader) -> (u64, u64) {
+    let start = segment.vaddr & !(PAGE_SIZE - 1);
+    let end = (segment.vaddr + segment.memsz + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    (start, end)
+}
+
+/// Map the pages of `segments`, each page once, with the permissions of all
+/// segments that touch it
+fn map_segments(space: &AddressSpace, segments: &[ProgramHeader]) -> Result<(), Error> {
+    let mut bounds = Vec::with_capacity(segments.len() * 2);
+    for segment in segments {
+        let (start, end) = page_span(segment);
+        bounds.push(start);
+        bounds.push(end);
+    }
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    // consecutive bounds enclose pages touched by the same segments
+    let mut runs: Vec<(u64, u64, PageTableFlags)> = Vec::new();
+    for window in bounds.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        let (mut touched, mut writable, mut executable) = (false, false, false);
+        for segment in segments {
+            let (first, last) = page_span(segment);
+            if first <= start && end <= last {
+                touched = true;
+                writable |= segment.is_writable();
+                executable |= segment.is_executable();
+            }
+        }
+        if !touched {
+            continue;
+        }
+        if writable && executable {
+            return Err(Error::WritableAndExecutable);
+        }
+
+        let mut flags = PageTableFlags::empty();
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        match runs.last_mut() {
+            Some(run) if run.1 == start && run.2 == flags => run.1 = end,
+            _ => runs.push((start, end, flags)),
+        }
+    }
+
+    for (start, end, flags) in runs {
+        space
+            .map_region(VirtAddr::new(start), (end - start) / PAGE_SIZE, flags)
+            .map_err(Error::Memory)?;
+    }
+    Ok(())
+}
+
+/// Returns where the program headers are loaded, or 0 if no segment contains them
+fn program_headers_addr(elf: &Elf, segments: &[ProgramHeader]) -> u64 {
+    let phoff = elf.header().phoff;
+    segments
+        .iter()
+        .find(|segment| segment.offset <= phoff && phoff - segment.offset < segment.filesz)
+        .map_or(0, |segment| segment.vaddr + (phoff - segment.offset))
+}
+
+/// Write the arguments and the vectors below `STACK_TOP`, returning the stack pointer
+fn push_arguments(
+    space: &AddressSpace,
+    args: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, Error> {
+    let strings_len: u64 = args.iter().map(|arg| arg.len() as u64 + 1).sum();
+    // argc, argv and its terminator, the empty environment and auxv with AT_NULL
+    let word_count = 1 + args.len() as u64 + 1 + 1 + 2 * (auxv.len() as u64 + 1);
+    // leave half of the stack to the program
+    if strings_len + word_count * 8 + 16 > STACK_PAGES * PAGE_SIZE / 2 {
+        return Err(Error::ArgumentsTooLarge);
+    }
+    let strings = STACK_TOP - strings_len;
+    let stack_pointer = (strings - word_count * 8) & !0xf;
+
+    let mut words: Vec<u64> = Vec::with_capacity(word_count as usize);
+    let mut image: Vec<u8> = Vec::with_capacity(strings_len as usize);
+    words.push(args.len() as u64);
+    for arg in args {
+        words.push(strings + image.len() as u64);
+        image.extend_from_slice(arg.as_bytes());
+        image.push(0);
+    }
+    words.push(0);
+    words.push(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.push(key);
+        words.push(value);
+    }
+
+    let mut vectors = Vec::with_capacity(words.len() * 8);
+    for word in words {
+        vectors.extend_from_slice(&word.to_le_bytes());
+    }
+    space
+        .write(VirtAddr::new(strings), &image)
+        .map_err(Error::Memory)?;
+    space
+        .write(VirtAddr::new(stack_pointer), &vectors)
+        .map_err(Error::Memory)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
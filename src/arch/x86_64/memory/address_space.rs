@@ -0,0 +1,586 @@
+//! Per-process address spaces.
+//!
+//! Every address space has its own level 4 table. The entries outside of the
+//! user range are copied from the kernel's table, so all address spaces share
+//! the kernel's mappings. Kernel mappings created later are only shared if
+//! they go through an entry that existed when the address space was created,
+//! which is why `init` allocates the level 3 table of the VMM arena up front.
+//!
+//! User regions live in `USER_START..USER_END` and are private to their
+//! address space. Their frames and page tables are freed when it is dropped.
+//!
+//! `fork` clones an address space without copying its memory. Both address
+//! spaces share the frames, and writable pages are mapped read-only and
+//! marked copy-on-write in both. The first write to such a page faults, and
+//! the fault handler gives the writer a private copy. `CR0.WP` is set so
+//! writes from the kernel are caught as well.
+
+use crate::arch::memory::{self, frame, phys_to_virt, vmm, vmm::Error};
+use crate::sync::{IrqSpinLock, LockClass};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags},
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::UnmapError, Mapper, MapperAllSizes, OffsetPageTable, Page, PageTable,
+        PageTableEntry, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Start of the user range, the first address of level 4 entry 128
+pub const USER_START: u64 = 0x0000_4000_0000_0000;
+/// End of the user range, the end of the lower half
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+const PAGE_SIZE: u64 = vmm::PAGE_SIZE;
+
+/// Marks a read-only page that is writable once it is copied
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Physical address of the kernel's level 4 table
+static KERNEL_L4: AtomicU64 = AtomicU64::new(0);
+
+static INNER_CLASS: LockClass = LockClass::new("memory::AddressSpace::inner");
+
+fn user_entries() -> core::ops::Range<usize> {
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_START)).p4_index();
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_END - 1)).p4_index();
+    usize::from(first)..usize::from(last) + 1
+}
+
+fn zeroed_frame() -> Option<PhysFrame> {
+    let frame = frame::allocate(0)?;
+    let ptr = frame::frame_to_virt(frame).as_mut_ptr::<u8>();
+    unsafe { ptr::write_bytes(ptr, 0, PAGE_SIZE as usize) };
+    Some(frame)
+}
+
+/// Record the kernel's page table and prepare the entries shared with address spaces.
+///
+/// Must be called after the VMM is initialized.
+pub fn init() {
+    let (kernel_l4, _) = Cr3::read();
+    KERNEL_L4.store(kernel_l4.start_address().as_u64(), Ordering::Relaxed);
+
+    memory::with_page_table(|mapper| {
+        let l4 = mapper.level_4_table();
+        for index in user_entries() {
+            assert!(
+                l4[index].is_unused(),
+                "level 4 entry {} of the user range is used by the kernel",
+                index
+            );
+        }
+
+        let arena =
+            Page::<Size4KiB>::containing_address(VirtAddr::new(vmm::ARENA_START)).p4_index();
+        if l4[arena].is_unused() {
+            // nothing in the arena is ever executed
+            let frame = zeroed_frame().expect("no frame for the VMM arena");
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            l4[arena].set_frame(frame, flags);
+        }
+    });
+
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+/// Switch back to the kernel's page table
+pub fn activate_kernel() {
+    let frame = PhysFrame::containing_address(PhysAddr::new(KERNEL_L4.load(Ordering::Relaxed)));
+    if Cr3::read().0 != frame {
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+}
+
+/// A private region of user memory
+#[derive(Clone, Copy, Debug)]
+pub struct UserRegion {
+    pub start: VirtAddr,
+    pub pages: u64,
+    pub flags: PageTableFlags,
+}
+
+impl UserRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.pages * PAGE_SIZE
+    }
+
+    fn page(&self, index: u64) -> Page {
+        Page::containing_address(self.start + index * PAGE_SIZE)
+    }
+}
+
+struct Inner {
+    regions: BTreeMap<u64, UserRegion>,
+}
+
+pub struct AddressSpace {
+    l4: PhysFrame,
+    inner: IrqSpinLock<Inner>,
+}
+
+impl AddressSpace {
+    /// Create an address space sharing the kernel's mappings and without user regions
+    pub fn new() -> Result<AddressSpace, Error> {
+        let l4 = zeroed_frame().ok_or(Error::OutOfMemory)?;
+
+        let kernel = PhysAddr::new(KERNEL_L4.load(Ordering::Relaxed));
+        assert!(!kernel.is_null(), "address spaces are not initialized");
+        unsafe {
+            let kernel = &*phys_to_virt(kernel).as_ptr::<PageTable>();
+            let table = &mut *frame::frame_to_virt(l4).as_mut_ptr::<PageTable>();
+            let user = user_entries();
+            for (index, entry) in kernel.iter().enumerate() {
+                if !user.contains(&index) {
+                    table[index] = entry.clone();
+                }
+            }
+        }
+
+        Ok(AddressSpace {
+            l4,
+            inner: IrqSpinLock::with_class(
+                Inner {
+                    regions: BTreeMap::new(),
+                },
+                &INNER_CLASS,
+            ),
+        })
+    }
+
+    /// Returns the frame of the level 4 table, as loaded into CR3
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4
+    }
+
+    /// Load this address space into CR3
+    pub fn activate(&self) {
+        if !self.is_active() {
+            unsafe { Cr3::write(self.l4, Cr3Flags::empty()) };
+        }
+    }
+
+    /// Returns a mapper for the page table of this address space.
+    ///
+    /// The caller must hold `inner` for as long as the mapper is used.
+    unsafe fn mapper(&self) -> OffsetPageTable<'_> {
+        OffsetPageTable::new(self.level_4_table(), memory::physical_memory_offset())
+    }
+
+    /// Returns the level 4 table of this address space.
+    ///
+    /// The caller must hold `inner` for as long as the table is used.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn level_4_table(&self) -> &mut PageTable {
+        &mut *frame::frame_to_virt(self.l4).as_mut_ptr::<PageTable>()
+    }
+
+    /// Map `pages` fresh zeroed pages at `start`, accessible from user mode
+    pub fn map_region(
+        &self,
+        start: VirtAddr,
+        pages: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), Error> {
+        let region = UserRegion {
+            start,
+            pages,
+            flags: flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+        };
+        if pages == 0
+            || !start.is_aligned(PAGE_SIZE)
+            || start.as_u64() < USER_START
+            || USER_END - start.as_u64() < pages * PAGE_SIZE
+        {
+            return Err(Error::InvalidRange);
+        }
+
+        let mut inner = self.inner.lock();
+        let overlaps = inner
+            .regions
+            .range(..region.end().as_u64())
+            .next_back()
+            .map_or(false, |(_, r)| r.end() > start);
+        if overlaps {
+            return Err(Error::Overlap);
+        }
+
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        let mut frame_allocator = frame::GlobalFrameAllocator;
+        for index in 0..pages {
+            let page = region.page(index);
+            let result = zeroed_frame().ok_or(Error::OutOfMemory).and_then(|frame| {
+                match unsafe { mapper.map_to(page, frame, region.flags, &mut frame_allocator) } {
+                    Ok(flush) => {
+                        if active {
+                            flush.flush();
+                        } else {
+                            flush.ignore();
+                        }
+                        Ok(())
+                    }
+                    Err(_) => {
+                        unsafe { frame::deallocate(frame, 0) };
+                        Err(Error::OutOfMemory)
+                    }
+                }
+            });
+
+            if let Err(err) = result {
+                unmap_pages(&mut mapper, &region, index, active);
+                return Err(err);
+            }
+            set_user_accessible(mapper.level_4_table(), page);
+        }
+
+        inner.regions.insert(start.as_u64(), region);
+        Ok(())
+    }
+
+    /// Unmap a region and free its frames
+    pub fn unmap_region(&self, start: VirtAddr) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+        let region = inner
+            .regions
+            .remove(&start.as_u64())
+            .ok_or(Error::UnknownRegion)?;
+        let mut mapper = unsafe { self.mapper() };
+        unmap_pages(&mut mapper, &region, region.pages, self.is_active());
+        Ok(())
+    }
+
+    /// Change the flags of a region. User access is always allowed.
+    ///
+    /// Shared pages stay read-only and become copy-on-write if the region is writable.
+    pub fn protect(&self, start: VirtAddr, flags: PageTableFlags) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+        let region = inner
+            .regions
+            .get_mut(&start.as_u64())
+            .ok_or(Error::UnknownRegion)?;
+        region.flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        let active = self.is_active();
+        let l4 = unsafe { self.level_4_table() };
+        for index in 0..region.pages {
+            let page = region.page(index);
+            if let Some(entry) = leaf_entry(l4, page) {
+                let frame = PhysFrame::containing_address(entry.addr());
+                if region.flags.contains(PageTableFlags::WRITABLE) && frame::owners(frame) > 1 {
+                    entry.set_flags((region.flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE);
+                } else {
+                    entry.set_flags(region.flags);
+                }
+                if active {
+                    tlb::flush(page.start_address());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a copy of this address space that shares its frames copy-on-write
+    pub fn fork(&self) -> Result<AddressSpace, Error> {
+        let child = AddressSpace::new()?;
+
+        let inner = self.inner.lock();
+        let mut child_inner = child.inner.lock();
+        let l4 = unsafe { self.level_4_table() };
+        let mut child_mapper = unsafe { child.mapper() };
+        let mut frame_allocator = frame::GlobalFrameAllocator;
+
+        let mut clone = || {
+            for region in inner.regions.values() {
+                child_inner.regions.insert(region.start.as_u64(), *region);
+                for index in 0..region.pages {
+                    let page = region.page(index);
+                    let entry = match leaf_entry(l4, page) {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    let mut flags = entry.flags();
+                    if flags.contains(PageTableFlags::WRITABLE) {
+                        flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                        entry.set_flags(flags);
+                    }
+
+                    frame::share(frame);
+                    match unsafe { child_mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+                        Ok(flush) => flush.ignore(),
+                        Err(_) => {
+                            unsafe { frame::release(frame) };
+                            return Err(Error::OutOfMemory);
+                        }
+                    }
+                    set_user_accessible(child_mapper.level_4_table(), page);
+                }
+            }
+            Ok(())
+        };
+        let result = clone();
+
+        // pages that were made read-only may still be writable in the TLB
+        if self.is_active() {
+            tlb::flush_all();
+        }
+
+        drop(child_inner);
+        result.map(|_| child)
+    }
+
+    /// Returns the physical address `addr` is mapped to in this address space
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let _inner = self.inner.lock();
+        unsafe { self.mapper() }.translate_addr(addr)
+    }
+
+    /// Returns the user regions ordered by address
+    pub fn regions(&self) -> Vec<UserRegion> {
+        self.inner.lock().regions.values().copied().collect()
+    }
+
+    /// Copy `data` to `addr` in this address space, which does not need to be active
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < data.len() {
+            let (ptr, len) = self.user_chunk(addr + done as u64, data.len() - done, true)?;
+            unsafe { ptr::copy_nonoverlapping(data[done..].as_ptr(), ptr, len) };
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Copy from `addr` in this address space into `buf`
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (ptr, len) = self.user_chunk(addr + done as u64, buf.len() - done, false)?;
+            unsafe { ptr::copy_nonoverlapping(ptr, buf[done..].as_mut_ptr(), len) };
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Returns a kernel pointer to `addr` and how many of `len` bytes fit into its page.
+    ///
+    /// A copy-on-write page is copied first if it is going to be written.
+    fn user_chunk(
+        &self,
+        addr: VirtAddr,
+        len: usize,
+        write: bool,
+    ) -> Result<(*mut u8, usize), Error> {
+        if addr.as_u64() < USER_START || addr.as_u64() >= USER_END {
+            return Err(Error::InvalidRange);
+        }
+
+        let _inner = self.inner.lock();
+        let page = Page::containing_address(addr);
+        let entry = leaf_entry(unsafe { self.level_4_table() }, page).ok_or(Error::NotMapped)?;
+        if write && entry.flags().contains(COPY_ON_WRITE) {
+            if !break_cow(entry) {
+                return Err(Error::OutOfMemory);
+            }
+            if self.is_active() {
+                tlb::flush(page.start_address());
+            }
+        }
+
+        let phys = entry.addr() + addr.as_u64() % PAGE_SIZE;
+        let in_page = (PAGE_SIZE - addr.as_u64() % PAGE_SIZE) as usize;
+        Ok((phys_to_virt(phys).as_mut_ptr(), len.min(in_page)))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+
+        let inner = self.inner.lock();
+        let mut mapper = unsafe { self.mapper() };
+        for region in inner.regions.values() {
+            unmap_pages(&mut mapper, region, region.pages, false);
+        }
+
+        unsafe {
+            let l4 = &mut *frame::frame_to_virt(self.l4).as_mut_ptr::<PageTable>();
+            for index in user_entries() {
+                free_table(&mut l4[index], 3);
+            }
+            frame::deallocate(self.l4, 0);
+        }
+    }
+}
+
+/// Resolve a write to a copy-on-write page of the active address space.
+///
+/// Called from the page fault handler, returns whether the fault was handled.
+/// The handler runs with interrupts disabled and the owner of the address
+/// space only changes its page table while holding an interrupt-disabling
+/// lock, so the table is not locked here.
+pub fn handle_page_fault(addr: VirtAddr, err: PageFaultErrorCode) -> bool {
+    let write = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !err.contains(write) || addr.as_u64() < USER_START || addr.as_u64() >= USER_END {
+        return false;
+    }
+
+    let (l4, _) = Cr3::read();
+    let l4 = unsafe { &mut *frame::frame_to_virt(l4).as_mut_ptr::<PageTable>() };
+    let page = Page::containing_address(addr);
+    match leaf_entry(l4, page) {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => {
+            if !break_cow(entry) {
+                return false;
+            }
+            tlb::flush(page.start_address());
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if user code may access `addr..addr + len` in the active address space.
+///
+/// Copy-on-write pages count as writable, as the first write copies them.
+pub fn is_user_accessible(addr: VirtAddr, len: u64, write: bool) -> bool {
+    let end = match addr.as_u64().checked_add(len) {
+        Some(end) if addr.as_u64() >= USER_START && end <= USER_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+
+    let (l4, _) = Cr3::read();
+    let l4 = unsafe { &mut *frame::frame_to_virt(l4).as_mut_ptr::<PageTable>() };
+    let first = Page::containing_address(addr);
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| match leaf_entry(l4, page) {
+        Some(entry) => {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                && (!write || flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE))
+        }
+        None => false,
+    })
+}
+
+/// Make a copy-on-write page writable, copying its frame if it is still shared.
+///
+/// Returns false if no frame is available for the copy. Called from the page
+/// fault handler, so the frame allocator is only tried and a fault taken while
+/// holding it is reported as genuine.
+fn break_cow(entry: &mut PageTableEntry) -> bool {
+    let mut allocator = match frame::FRAME_ALLOCATOR.try_lock() {
+        Some(allocator) => allocator,
+        None => return false,
+    };
+    let shared = PhysFrame::containing_address(entry.addr());
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if allocator.owners(shared) == 1 {
+        entry.set_flags(flags);
+        return true;
+    }
+
+    let copy = match allocator.allocate(0) {
+        Some(copy) => copy,
+        None => return false,
+    };
+    unsafe {
+        ptr::copy_nonoverlapping(
+            frame::frame_to_virt(shared).as_ptr::<u8>(),
+            frame::frame_to_virt(copy).as_mut_ptr::<u8>(),
+            PAGE_SIZE as usize,
+        );
+    }
+    entry.set_frame(copy, flags);
+    unsafe { allocator.release(shared) };
+    true
+}
+
+/// Returns the present level 1 entry mapping `page`
+fn leaf_entry(l4: &mut PageTable, page: Page) -> Option<&mut PageTableEntry> {
+    let indexes: [PageTableIndex; 3] = [page.p4_index(), page.p3_index(), page.p2_index()];
+    let mut table = l4;
+    for &index in indexes.iter() {
+        let entry = &table[index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+    }
+
+    let entry = &mut table[page.p1_index()];
+    if entry.flags().contains(PageTableFlags::PRESENT) {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// Unmap the first `pages` pages of a region and release their frames
+fn unmap_pages(mapper: &mut OffsetPageTable, region: &UserRegion, pages: u64, active: bool) {
+    for index in 0..pages {
+        match mapper.unmap(region.page(index)) {
+            Ok((frame, flush)) => {
+                if active {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+                unsafe { frame::release(frame) };
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => panic!("failed to unmap {:?}: {:?}", region.page(index), err),
+        }
+    }
+}
+
+/// Allow user access on the table entries leading to `page`
+fn set_user_accessible(l4: &mut PageTable, page: Page) {
+    let indexes: [PageTableIndex; 3] = [page.p4_index(), page.p3_index(), page.p2_index()];
+    let mut table = l4;
+    for &index in indexes.iter() {
+        let entry = &mut table[index];
+        let flags = entry.flags() | PageTableFlags::USER_ACCESSIBLE;
+        let addr = entry.addr();
+        entry.set_addr(addr, flags);
+        table = unsafe { &mut *phys_to_virt(addr).as_mut_ptr::<PageTable>() };
+    }
+}
+
+/// Free the page table referenced by `entry` and all tables below it.
+///
+/// `level` is the level of the referenced table. Frames mapped by level 1
+/// tables must already be unmapped.
+unsafe fn free_table(entry: &mut PageTableEntry, level: u8) {
+    if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level > 1 {
+        let table = &mut *frame::frame_to_virt(frame).as_mut_ptr::<PageTable>();
+        for entry in table.iter_mut() {
+            free_table(entry, level - 1);
+        }
+    }
+    frame::deallocate(frame, 0);
+    entry.set_unused();
+}
@@ -0,0 +1,184 @@
+//! Heap debugging, enabled with the `heap-debug` feature.
+//!
+//! Every allocation is surrounded by red zones filled with a canary and
+//! preceded by a header recording its layout:
+//!
+//! ```text
+//! | padding | Header | red zone | object | red zone |
+//! ```
+//!
+//! Freeing an object checks the header against the passed `Layout` and both
+//! red zones against the canary, then poisons the object. Freed blocks wait in
+//! a quarantine before they are reused, so double frees and writes after free
+//! are caught in the meantime. Any failed check panics with a report.
+
+use super::slab::SlabAllocator;
+use crate::sync::IrqLock;
+use core::alloc::Layout;
+use core::{mem, ptr};
+
+/// Size of each red zone in bytes
+pub const RED_ZONE: usize = 16;
+
+pub const CANARY: u8 = 0xfd;
+/// Fills new objects, so reads of uninitialized memory stand out
+pub const ALLOC_POISON: u8 = 0xcd;
+/// Fills freed objects
+pub const FREE_POISON: u8 = 0xdd;
+
+/// Number of freed blocks held back from reuse
+pub const QUARANTINE_SIZE: usize = 32;
+
+const ALLOCATED: usize = 0x5afe_5afe_5afe_5afe;
+const FREED: usize = 0xdead_dead_dead_dead;
+
+/// Placed directly before the first red zone
+#[repr(C)]
+struct Header {
+    size: usize,
+    align: usize,
+    /// Last, so that it survives the free list link the slab caches write at
+    /// the start of a freed block
+    state: usize,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+struct Quarantine {
+    blocks: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+// Only accessed with the heap allocator locked
+static QUARANTINE: IrqLock<Quarantine> = IrqLock::new(Quarantine {
+    blocks: [None; QUARANTINE_SIZE],
+    next: 0,
+});
+
+/// Bytes between the start of the block and the object
+fn prefix(align: usize) -> usize {
+    (HEADER_SIZE + RED_ZONE + align - 1) & !(align - 1)
+}
+
+/// Layout of the block holding an object of `layout`
+fn block_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(
+        prefix(layout.align()) + layout.size() + RED_ZONE,
+        layout.align().max(mem::align_of::<Header>()),
+    )
+    .expect("heap-debug: layout too large for red zones")
+}
+
+unsafe fn header(object: *mut u8) -> *mut Header {
+    object.sub(RED_ZONE + HEADER_SIZE) as *mut Header
+}
+
+/// Allocate an object surrounded by red zones
+pub(super) unsafe fn alloc(allocator: &mut SlabAllocator, layout: Layout) -> *mut u8 {
+    let block = allocator.allocate(block_layout(layout));
+    if block.is_null() {
+        return block;
+    }
+
+    let object = block.add(prefix(layout.align()));
+    header(object).write(Header {
+        size: layout.size(),
+        align: layout.align(),
+        state: ALLOCATED,
+    });
+    ptr::write_bytes(object.sub(RED_ZONE), CANARY, RED_ZONE);
+    ptr::write_bytes(object, ALLOC_POISON, layout.size());
+    ptr::write_bytes(object.add(layout.size()), CANARY, RED_ZONE);
+    object
+}
+
+/// Check and poison an object, then move it to the quarantine
+pub(super) unsafe fn dealloc(allocator: &mut SlabAllocator, object: *mut u8, layout: Layout) {
+    let header = &mut *header(object);
+    match header.state {
+        ALLOCATED => {}
+        FREED => panic!(
+            "heap-debug: double free of {:p} (size {}, align {})",
+            object,
+            layout.size(),
+            layout.align()
+        ),
+        state => panic!(
+            "heap-debug: freeing {:p} (size {}, align {}), which is not a live heap object: header state is {:#x}",
+            object,
+            layout.size(),
+            layout.align(),
+            state
+        ),
+    }
+
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!(
+            "heap-debug: layout mismatch freeing {:p} with size {}, align {}, but it was allocated with size {}, align {}",
+            object,
+            layout.size(),
+            layout.align(),
+            header.size,
+            header.align
+        );
+    }
+
+    check_red_zone(object, layout, object.sub(RED_ZONE), "underflow");
+    check_red_zone(object, layout, object.add(layout.size()), "overflow");
+
+    header.state = FREED;
+    ptr::write_bytes(object, FREE_POISON, layout.size());
+
+    let evicted = {
+        let mut quarantine = QUARANTINE.lock();
+        let next = quarantine.next;
+        quarantine.next = (next + 1) % QUARANTINE_SIZE;
+        mem::replace(
+            &mut quarantine.blocks[next],
+            Some((object as usize, layout)),
+        )
+    };
+
+    if let Some((object, layout)) = evicted {
+        let object = object as *mut u8;
+        check_poison(object, layout);
+        allocator.deallocate(object.sub(prefix(layout.align())), block_layout(layout));
+    }
+}
+
+unsafe fn check_red_zone(object: *mut u8, layout: Layout, zone: *const u8, kind: &str) {
+    for i in 0..RED_ZONE {
+        let byte = *zone.add(i);
+        if byte != CANARY {
+            panic!(
+                "heap-debug: buffer {} of {:p} (size {}, align {}): red zone byte {} at {:p} is {:#04x}, expected {:#04x}",
+                kind,
+                object,
+                layout.size(),
+                layout.align(),
+                i,
+                zone.add(i),
+                byte,
+                CANARY
+            );
+        }
+    }
+}
+
+/// Make sure a quarantined object was not written to after it was freed
+unsafe fn check_poison(object: *mut u8, layout: Layout) {
+    for i in 0..layout.size() {
+        let byte = *object.add(i);
+        if byte != FREE_POISON {
+            panic!(
+                "heap-debug: use after free of {:p} (size {}, align {}): byte {} is {:#04x}, expected {:#04x}",
+                object,
+                layout.size(),
+                layout.align(),
+                i,
+                byte,
+                FREE_POISON
+            );
+        }
+    }
+}
@@ -1,3 +1,5 @@
-pub mod fixed_size_block;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod slab;
 
-pub use fixed_size_block::FixedSizeBlockAllocator;
+pub use slab::{CacheStats, KmemBox, KmemCache, SlabAllocator, SlabCache};
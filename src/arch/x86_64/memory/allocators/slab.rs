@@ -0,0 +1,571 @@
+//! Slab allocator.
+//!
+//! Objects of one size are carved out of slabs, naturally aligned blocks that
+//! are a page large unless that would hold fewer than `MIN_OBJECTS_PER_SLAB`
+//! objects. Each slab starts with a header listing its free objects, so a
+//! freed object finds its slab by masking its address. A slab that becomes
+//! empty is returned to the backing heap once more than `MAX_EMPTY_SLABS` are
+//! cached.
+
+#[cfg(feature = "heap-debug")]
+use super::debug;
+use crate::arch::memory::heap::{self, Locked};
+use crate::sync::{IrqSpinLock, LockClass};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::{
+    mem,
+    ptr::{self, NonNull},
+};
+
+/// The object sizes of the general purpose caches.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the object alignment (alignments must be always powers of 2).
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+const PAGE_SIZE: usize = 4096;
+
+/// Slabs are enlarged until they hold at least this many objects
+pub const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Number of empty slabs a cache keeps instead of returning them
+pub const MAX_EMPTY_SLABS: usize = 1;
+
+/// Choose an appropriate cache for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+pub(crate) fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at the start of every slab
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// Usage statistics of a cache
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slab_size: usize,
+    pub objects_per_slab: usize,
+    /// Slabs currently owned by the cache, including empty ones
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    /// Slabs returned to the backing heap over the lifetime of the cache
+    pub slabs_released: u64,
+}
+
+/// A cache of equally sized objects.
+///
+/// The cache does not allocate memory itself. When it runs out of objects the
+/// caller adds a slab of `slab_layout()`, and slabs handed back by `free` and
+/// `take_empty` must be returned to wherever they came from.
+pub struct SlabCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    /// Geometry, computed when the first slab is requested
+    object_size: usize,
+    slab_size: usize,
+    first_object: usize,
+    objects_per_slab: usize,
+    /// Slabs with both used and free objects. Full slabs are not tracked.
+    partial: *mut Slab,
+    empty: *mut Slab,
+    empty_slabs: usize,
+    slabs: usize,
+    objects_in_use: usize,
+    allocations: u64,
+    frees: u64,
+    slabs_released: u64,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> SlabCache {
+        SlabCache {
+            name,
+            size,
+            align,
+            object_size: 0,
+            slab_size: 0,
+            first_object: 0,
+            objects_per_slab: 0,
+            partial: ptr::null_mut(),
+            empty: ptr::null_mut(),
+            empty_slabs: 0,
+            slabs: 0,
+            objects_in_use: 0,
+            allocations: 0,
+            frees: 0,
+            slabs_released: 0,
+        }
+    }
+
+    fn init_geometry(&mut self) {
+        if self.slab_size != 0 {
+            return;
+        }
+
+        let align = self.align.max(mem::align_of::<FreeObject>());
+        let object_size = round_up(self.size.max(mem::size_of::<FreeObject>()), align);
+        let first_object = round_up(mem::size_of::<Slab>(), align);
+        let mut slab_size = PAGE_SIZE.max(align);
+        while (slab_size - first_object) / object_size < MIN_OBJECTS_PER_SLAB {
+            slab_size *= 2;
+        }
+
+        self.object_size = object_size;
+        self.first_object = first_object;
+        self.slab_size = slab_size;
+        self.objects_per_slab = (slab_size - first_object) / object_size;
+    }
+
+    /// Returns the layout of the slabs this cache expects
+    pub fn slab_layout(&mut self) -> Layout {
+        self.init_geometry();
+        Layout::from_size_align(self.slab_size, self.slab_size).unwrap()
+    }
+
+    /// Hand a new slab of `slab_layout()` to the cache.
+    ///
+    /// This function is unsafe because the caller must guarantee that the slab
+    /// is unused, valid for writes and laid out as returned by `slab_layout`.
+    pub unsafe fn add_slab(&mut self, ptr: *mut u8) {
+        self.init_geometry();
+
+        let mut free = ptr::null_mut();
+        for i in (0..self.objects_per_slab).rev() {
+            let object = ptr.add(self.first_object + i * self.object_size) as *mut FreeObject;
+            object.write(FreeObject { next: free });
+            free = object;
+        }
+
+        let slab = ptr as *mut Slab;
+        slab.write(Slab {
+            next: self.empty,
+            prev: ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        self.empty = slab;
+        self.empty_slabs += 1;
+        self.slabs += 1;
+    }
+
+    /// Allocate an object from the slabs owned by the cache.
+    ///
+    /// Returns a null pointer if all slabs are full.
+    pub fn alloc(&mut self) -> *mut u8 {
+        let slab = if !self.partial.is_null() {
+            self.partial
+        } else if !self.empty.is_null() {
+            let slab = self.empty;
+            unsafe {
+                self.empty = (*slab).next;
+                self.push_partial(slab);
+            }
+            self.empty_slabs -= 1;
+            slab
+        } else {
+            return ptr::null_mut();
+        };
+
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.unlink_partial(slab);
+            }
+
+            self.objects_in_use += 1;
+            self.allocations += 1;
+            object as *mut u8
+        }
+    }
+
+    /// Return an object to its slab.
+    ///
+    /// If that leaves more than `MAX_EMPTY_SLABS` empty slabs, the now empty
+    /// slab is returned and must be freed by the caller. Otherwise a null
+    /// pointer is returned.
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was returned by `alloc` on this cache and is no longer in use.
+    pub unsafe fn free(&mut self, ptr: *mut u8) -> *mut u8 {
+        let slab = (ptr as usize & !(self.slab_size - 1)) as *mut Slab;
+        let was_full = (*slab).free.is_null();
+
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject { next: (*slab).free });
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        self.objects_in_use -= 1;
+        self.frees += 1;
+
+        if (*slab).in_use == 0 {
+            if !was_full {
+                self.unlink_partial(slab);
+            }
+            if self.empty_slabs < MAX_EMPTY_SLABS {
+                (*slab).next = self.empty;
+                self.empty = slab;
+                self.empty_slabs += 1;
+                return ptr::null_mut();
+            }
+            self.slabs -= 1;
+            self.slabs_released += 1;
+            slab as *mut u8
+        } else {
+            if was_full {
+                self.push_partial(slab);
+            }
+            ptr::null_mut()
+        }
+    }
+
+    /// Remove an empty slab from the cache so it can be freed by the caller
+    pub fn take_empty(&mut self) -> Option<*mut u8> {
+        if self.empty.is_null() {
+            return None;
+        }
+
+        let slab = self.empty;
+        self.empty = unsafe { (*slab).next };
+        self.empty_slabs -= 1;
+        self.slabs -= 1;
+        self.slabs_released += 1;
+        Some(slab as *mut u8)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name: self.name,
+            object_size: self.object_size,
+            slab_size: self.slab_size,
+            objects_per_slab: self.objects_per_slab,
+            slabs: self.slabs,
+            objects_in_use: self.objects_in_use,
+            allocations: self.allocations,
+            frees: self.frees,
+            slabs_released: self.slabs_released,
+        }
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink_partial(&mut self, slab: *mut Slab) {
+        let Slab { next, prev, .. } = *slab;
+        if prev.is_null() {
+            self.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+}
+
+/// The kernel heap allocator.
+///
+/// Small allocations are served by one `SlabCache` per block size, larger
+/// ones and the slabs themselves by a linked list allocator.
+pub struct SlabAllocator {
+    caches: [SlabCache; BLOCK_SIZES.len()],
+    fallback_allocator: linked_list_allocator::Heap,
+}
+
+impl SlabAllocator {
+    /// Creates an empty SlabAllocator.
+    pub const fn new() -> Self {
+        SlabAllocator {
+            caches: [
+                SlabCache::new("size-8", 8, 8),
+                SlabCache::new("size-16", 16, 16),
+                SlabCache::new("size-32", 32, 32),
+                SlabCache::new("size-64", 64, 64),
+                SlabCache::new("size-128", 128, 128),
+                SlabCache::new("size-256", 256, 256),
+                SlabCache::new("size-512", 512, 512),
+                SlabCache::new("size-1024", 1024, 1024),
+                SlabCache::new("size-2048", 2048, 2048),
+            ],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Return all empty slabs to the fallback allocator. Returns the number of slabs freed.
+    pub fn reclaim(&mut self) -> usize {
+        let mut freed = 0;
+        for cache in self.caches.iter_mut() {
+            while let Some(slab) = cache.take_empty() {
+                let layout = cache.slab_layout();
+                unsafe {
+                    self.fallback_allocator
+                        .deallocate(NonNull::new_unchecked(slab), layout)
+                };
+                freed += 1;
+            }
+        }
+        freed
+    }
+
+    /// Returns the statistics of each general purpose cache, in `BLOCK_SIZES` order
+    pub fn stats(&self) -> [CacheStats; BLOCK_SIZES.len()] {
+        let mut stats = [self.caches[0].stats(); BLOCK_SIZES.len()];
+        for (stats, cache) in stats.iter_mut().zip(self.caches.iter()) {
+            *stats = cache.stats();
+        }
+        stats
+    }
+
+    /// Allocate a block for `layout` from a cache or the fallback allocator
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => self.alloc_object(index),
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    /// Free a block returned by `allocate`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was returned by `allocate` with the same layout and is no longer in use.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let slab = self.caches[index].free(ptr);
+                if !slab.is_null() {
+                    let layout = self.caches[index].slab_layout();
+                    self.fallback_allocator
+                        .deallocate(NonNull::new_unchecked(slab), layout);
+                }
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    fn alloc_object(&mut self, index: usize) -> *mut u8 {
+        let object = self.caches[index].alloc();
+        if !object.is_null() {
+            return object;
+        }
+
+        let layout = self.caches[index].slab_layout();
+        let slab = self.fallback_alloc(layout);
+        if slab.is_null() {
+            return slab;
+        }
+        unsafe { self.caches[index].add_slab(slab) };
+        self.caches[index].alloc()
+    }
+
+    /// Allocates using the fallback allocator.
+    ///
+    /// If it is exhausted, empty slabs are reclaimed before the heap is grown.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        if self.reclaim() > 0 {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+
+        if !heap::grow(&mut self.fallback_allocator, layout) {
+            return ptr::null_mut();
+        }
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+
+        #[cfg(not(feature = "heap-debug"))]
+        let ptr = allocator.allocate(layout);
+        #[cfg(feature = "heap-debug")]
+        let ptr = debug::alloc(&mut allocator, layout);
+
+        heap::account_alloc(ptr, layout);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+
+        #[cfg(not(feature = "heap-debug"))]
+        allocator.deallocate(ptr, layout);
+        #[cfg(feature = "heap-debug")]
+        debug::dealloc(&mut allocator, ptr, layout);
+
+        heap::account_dealloc(ptr, layout);
+    }
+}
+
+static KMEM_CACHE_CLASS: LockClass = LockClass::new("memory::slab::KmemCache::cache");
+
+/// A cache of objects of type `T`, each initialized by a constructor.
+///
+/// Slabs are allocated from the kernel heap.
+pub struct KmemCache<T> {
+    cache: IrqSpinLock<SlabCache>,
+    ctor: fn() -> T,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for KmemCache<T> {}
+unsafe impl<T: Send> Sync for KmemCache<T> {}
+
+impl<T> KmemCache<T> {
+    pub const fn new(name: &'static str, ctor: fn() -> T) -> KmemCache<T> {
+        KmemCache {
+            cache: IrqSpinLock::with_class(
+                SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>()),
+                &KMEM_CACHE_CLASS,
+            ),
+            ctor,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Allocate an object initialized by the constructor of this cache
+    pub fn alloc(&self) -> Option<KmemBox<T>> {
+        let mut cache = self.cache.lock();
+        let mut object = cache.alloc();
+
+        if object.is_null() {
+            // the heap is not called with the cache locked
+            let layout = cache.slab_layout();
+            cache.release();
+            let slab = unsafe { alloc::alloc::alloc(layout) };
+            if slab.is_null() {
+                return None;
+            }
+
+            cache = self.cache.lock();
+            unsafe { cache.add_slab(slab) };
+            object = cache.alloc();
+        }
+        cache.release();
+
+        let ptr = NonNull::new(object as *mut T)?;
+        unsafe { ptr.as_ptr().write((self.ctor)()) };
+        Some(KmemBox { cache: self, ptr })
+    }
+
+    /// Return all empty slabs to the heap. Returns the number of slabs freed.
+    pub fn reclaim(&self) -> usize {
+        let mut freed = 0;
+        loop {
+            let mut cache = self.cache.lock();
+            let layout = cache.slab_layout();
+            let slab = match cache.take_empty() {
+                Some(slab) => slab,
+                None => return freed,
+            };
+            cache.release();
+
+            unsafe { alloc::alloc::dealloc(slab, layout) };
+            freed += 1;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+
+    unsafe fn free(&self, ptr: NonNull<T>) {
+        ptr::drop_in_place(ptr.as_ptr());
+
+        let mut cache = self.cache.lock();
+        let layout = cache.slab_layout();
+        let slab = cache.free(ptr.as_ptr() as *mut u8);
+        cache.release();
+
+        if !slab.is_null() {
+            alloc::alloc::dealloc(slab, layout);
+        }
+    }
+}
+
+impl<T> Drop for KmemCache<T> {
+    fn drop(&mut self) {
+        // every object borrows the cache, so all slabs are empty by now
+        self.reclaim();
+    }
+}
+
+/// An object allocated from a `KmemCache`, returned to it when dropped
+pub struct KmemBox<'a, T> {
+    cache: &'a KmemCache<T>,
+    ptr: NonNull<T>,
+}
+
+unsafe impl<'a, T: Send> Send for KmemBox<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for KmemBox<'a, T> {}
+
+impl<'a, T> Deref for KmemBox<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<'a, T> DerefMut for KmemBox<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<'a, T> Drop for KmemBox<'a, T> {
+    fn drop(&mut self) {
+        unsafe { self.cache.free(self.ptr) };
+    }
+}
@@ -0,0 +1,101 @@
+//! Physically contiguous buffers for device DMA.
+//!
+//! A `DmaBuffer` is a block of contiguous frames from the buddy allocator,
+//! accessed through the physical memory mapping. Buddy blocks are naturally
+//! aligned to their size, so alignment is satisfied by allocating a block at
+//! least as large as the alignment. Devices that can only address part of
+//! physical memory pass a limit the whole buffer must lie below.
+
+use crate::arch::memory::frame::{self, MAX_ORDER};
+use core::slice;
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+const FRAME_SIZE: usize = 4096;
+
+/// No placement constraint
+pub const NO_LIMIT: u64 = u64::MAX;
+/// Limit for devices with 32 bit addressing
+pub const LIMIT_4GIB: u64 = 0x1_0000_0000;
+/// Limit for ISA DMA, which only reaches the first 16 MiB
+pub const LIMIT_ISA: u64 = 0x100_0000;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The size is zero or larger than the largest block of frames
+    InvalidSize,
+    /// The alignment is not a power of two or larger than the largest block of frames
+    InvalidAlignment,
+    /// No free block satisfies the constraints
+    OutOfMemory,
+}
+
+/// A zeroed, physically contiguous buffer whose frames are freed when it is dropped
+#[derive(Debug)]
+pub struct DmaBuffer {
+    frame: PhysFrame,
+    order: usize,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Allocate a page aligned buffer anywhere in physical memory
+    pub fn new(len: usize) -> Result<DmaBuffer, Error> {
+        DmaBuffer::with_constraints(len, FRAME_SIZE, NO_LIMIT)
+    }
+
+    /// Allocate a buffer aligned to `align` bytes that ends at or below the physical address `limit`
+    pub fn with_constraints(len: usize, align: usize, limit: u64) -> Result<DmaBuffer, Error> {
+        let max_size = FRAME_SIZE << MAX_ORDER;
+        if len == 0 || len > max_size {
+            return Err(Error::InvalidSize);
+        }
+        if !align.is_power_of_two() || align > max_size {
+            return Err(Error::InvalidAlignment);
+        }
+
+        let size = len.max(align).next_power_of_two().max(FRAME_SIZE);
+        let order = (size / FRAME_SIZE).trailing_zeros() as usize;
+        let frame = if limit == NO_LIMIT {
+            frame::allocate(order)
+        } else {
+            frame::allocate_below(order, PhysAddr::new(limit))
+        }
+        .ok_or(Error::OutOfMemory)?;
+
+        let buffer = DmaBuffer { frame, order, len };
+        unsafe { buffer.virt_addr().as_mut_ptr::<u8>().write_bytes(0, size) };
+        Ok(buffer)
+    }
+
+    /// Returns the physical address to program into the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frame.start_address()
+    }
+
+    /// Returns the address of the buffer in the physical memory mapping
+    pub fn virt_addr(&self) -> VirtAddr {
+        frame::frame_to_virt(self.frame)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { frame::deallocate(self.frame, self.order) };
+    }
+}
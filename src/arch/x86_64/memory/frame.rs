@@ -0,0 +1,458 @@
+//! Physical memory manager.
+//!
+//! Frames are handed out by a buddy allocator in blocks of `2^order` frames.
+//! Free blocks are kept in one list per order, linked through the blocks
+//! themselves via the physical memory mapping. One metadata byte per frame
+//! records whether a frame starts a free block and of which order, which lets
+//! freed blocks merge with their buddy in constant time.
+//!
+//! Frames can be shared, such as between address spaces after a fork. A
+//! counter per frame records its owners beyond the first, and a shared frame
+//! is only freed once its last owner releases it.
+
+use crate::arch::memory::phys_to_virt;
+use crate::sync::{IrqSpinLock, LockClass};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ptr;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Largest block order. Blocks of this order span 4 MiB.
+pub const MAX_ORDER: usize = 10;
+
+/// Order of the blocks backing a 2 MiB huge page
+pub const HUGE_PAGE_ORDER: usize = 9;
+
+const FRAME_SIZE: u64 = 4096;
+const NO_FRAME: usize = usize::MAX;
+
+static FRAME_ALLOCATOR_CLASS: LockClass = LockClass::new("memory::frame::FRAME_ALLOCATOR");
+
+pub static FRAME_ALLOCATOR: IrqSpinLock<BuddyFrameAllocator> =
+    IrqSpinLock::with_class(BuddyFrameAllocator::empty(), &FRAME_ALLOCATOR_CLASS);
+
+/// Links between free blocks, stored in the first bytes of each free block
+#[derive(Clone, Copy)]
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+pub struct BuddyFrameAllocator {
+    /// One byte per frame: `order + 1` if the frame starts a free block, 0 otherwise
+    meta: *mut u8,
+    /// One counter per frame: the number of owners besides the first
+    shares: *mut u16,
+    /// Number of frames covered by `meta` and `shares`
+    frames: usize,
+    free_lists: [usize; MAX_ORDER + 1],
+    usable_frames: usize,
+    free_frames: usize,
+}
+
+unsafe impl Send for BuddyFrameAllocator {}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator without any memory.
+    pub const fn empty() -> Self {
+        BuddyFrameAllocator {
+            meta: ptr::null_mut(),
+            shares: ptr::null_mut(),
+            frames: 0,
+            free_lists: [NO_FRAME; MAX_ORDER + 1],
+            usable_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Create an allocator managing the usable frames of the passed memory map.
+    ///
+    /// The metadata is placed in the first usable region large enough to hold it.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames marked as `USABLE` in it are really
+    /// unused, and that physical memory is mapped at the offset passed to
+    /// `memory::init`.
+    pub unsafe fn init(memory_map: &MemoryMap) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| {
+                    let start = (r.range.start_addr() + FRAME_SIZE - 1) / FRAME_SIZE;
+                    let end = r.range.end_addr() / FRAME_SIZE;
+                    (start as usize, end as usize)
+                })
+                .filter(|(start, end)| start < end)
+        };
+
+        let frames = usable().map(|(_, end)| end).max().unwrap_or(0);
+        // the share counters follow the metadata bytes, aligned to two bytes
+        let shares_offset = (frames + 1) & !1;
+        let meta_bytes = shares_offset + frames * 2;
+        let meta_frames = (meta_bytes + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+        let meta_start = usable()
+            .find(|(start, end)| end - start >= meta_frames)
+            .map(|(start, _)| start)
+            .expect("no usable region can hold the frame metadata");
+
+        let meta = phys_to_virt(PhysAddr::new(meta_start as u64 * FRAME_SIZE)).as_mut_ptr::<u8>();
+        ptr::write_bytes(meta, 0, meta_bytes);
+
+        let mut allocator = BuddyFrameAllocator {
+            meta,
+            shares: meta.add(shares_offset) as *mut u16,
+            frames,
+            ..BuddyFrameAllocator::empty()
+        };
+
+        let meta_end = meta_start + meta_frames;
+        for (start, end) in usable() {
+            if start < meta_end && meta_start < end {
+                allocator.add_range(start, meta_start.max(start));
+                allocator.add_range(meta_end.min(end), end);
+            } else {
+                allocator.add_range(start, end);
+            }
+        }
+
+        allocator
+    }
+
+    /// Add the frames `start..end` as free blocks that are as large as alignment allows
+    fn add_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let mut order = MAX_ORDER;
+            while start % (1 << order) != 0 || start + (1 << order) > end {
+                order -= 1;
+            }
+            self.push(start, order);
+            self.usable_frames += 1 << order;
+            self.free_frames += 1 << order;
+            start += 1 << order;
+        }
+    }
+
+    /// Allocate a naturally aligned block of `2^order` contiguous frames.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NO_FRAME)?;
+        let block = self.free_lists[current];
+        Some(self.take(block, current, order))
+    }
+
+    /// Allocate a naturally aligned block of `2^order` frames that ends at or below `limit`.
+    ///
+    /// Unlike `allocate`, this searches the free lists, so it is meant for the
+    /// rare allocations with placement constraints, such as DMA buffers.
+    pub fn allocate_below(&mut self, order: usize, limit: PhysAddr) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // the lowest part of a larger block can be split off as well
+        let limit = (limit.as_u64() / FRAME_SIZE) as usize;
+        for current in order..=MAX_ORDER {
+            let mut block = self.free_lists[current];
+            while block != NO_FRAME {
+                if block + (1 << order) <= limit {
+                    return Some(self.take(block, current, order));
+                }
+                block = unsafe { self.node(block).next };
+            }
+        }
+        None
+    }
+
+    /// Remove the free `block` of order `current` and split it down to `order`
+    fn take(&mut self, block: usize, mut current: usize, order: usize) -> PhysFrame {
+        self.remove(block, current);
+
+        // return the upper halves until the block has the requested size
+        while current > order {
+            current -= 1;
+            self.push(block + (1 << current), current);
+        }
+
+        self.free_frames -= 1 << order;
+        frame_at(block)
+    }
+
+    /// Free a block of `2^order` frames, merging it with free buddies.
+    ///
+    /// This function is unsafe because the caller must guarantee that the block
+    /// was returned by `allocate` with the same order and is no longer in use.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let mut block = index_of(frame);
+        assert!(
+            order <= MAX_ORDER,
+            "freeing block of invalid order {}",
+            order
+        );
+        assert!(
+            block + (1 << order) <= self.frames,
+            "freeing frame {:#x} not managed by the frame allocator",
+            frame.start_address().as_u64()
+        );
+        assert_eq!(
+            block % (1 << order),
+            0,
+            "freeing misaligned block of order {}",
+            order
+        );
+        assert!(
+            self.free_block_containing(block, order).is_none(),
+            "double free of frame {:#x}",
+            frame.start_address().as_u64()
+        );
+        assert_eq!(
+            *self.shares.add(block),
+            0,
+            "freeing shared frame {:#x}",
+            frame.start_address().as_u64()
+        );
+
+        self.free_frames += 1 << order;
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if buddy >= self.frames || *self.meta.add(buddy) != order as u8 + 1 {
+                break;
+            }
+            self.remove(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+
+        self.push(block, order);
+    }
+
+    /// Add an owner to an allocated frame
+    pub fn share(&mut self, frame: PhysFrame) {
+        let block = self.allocated(frame);
+        unsafe {
+            let shares = &mut *self.shares.add(block);
+            *shares = shares.checked_add(1).expect("frame shared too often");
+        }
+    }
+
+    /// Drop an owner of a frame, freeing it if it was the last one.
+    ///
+    /// Returns whether the frame was freed. This function is unsafe for the
+    /// same reasons as `deallocate`, for the releasing owner.
+    pub unsafe fn release(&mut self, frame: PhysFrame) -> bool {
+        let block = self.allocated(frame);
+        let shares = &mut *self.shares.add(block);
+        if *shares > 0 {
+            *shares -= 1;
+            false
+        } else {
+            self.deallocate(frame, 0);
+            true
+        }
+    }
+
+    /// Returns the number of owners of an allocated frame
+    pub fn owners(&self, frame: PhysFrame) -> usize {
+        let block = self.allocated(frame);
+        unsafe { *self.shares.add(block) as usize + 1 }
+    }
+
+    fn allocated(&self, frame: PhysFrame) -> usize {
+        let block = index_of(frame);
+        assert!(
+            block < self.frames && self.free_block_containing(block, 0).is_none(),
+            "frame {:#x} is not allocated",
+            frame.start_address().as_u64()
+        );
+        block
+    }
+
+    /// Returns the number of frames available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of allocated frames
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Returns the number of frames managed by the allocator
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Returns the number of free blocks of each order
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let mut counts = [0; MAX_ORDER + 1];
+        for (order, count) in counts.iter_mut().enumerate() {
+            let mut block = self.free_lists[order];
+            while block != NO_FRAME {
+                *count += 1;
+                block = unsafe { self.node(block).next };
+            }
+        }
+        counts
+    }
+
+    /// Returns the free block of order `order` or higher that contains `block`.
+    ///
+    /// Only the block's own head is marked in `meta` when it is free, so the
+    /// heads of all larger blocks it could have been merged into are checked.
+    fn free_block_containing(&self, block: usize, order: usize) -> Option<(usize, usize)> {
+        (order..=MAX_ORDER)
+            .map(|order| (block & !((1 << order) - 1), order))
+            .find(|&(head, order)| unsafe { *self.meta.add(head) } == order as u8 + 1)
+    }
+
+    unsafe fn node(&self, block: usize) -> &mut FreeBlock {
+        &mut *phys_to_virt(frame_at(block).start_address()).as_mut_ptr::<FreeBlock>()
+    }
+
+    fn push(&mut self, block: usize, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            *self.meta.add(block) = order as u8 + 1;
+            *self.node(block) = FreeBlock {
+                next: head,
+                prev: NO_FRAME,
+            };
+            if head != NO_FRAME {
+                self.node(head).prev = block;
+            }
+        }
+        self.free_lists[order] = block;
+    }
+
+    fn remove(&mut self, block: usize, order: usize) {
+        unsafe {
+            let FreeBlock { next, prev } = *self.node(block);
+            if prev != NO_FRAME {
+                self.node(prev).next = next;
+            } else {
+                self.free_lists[order] = next;
+            }
+            if next != NO_FRAME {
+                self.node(next).prev = prev;
+            }
+            *self.meta.add(block) = 0;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame, 0)
+    }
+}
+
+/// A handle to `FRAME_ALLOCATOR` for APIs taking a `FrameAllocator`.
+///
+/// The global allocator is locked for each call, so it must not already be held.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        FRAME_ALLOCATOR.lock().allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        FRAME_ALLOCATOR.lock().deallocate(frame, 0)
+    }
+}
+
+/// A handle to `FRAME_ALLOCATOR` that only tries to lock it, for the page fault handler.
+///
+/// Allocation fails instead of spinning if the fault was taken while the
+/// global allocator was held.
+pub struct TryGlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for TryGlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        try_allocate(0)
+    }
+}
+
+/// Initialize the global frame allocator from the bootloader's memory map.
+///
+/// This function is unsafe for the same reasons as `BuddyFrameAllocator::init`.
+pub unsafe fn init(memory_map: &MemoryMap) {
+    *FRAME_ALLOCATOR.lock() = BuddyFrameAllocator::init(memory_map);
+}
+
+/// Allocate `2^order` contiguous frames from the global allocator
+pub fn allocate(order: usize) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().allocate(order)
+}
+
+/// Allocate `2^order` contiguous frames from the global allocator without blocking.
+///
+/// Returns `None` if the allocator is already locked, such as when called from
+/// a fault taken while it was held.
+pub fn try_allocate(order: usize) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.try_lock()?.allocate(order)
+}
+
+/// Allocate `2^order` contiguous frames ending at or below `limit` from the global allocator
+pub fn allocate_below(order: usize, limit: PhysAddr) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().allocate_below(order, limit)
+}
+
+/// Return `2^order` contiguous frames to the global allocator.
+///
+/// This function is unsafe for the same reasons as `BuddyFrameAllocator::deallocate`.
+pub unsafe fn deallocate(frame: PhysFrame, order: usize) {
+    FRAME_ALLOCATOR.lock().deallocate(frame, order)
+}
+
+/// Add an owner to an allocated frame of the global allocator
+pub fn share(frame: PhysFrame) {
+    FRAME_ALLOCATOR.lock().share(frame)
+}
+
+/// Drop an owner of a frame, returning it to the global allocator if it was the last one.
+///
+/// This function is unsafe for the same reasons as `BuddyFrameAllocator::release`.
+pub unsafe fn release(frame: PhysFrame) -> bool {
+    FRAME_ALLOCATOR.lock().release(frame)
+}
+
+pub fn owners(frame: PhysFrame) -> usize {
+    FRAME_ALLOCATOR.lock().owners(frame)
+}
+
+pub fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_frames()
+}
+
+pub fn used_frames() -> usize {
+    FRAME_ALLOCATOR.lock().used_frames()
+}
+
+/// Returns the virtual address of a frame in the physical memory mapping
+pub fn frame_to_virt(frame: PhysFrame) -> VirtAddr {
+    phys_to_virt(frame.start_address())
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn index_of(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
@@ -1,45 +1,245 @@
-use crate::arch::memory::allocators::FixedSizeBlockAllocator;
+//! The kernel heap.
+//!
+//! The heap starts out with a fraction of usable RAM mapped at `HEAP_START`
+//! and grows on demand, one contiguous range of pages at a time, whenever the
+//! fallback allocator runs out of memory. It never grows beyond `max_size`.
+//! Aligned 2 MiB chunks of the heap are mapped with huge pages when a 2 MiB
+//! block of frames is available, the rest with 4 KiB pages. The heap is never
+//! executable.
+
+use crate::arch::memory::allocators::slab::{self, CacheStats, SlabAllocator, BLOCK_SIZES};
+use crate::arch::memory::{self, frame, leak, vmm};
 use crate::sync::{IrqGuard, IrqLock};
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MB
+
+/// Lower bound for both the initial and the maximum heap size
+pub const HEAP_MIN_SIZE: usize = 1024 * 1024; // 1 MB
+
+/// The heap is created with this fraction of usable RAM
+const INITIAL_FRACTION: usize = 64;
+/// The heap may grow to this fraction of usable RAM
+const MAX_FRACTION: usize = 4;
+/// Smallest amount by which the heap grows, to avoid mapping a page per allocation
+const GROW_STEP: usize = 256 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+const HUGE_PAGE_SIZE: usize = vmm::HUGE_PAGE_SIZE as usize;
+
+/// Current end of the mapped heap
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+/// Limit the heap end may not grow past
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_START);
+
+/// Heap usage since boot
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Bytes currently allocated, as requested by the callers
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub failed_allocations: u64,
+    /// Allocations per `BLOCK_SIZES` class, followed by those too large for any class
+    pub size_classes: [u64; BLOCK_SIZES.len() + 1],
+}
+
+static STATS: IrqLock<HeapStats> = IrqLock::new(HeapStats {
+    current_bytes: 0,
+    peak_bytes: 0,
+    allocations: 0,
+    frees: 0,
+    failed_allocations: 0,
+    size_classes: [0; BLOCK_SIZES.len() + 1],
+});
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
-
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+static ALLOCATOR: Locked<SlabAllocator> = Locked::new(SlabAllocator::new());
+
+/// Map the initial heap and set its limit from the amount of usable RAM.
+///
+/// The frame allocator and the page table must be initialized.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    let usable = frame::FRAME_ALLOCATOR.lock().usable_frames() * PAGE_SIZE;
+    let max_size = page_align(usable / MAX_FRACTION).max(HEAP_MIN_SIZE);
+    let initial_size = page_align(usable / INITIAL_FRACTION)
+        .max(HEAP_MIN_SIZE)
+        .min(max_size);
+
+    HEAP_LIMIT.store(HEAP_START + max_size, Ordering::Relaxed);
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    let mapped = map_pages(HEAP_START, initial_size);
+    HEAP_END.store(HEAP_START + mapped, Ordering::Relaxed);
+    if mapped < initial_size {
+        return Err(MapToError::FrameAllocationFailed);
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, initial_size);
     }
 
     Ok(())
 }
 
+/// Grow `heap` so that an allocation of `layout` can succeed.
+///
+/// Called by the heap allocator with its lock held. Returns false if the heap
+/// limit was reached or no frames are left; pages mapped before running out of
+/// frames are still added to the heap.
+pub(crate) fn grow(heap: &mut linked_list_allocator::Heap, layout: Layout) -> bool {
+    let top = heap.top();
+    let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+
+    // enough to place the allocation at any alignment in the new range
+    let needed = page_align(layout.size() + layout.align());
+    let size = needed.max(GROW_STEP).min(limit.saturating_sub(top));
+    if size < needed {
+        return false;
+    }
+
+    let mapped = map_pages(top, size);
+    if mapped > 0 {
+        unsafe { heap.extend(mapped) };
+        HEAP_END.store(top + mapped, Ordering::Relaxed);
+    }
+
+    mapped == size
+}
+
+/// Map fresh frames to `start..start + size`. Returns the number of bytes mapped.
+fn map_pages(start: usize, size: usize) -> usize {
+    let flags = flags();
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start as u64));
+
+    memory::with_page_table(|mapper| {
+        let mut frame_allocator = frame::GlobalFrameAllocator;
+        let mut mapped = 0;
+        while mapped < size {
+            let page = first_page + (mapped / PAGE_SIZE) as u64;
+            if size - mapped >= HUGE_PAGE_SIZE && map_huge_page(mapper, page, flags) {
+                mapped += HUGE_PAGE_SIZE;
+                continue;
+            }
+
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
+            match unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    unsafe { frame::deallocate(frame, 0) };
+                    break;
+                }
+            }
+            mapped += PAGE_SIZE;
+        }
+        mapped
+    })
+}
+
+/// Try to map a huge page at `page`, which must start on a 2 MiB boundary to succeed
+fn map_huge_page(mapper: &mut OffsetPageTable, page: Page, flags: PageTableFlags) -> bool {
+    if !page.start_address().is_aligned(vmm::HUGE_PAGE_SIZE) {
+        return false;
+    }
+    let frame = match frame::allocate(frame::HUGE_PAGE_ORDER) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let page = Page::<Size2MiB>::containing_address(page.start_address());
+    let huge_frame = PhysFrame::<Size2MiB>::containing_address(frame.start_address());
+    let mut frame_allocator = frame::GlobalFrameAllocator;
+    match unsafe { mapper.map_to(page, huge_frame, flags, &mut frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame::deallocate(frame, frame::HUGE_PAGE_ORDER) };
+            false
+        }
+    }
+}
+
+/// Returns the flags every heap page is mapped with
+pub fn flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+fn page_align(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Returns the number of bytes currently mapped for the heap
+pub fn size() -> usize {
+    HEAP_END.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// Returns the size the heap may grow to
+pub fn max_size() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// Returns the heap usage statistics
+pub fn stats() -> HeapStats {
+    *STATS.lock()
+}
+
+/// Reset the peak to the current number of allocated bytes
+pub fn reset_peak() {
+    let mut stats = STATS.lock();
+    stats.peak_bytes = stats.current_bytes;
+}
+
+/// Record an allocation made by the heap allocator. `ptr` is null if it failed.
+pub(crate) fn account_alloc(ptr: *mut u8, layout: Layout) {
+    let mut stats = STATS.lock();
+    if ptr.is_null() {
+        stats.failed_allocations += 1;
+        return;
+    }
+
+    stats.allocations += 1;
+    stats.current_bytes += layout.size();
+    stats.peak_bytes = stats.peak_bytes.max(stats.current_bytes);
+    let class = slab::list_index(&layout).unwrap_or(BLOCK_SIZES.len());
+    stats.size_classes[class] += 1;
+    stats.release();
+
+    leak::record_alloc(ptr, layout);
+}
+
+/// Record a deallocation made by the heap allocator
+pub(crate) fn account_dealloc(ptr: *mut u8, layout: Layout) {
+    let mut stats = STATS.lock();
+    stats.frees += 1;
+    stats.current_bytes -= layout.size();
+    stats.release();
+
+    leak::record_dealloc(ptr);
+}
+
+/// Returns the statistics of the general purpose slab caches, in `BLOCK_SIZES` order
+pub fn slab_stats() -> [CacheStats; BLOCK_SIZES.len()] {
+    ALLOCATOR.lock().stats()
+}
+
+/// Return empty slabs to the heap. Returns the number of slabs freed.
+pub fn reclaim() -> usize {
+    ALLOCATOR.lock().reclaim()
+}
+
 pub struct Locked<A> {
     inner: IrqLock<A>,
 }
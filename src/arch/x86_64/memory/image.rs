@@ -0,0 +1,144 @@
+//! Protection of the kernel image.
+//!
+//! rxinu sets the permissions of its own segments so that no kernel memory is
+//! both writable and executable: code is read-only and executable, read-only
+//! data and writable data are never executable. The section headers are not
+//! loaded with the kernel, so the program headers are walked instead; the
+//! linker groups sections into segments by their permissions. They are found
+//! through `__ehdr_start`, which the linker defines when the ELF header is
+//! loaded as part of the first segment.
+//!
+//! `init` also enables no-execute support and makes the physical memory
+//! mapping non-executable, as it is a writable alias of the whole image.
+
+use crate::arch::memory;
+use crate::elf::{Elf, FileHeader};
+use core::{ptr, slice};
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+/// Returns the kernel's ELF header and program headers, as loaded into memory
+pub fn kernel_elf() -> Elf<'static> {
+    unsafe {
+        let base = &__ehdr_start as *const u8;
+        let header = ptr::read_unaligned(base as *const FileHeader);
+        let headers = slice::from_raw_parts(base, Elf::headers_len(&header));
+        Elf::parse(headers).expect("invalid kernel ELF headers")
+    }
+}
+
+/// A loaded segment of the kernel image, extended to page boundaries
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match (self.executable, self.writable) {
+            (true, _) => "kernel code",
+            (false, false) => "kernel read-only data",
+            (false, true) => "kernel data",
+        }
+    }
+}
+
+/// Returns the loaded segments of the kernel image
+pub fn segments() -> impl Iterator<Item = Segment> {
+    kernel_elf()
+        .program_headers()
+        .filter(|header| header.is_load() && header.memsz > 0)
+        .map(|header| Segment {
+            start: VirtAddr::new(header.vaddr).align_down(PAGE_SIZE),
+            end: VirtAddr::new(header.vaddr + header.memsz).align_up(PAGE_SIZE),
+            writable: header.is_writable(),
+            executable: header.is_executable(),
+        })
+}
+
+/// Returns the kernel segment containing `addr`
+pub fn segment_of(addr: VirtAddr) -> Option<Segment> {
+    segments().find(|segment| segment.contains(addr))
+}
+
+/// Returns the flags for a page of the image. A page shared by two segments
+/// gets the permissions of both, so this panics if that would make it both
+/// writable and executable.
+fn page_flags(page: Page) -> PageTableFlags {
+    let start = page.start_address();
+    let flags = segments()
+        .filter(|segment| segment.start <= start && start < segment.end)
+        .fold(
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            |mut flags, segment| {
+                if segment.writable {
+                    flags |= PageTableFlags::WRITABLE;
+                }
+                if segment.executable {
+                    flags.remove(PageTableFlags::NO_EXECUTE);
+                }
+                flags
+            },
+        );
+
+    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+        panic!(
+            "kernel page at {:#x} is shared by a writable and an executable segment",
+            start.as_u64()
+        );
+    }
+    flags
+}
+
+/// Enable no-execute support and apply W^X to the kernel image.
+///
+/// Must be called before anything is mapped with `NO_EXECUTE`.
+pub fn init() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+
+    let physical_memory = memory::physical_memory_offset();
+    let physical_entry = Page::<Size4KiB>::containing_address(physical_memory).p4_index();
+    for segment in segments() {
+        assert_ne!(
+            Page::<Size4KiB>::containing_address(segment.start).p4_index(),
+            physical_entry,
+            "kernel image shares a level 4 entry with the physical memory mapping"
+        );
+    }
+
+    memory::with_page_table(|mapper| {
+        for segment in segments() {
+            let first = Page::<Size4KiB>::containing_address(segment.start);
+            let last = Page::<Size4KiB>::containing_address(segment.end - 1u64);
+            for page in Page::range_inclusive(first, last) {
+                match unsafe { mapper.update_flags(page, page_flags(page)) } {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => panic!("failed to protect kernel page {:?}: {:?}", page, err),
+                }
+            }
+        }
+
+        let entry = &mut mapper.level_4_table()[physical_entry];
+        let flags = entry.flags() | PageTableFlags::NO_EXECUTE;
+        entry.set_flags(flags);
+    });
+
+    tlb::flush_all();
+}
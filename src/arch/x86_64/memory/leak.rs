@@ -0,0 +1,193 @@
+//! Opt-in tracking of live heap allocations.
+//!
+//! While tracking is enabled, every heap allocation is recorded in a fixed
+//! table together with the current tag, and removed when it is freed. The tag
+//! is global rather than per task, so allocations made by interrupt handlers
+//! or other tasks in the meantime carry it as well.
+//!
+//! ```ignore
+//! let leaks = leak::scope("open_close", || scenario());
+//! assert!(leaks.is_empty(), "{}", leaks);
+//! ```
+
+use crate::sync::IrqLock;
+use core::alloc::Layout;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Maximum number of live allocations that can be tracked at once
+pub const MAX_TRACKED: usize = 512;
+
+/// Number of leaked allocations listed in a `LeakReport`
+pub const REPORTED_ALLOCATIONS: usize = 8;
+
+pub const DEFAULT_TAG: &str = "untagged";
+
+/// Checked before taking the tracker lock on every allocation
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    pub tag: &'static str,
+}
+
+struct Tracker {
+    tag: &'static str,
+    table: [Option<Allocation>; MAX_TRACKED],
+    /// Allocations that did not fit into the table
+    untracked: usize,
+}
+
+// Not allocated from the heap, as it is updated by the heap allocator
+static TRACKER: IrqLock<Tracker> = IrqLock::new(Tracker {
+    tag: DEFAULT_TAG,
+    table: [None; MAX_TRACKED],
+    untracked: 0,
+});
+
+/// Live tracked allocations, optionally restricted to one tag
+pub struct LeakReport {
+    pub count: usize,
+    pub bytes: usize,
+    /// Allocations that could not be tracked, so leaks may be missing from the report
+    pub untracked: usize,
+    allocations: [Option<Allocation>; REPORTED_ALLOCATIONS],
+}
+
+impl LeakReport {
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the first `REPORTED_ALLOCATIONS` leaked allocations
+    pub fn allocations(&self) -> impl Iterator<Item = &Allocation> {
+        self.allocations.iter().flatten()
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} live allocations ({} bytes), {} untracked",
+            self.count, self.bytes, self.untracked
+        )?;
+        for allocation in self.allocations() {
+            writeln!(
+                f,
+                "  {:#x}: {} bytes tagged {}",
+                allocation.ptr, allocation.size, allocation.tag
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Forget all tracked allocations and start tracking new ones
+pub fn enable() {
+    let mut tracker = TRACKER.lock();
+    tracker.table.iter_mut().for_each(|slot| *slot = None);
+    tracker.untracked = 0;
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Set the tag of new allocations. Returns the previous tag.
+pub fn set_tag(tag: &'static str) -> &'static str {
+    let mut tracker = TRACKER.lock();
+    core::mem::replace(&mut tracker.tag, tag)
+}
+
+/// Returns all live tracked allocations
+pub fn report() -> LeakReport {
+    collect(None)
+}
+
+/// Returns the live tracked allocations carrying `tag`
+pub fn report_tag(tag: &'static str) -> LeakReport {
+    collect(Some(tag))
+}
+
+/// Run `f` with its allocations tagged with `tag`, and report those still live afterwards.
+///
+/// Tracking is enabled for the duration of `f` if it is not already.
+pub fn scope<F: FnOnce()>(tag: &'static str, f: F) -> LeakReport {
+    let was_enabled = enabled();
+    if !was_enabled {
+        enable();
+    }
+    let previous = set_tag(tag);
+
+    f();
+
+    set_tag(previous);
+    let report = report_tag(tag);
+    if !was_enabled {
+        disable();
+    }
+    report
+}
+
+fn collect(tag: Option<&'static str>) -> LeakReport {
+    let tracker = TRACKER.lock();
+    let mut report = LeakReport {
+        count: 0,
+        bytes: 0,
+        untracked: tracker.untracked,
+        allocations: [None; REPORTED_ALLOCATIONS],
+    };
+
+    for allocation in tracker.table.iter().flatten() {
+        if tag.map_or(false, |tag| tag != allocation.tag) {
+            continue;
+        }
+        if report.count < REPORTED_ALLOCATIONS {
+            report.allocations[report.count] = Some(*allocation);
+        }
+        report.count += 1;
+        report.bytes += allocation.size;
+    }
+
+    report
+}
+
+pub(crate) fn record_alloc(ptr: *mut u8, layout: Layout) {
+    if !enabled() {
+        return;
+    }
+
+    let mut tracker = TRACKER.lock();
+    let allocation = Allocation {
+        ptr: ptr as usize,
+        size: layout.size(),
+        tag: tracker.tag,
+    };
+    match tracker.table.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(allocation),
+        None => tracker.untracked += 1,
+    }
+}
+
+pub(crate) fn record_dealloc(ptr: *mut u8) {
+    if !enabled() {
+        return;
+    }
+
+    let mut tracker = TRACKER.lock();
+    if let Some(slot) = tracker
+        .table
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |a| a.ptr == ptr as usize))
+    {
+        *slot = None;
+    }
+}
@@ -1,15 +1,67 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::sync::{IrqSpinLock, LockClass};
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{OffsetPageTable, PageTable},
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
 pub mod allocators;
+pub mod dma;
+pub mod frame;
 pub mod heap;
+pub mod image;
+pub mod leak;
+pub mod stack;
+pub mod vmm;
+pub mod xinu;
 
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+static PAGE_TABLE_CLASS: LockClass = LockClass::new("memory::PAGE_TABLE");
+
+/// The kernel's page table. Code holding it must not allocate from the heap,
+/// as the heap maps new pages through it when it grows.
+static PAGE_TABLE: IrqSpinLock<Option<OffsetPageTable<'static>>> =
+    IrqSpinLock::with_class(None, &PAGE_TABLE_CLASS);
+
+/// Take over the active page table.
+///
+/// This function is unsafe for the same reasons as `active_level_4_table`.
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    *PAGE_TABLE.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+}
+
+/// Run `f` with exclusive access to the kernel's page table.
+///
+/// `f` must not allocate from the heap.
+pub fn with_page_table<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut OffsetPageTable<'static>) -> T,
+{
+    let mut page_table = PAGE_TABLE.lock();
+    f(page_table.as_mut().expect("page table not initialized"))
+}
+
+/// Like `with_page_table`, but returns `None` instead of waiting if the page table is locked
+pub fn try_with_page_table<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&mut OffsetPageTable<'static>) -> T,
+{
+    let mut page_table = PAGE_TABLE.try_lock()?;
+    Some(f(page_table.as_mut()?))
+}
+
+/// Returns the virtual address at which the complete physical memory is mapped.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Returns the address of `phys` in the physical memory mapping.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + phys.as_u64()
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -29,44 +81,3 @@ pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static
 
     &mut *page_table_ptr // unsafe
 }
-
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
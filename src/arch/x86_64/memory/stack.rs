@@ -0,0 +1,186 @@
+//! Kernel stacks.
+//!
+//! Every kernel stack sits directly above an unmapped guard page, so a stack
+//! overflow causes a page fault instead of silently corrupting memory. The
+//! boot stack gets its guard page from the bootloader, all other stacks are
+//! allocated from the VMM. Each stack is registered so the fault handlers can
+//! report which one overflowed.
+
+use crate::arch::memory::{self, vmm};
+use crate::sync::{IrqSpinLock, LockClass};
+use core::mem;
+use x86_64::{
+    structures::paging::{Mapper, MapperAllSizes, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+pub const GUARD_PAGES: u64 = 1;
+
+/// Maximum number of registered stacks
+pub const MAX_STACKS: usize = 64;
+
+/// Limit for probing the extent of the boot stack
+const MAX_BOOT_STACK_PAGES: u64 = 1024;
+
+const PAGE_SIZE: u64 = vmm::PAGE_SIZE;
+
+#[derive(Clone, Copy, Debug)]
+pub struct StackInfo {
+    pub name: &'static str,
+    /// Start of the guard pages
+    pub guard: VirtAddr,
+    /// Lowest usable address, directly above the guard pages
+    pub bottom: VirtAddr,
+    /// Address above the highest usable byte, the initial stack pointer
+    pub top: VirtAddr,
+}
+
+impl StackInfo {
+    pub fn is_guard(&self, addr: VirtAddr) -> bool {
+        self.guard <= addr && addr < self.bottom
+    }
+}
+
+static STACKS_CLASS: LockClass = LockClass::new("memory::stack::STACKS");
+
+// A fixed table so the fault path can search it without allocating
+static STACKS: IrqSpinLock<[Option<StackInfo>; MAX_STACKS]> =
+    IrqSpinLock::with_class([None; MAX_STACKS], &STACKS_CLASS);
+
+fn register(info: StackInfo) {
+    let mut stacks = STACKS.lock();
+    let slot = stacks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many kernel stacks");
+    *slot = Some(info);
+}
+
+fn unregister(bottom: VirtAddr) {
+    let mut stacks = STACKS.lock();
+    if let Some(slot) = stacks
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |s| s.bottom == bottom))
+    {
+        *slot = None;
+    }
+}
+
+/// A kernel stack allocated from the VMM, released when dropped
+#[derive(Debug)]
+pub struct KernelStack {
+    region: VirtAddr,
+    info: StackInfo,
+}
+
+impl KernelStack {
+    /// Allocate a stack of `pages` usable pages above a guard page
+    pub fn new(name: &'static str, pages: u64) -> Result<KernelStack, vmm::Error> {
+        let region = vmm::reserve_guarded(pages + GUARD_PAGES, GUARD_PAGES, name)?;
+        if let Err(err) = vmm::map_region(region, PageTableFlags::WRITABLE) {
+            vmm::release(region).expect("vmm: stack region vanished");
+            return Err(err);
+        }
+
+        let bottom = region + GUARD_PAGES * PAGE_SIZE;
+        let info = StackInfo {
+            name,
+            guard: region,
+            bottom,
+            top: bottom + pages * PAGE_SIZE,
+        };
+        register(info);
+
+        Ok(KernelStack { region, info })
+    }
+
+    /// Returns the initial stack pointer
+    pub fn top(&self) -> VirtAddr {
+        self.info.top
+    }
+
+    pub fn info(&self) -> StackInfo {
+        self.info
+    }
+
+    /// Keep the stack forever, such as for stacks the CPU switches to on its own
+    pub fn leak(self) -> StackInfo {
+        let info = self.info;
+        mem::forget(self);
+        info
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unregister(self.info.bottom);
+        vmm::release(self.region).expect("vmm: stack region vanished");
+    }
+}
+
+/// Register the boot stack, whose guard page is left unmapped by the bootloader,
+/// and make it non-executable
+pub fn init() {
+    let rsp: u64;
+    unsafe { llvm_asm!("mov %rsp, $0" : "=r"(rsp)) };
+
+    let mapped = |addr: u64| {
+        memory::with_page_table(|mapper| mapper.translate_addr(VirtAddr::new(addr)).is_some())
+    };
+
+    let current = rsp & !(PAGE_SIZE - 1);
+    let mut bottom = current;
+    while mapped(bottom - PAGE_SIZE) {
+        bottom -= PAGE_SIZE;
+        if current - bottom > MAX_BOOT_STACK_PAGES * PAGE_SIZE {
+            // no guard page to detect an overflow with
+            return;
+        }
+    }
+
+    let mut top = current + PAGE_SIZE;
+    while mapped(top) && top - current < MAX_BOOT_STACK_PAGES * PAGE_SIZE {
+        top += PAGE_SIZE;
+    }
+
+    memory::with_page_table(|mapper| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(bottom));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(top - 1));
+        for page in Page::range_inclusive(first, last) {
+            match unsafe { mapper.update_flags(page, flags) } {
+                Ok(flush) => flush.flush(),
+                Err(err) => panic!("failed to protect boot stack page {:?}: {:?}", page, err),
+            }
+        }
+    });
+
+    register(StackInfo {
+        name: "boot stack",
+        guard: VirtAddr::new(bottom - PAGE_SIZE),
+        bottom: VirtAddr::new(bottom),
+        top: VirtAddr::new(top),
+    });
+}
+
+/// Returns the stack whose guard page contains `addr`.
+///
+/// Called from the fault handlers, so `None` is returned if the registry is locked.
+pub fn guard_hit(addr: VirtAddr) -> Option<StackInfo> {
+    STACKS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|stack| stack.is_guard(addr))
+        .copied()
+}
+
+/// Returns the stack containing `addr`, including its guard pages
+pub fn stack_of(addr: VirtAddr) -> Option<StackInfo> {
+    STACKS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|stack| stack.guard <= addr && addr < stack.top)
+        .copied()
+}
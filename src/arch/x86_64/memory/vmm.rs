@@ -0,0 +1,620 @@
+//! Kernel virtual memory manager.
+//!
+//! The VMM keeps track of reserved virtual regions and of how each of them is
+//! mapped. Regions are reserved either at a fixed address or from an arena in
+//! the higher half that covers exactly one level 4 entry, which must be unused
+//! when the VMM is initialized. Mapping, unmapping and protection changes
+//! always apply to a whole region, except for its guard pages: the lowest
+//! `guard_pages` pages of a region are never mapped, so running off the bottom
+//! of a stack faults instead of corrupting the memory below.
+//!
+//! Lazy regions are backed on demand: the page fault handler maps a zeroed
+//! frame to a page of a lazy region when it is first accessed. Reserving large
+//! stacks or buffers this way only costs the frames actually touched.
+//!
+//! Growable regions such as the kernel heap are mapped from their start by
+//! their owner, which grows the mapping itself. The VMM only records them so
+//! that their range is not handed out again and faults in them are described.
+//!
+//! Regions of at least 2 MiB are placed so that their usable part starts on a
+//! 2 MiB boundary, and aligned 2 MiB chunks are mapped with huge pages where
+//! frames and alignment allow. Everything else uses 4 KiB pages, so a huge
+//! page that cannot be mapped just falls back to 512 small ones.
+//!
+//! The region table lives on the heap, so it is locked before the page table
+//! and never while the page table is held.
+
+use crate::arch::memory::{self, frame, heap};
+use crate::sync::{IrqSpinLock, LockClass};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ptr;
+use lazy_static::lazy_static;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size2MiB,
+        Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+pub const PAGE_SIZE: u64 = 4096;
+pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Number of 4 KiB pages covered by a huge page
+const HUGE_PAGE_PAGES: u64 = HUGE_PAGE_SIZE / PAGE_SIZE;
+
+/// Start of the arena that `reserve` allocates from
+pub const ARENA_START: u64 = 0xffff_a000_0000_0000;
+/// End of the arena, one level 4 entry (512 GiB) after its start
+pub const ARENA_END: u64 = 0xffff_a080_0000_0000;
+
+static REGIONS_CLASS: LockClass = LockClass::new("memory::vmm::REGIONS");
+
+lazy_static! {
+    /// Regions by start address
+    static ref REGIONS: IrqSpinLock<BTreeMap<u64, Region>> =
+        IrqSpinLock::with_class(BTreeMap::new(), &REGIONS_CLASS);
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// No free range of the requested size is left in the arena
+    OutOfVirtualMemory,
+    /// No frames are left to back a mapping
+    OutOfMemory,
+    /// The range overlaps an existing region
+    Overlap,
+    /// No region starts at the passed address
+    UnknownRegion,
+    /// The region is already mapped
+    AlreadyMapped,
+    /// The region is not mapped
+    NotMapped,
+    /// An address is not page aligned or a size is zero
+    InvalidRange,
+    /// A page of the region was mapped by someone else
+    PageAlreadyMapped,
+    /// The region is growable and mapped by its owner
+    Growable,
+}
+
+/// How a region is backed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backing {
+    /// Fresh zeroed frames owned by the region, freed when it is unmapped
+    Anonymous,
+    /// Contiguous physical memory starting at the given address, such as device memory
+    Physical(PhysAddr),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Reserved,
+    Mapped {
+        backing: Backing,
+        flags: PageTableFlags,
+    },
+    /// Pages are mapped to zeroed frames on first access
+    Lazy {
+        flags: PageTableFlags,
+    },
+    /// A prefix of the region is mapped by its owner, who grows it on demand
+    Growable {
+        flags: PageTableFlags,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: VirtAddr,
+    /// Size including the guard pages
+    pub pages: u64,
+    pub guard_pages: u64,
+    pub name: &'static str,
+    pub state: State,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.pages * PAGE_SIZE
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Returns true if `addr` lies in one of the guard pages
+    pub fn is_guard(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.start + self.guard_pages * PAGE_SIZE
+    }
+
+    fn page(&self, index: u64) -> Page {
+        Page::containing_address(self.start + index * PAGE_SIZE)
+    }
+
+    /// Returns true if a huge page can start at page `index`
+    fn huge_page_fits(&self, index: u64) -> bool {
+        self.page(index).start_address().is_aligned(HUGE_PAGE_SIZE)
+            && self.pages - index >= HUGE_PAGE_PAGES
+    }
+}
+
+/// Check that the arena is unused and register the kernel heap.
+///
+/// Must be called after the heap is initialized.
+pub fn init() {
+    let arena = Page::<Size4KiB>::containing_address(VirtAddr::new(ARENA_START));
+    let unused =
+        memory::with_page_table(|mapper| mapper.level_4_table()[arena.p4_index()].is_unused());
+    assert!(unused, "vmm: arena at {:#x} is already in use", ARENA_START);
+
+    let heap = VirtAddr::new(heap::HEAP_START as u64);
+    reserve_at(heap, heap::max_size() as u64 / PAGE_SIZE, "kernel heap")
+        .expect("vmm: heap overlaps an existing region");
+    map_growable(heap, heap::flags()).expect("vmm: heap region is not reserved");
+}
+
+/// Reserve `pages` pages from the arena. Returns the start of the new region.
+pub fn reserve(pages: u64, name: &'static str) -> Result<VirtAddr, Error> {
+    reserve_guarded(pages, 0, name)
+}
+
+/// Reserve `pages` pages from the arena, of which the lowest `guard_pages` are never mapped
+pub fn reserve_guarded(
+    pages: u64,
+    guard_pages: u64,
+    name: &'static str,
+) -> Result<VirtAddr, Error> {
+    if pages <= guard_pages {
+        return Err(Error::InvalidRange);
+    }
+
+    let mut regions = REGIONS.lock();
+    let size = pages * PAGE_SIZE;
+    let guard_size = guard_pages * PAGE_SIZE;
+
+    // align the usable part of regions that can hold a huge page
+    let align = if pages - guard_pages >= HUGE_PAGE_PAGES {
+        HUGE_PAGE_SIZE
+    } else {
+        PAGE_SIZE
+    };
+    let place = |start: u64| x86_64::align_up(start + guard_size, align) - guard_size;
+
+    // first fit between the arena regions
+    let mut start = place(ARENA_START);
+    for region in regions.range(ARENA_START..ARENA_END).map(|(_, r)| r) {
+        if region.start.as_u64() >= start && region.start.as_u64() - start >= size {
+            break;
+        }
+        start = place(region.end().as_u64().max(start));
+    }
+    if start > ARENA_END || ARENA_END - start < size {
+        return Err(Error::OutOfVirtualMemory);
+    }
+
+    let start = VirtAddr::new(start);
+    regions.insert(
+        start.as_u64(),
+        Region {
+            start,
+            pages,
+            guard_pages,
+            name,
+            state: State::Reserved,
+        },
+    );
+    Ok(start)
+}
+
+/// Reserve `pages` pages at a fixed, page aligned address
+pub fn reserve_at(start: VirtAddr, pages: u64, name: &'static str) -> Result<(), Error> {
+    if pages == 0 || !start.is_aligned(PAGE_SIZE) {
+        return Err(Error::InvalidRange);
+    }
+
+    let region = Region {
+        start,
+        pages,
+        guard_pages: 0,
+        name,
+        state: State::Reserved,
+    };
+
+    let mut regions = REGIONS.lock();
+    let overlaps = regions
+        .range(..region.end().as_u64())
+        .next_back()
+        .map_or(false, |(_, r)| r.end() > start);
+    if overlaps {
+        return Err(Error::Overlap);
+    }
+
+    regions.insert(start.as_u64(), region);
+    Ok(())
+}
+
+/// Unmap a region if it is mapped and release its virtual range
+pub fn release(start: VirtAddr) -> Result<(), Error> {
+    let mut regions = REGIONS.lock();
+    let region = *regions.get(&start.as_u64()).ok_or(Error::UnknownRegion)?;
+    match region.state {
+        State::Mapped { backing, .. } => unmap_pages(&region, region.pages, backing),
+        State::Lazy { .. } => unmap_pages(&region, region.pages, Backing::Anonymous),
+        State::Growable { .. } => return Err(Error::Growable),
+        State::Reserved => {}
+    }
+    regions.remove(&start.as_u64());
+    Ok(())
+}
+
+/// Map a reserved region to fresh zeroed frames
+pub fn map_region(start: VirtAddr, flags: PageTableFlags) -> Result<(), Error> {
+    map(start, Backing::Anonymous, flags)
+}
+
+/// Map a reserved region to the physical memory starting at `phys`
+pub fn map_physical(start: VirtAddr, phys: PhysAddr, flags: PageTableFlags) -> Result<(), Error> {
+    if !phys.is_aligned(PAGE_SIZE) {
+        return Err(Error::InvalidRange);
+    }
+    map(start, Backing::Physical(phys), flags)
+}
+
+/// Back a reserved region lazily with zeroed frames, mapped on first access
+pub fn map_lazy(start: VirtAddr, flags: PageTableFlags) -> Result<(), Error> {
+    let mut regions = REGIONS.lock();
+    let region = regions
+        .get_mut(&start.as_u64())
+        .ok_or(Error::UnknownRegion)?;
+    if region.state != State::Reserved {
+        return Err(Error::AlreadyMapped);
+    }
+
+    region.state = State::Lazy {
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    Ok(())
+}
+
+/// Record that the owner of a reserved region maps it from its start and grows
+/// the mapping itself with `flags`
+pub fn map_growable(start: VirtAddr, flags: PageTableFlags) -> Result<(), Error> {
+    let mut regions = REGIONS.lock();
+    let region = regions
+        .get_mut(&start.as_u64())
+        .ok_or(Error::UnknownRegion)?;
+    if region.state != State::Reserved {
+        return Err(Error::AlreadyMapped);
+    }
+
+    region.state = State::Growable {
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    Ok(())
+}
+
+fn map(start: VirtAddr, backing: Backing, flags: PageTableFlags) -> Result<(), Error> {
+    let mut regions = REGIONS.lock();
+    let region = regions
+        .get_mut(&start.as_u64())
+        .ok_or(Error::UnknownRegion)?;
+    if region.state != State::Reserved {
+        return Err(Error::AlreadyMapped);
+    }
+
+    let flags = flags | PageTableFlags::PRESENT;
+    let result = memory::with_page_table(|mapper| {
+        let mut frame_allocator = frame::GlobalFrameAllocator;
+        let mut index = region.guard_pages;
+        while index < region.pages {
+            if region.huge_page_fits(index) && map_huge_page(mapper, region, index, backing, flags)
+            {
+                index += HUGE_PAGE_PAGES;
+                continue;
+            }
+
+            let frame = match backing {
+                Backing::Anonymous => {
+                    let frame = frame_allocator
+                        .allocate_frame()
+                        .ok_or((index, Error::OutOfMemory))?;
+                    let ptr = frame::frame_to_virt(frame).as_mut_ptr::<u8>();
+                    unsafe { ptr::write_bytes(ptr, 0, PAGE_SIZE as usize) };
+                    frame
+                }
+                Backing::Physical(phys) => PhysFrame::containing_address(phys + index * PAGE_SIZE),
+            };
+
+            match unsafe { mapper.map_to(region.page(index), frame, flags, &mut frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    if backing == Backing::Anonymous {
+                        unsafe { frame::deallocate(frame, 0) };
+                    }
+                    return Err(match err {
+                        MapToError::PageAlreadyMapped(_) => (index, Error::PageAlreadyMapped),
+                        _ => (index, Error::OutOfMemory),
+                    });
+                }
+            }
+            index += 1;
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => {
+            region.state = State::Mapped { backing, flags };
+            Ok(())
+        }
+        Err((failed, err)) => {
+            // roll back the pages mapped before the failure
+            unmap_pages(region, failed, backing);
+            Err(err)
+        }
+    }
+}
+
+/// Try to map page `index` of a region and the following pages with a huge page
+fn map_huge_page(
+    mapper: &mut OffsetPageTable,
+    region: &Region,
+    index: u64,
+    backing: Backing,
+    flags: PageTableFlags,
+) -> bool {
+    let page = Page::<Size2MiB>::containing_address(region.page(index).start_address());
+    let frame = match backing {
+        Backing::Anonymous => match frame::allocate(frame::HUGE_PAGE_ORDER) {
+            Some(frame) => {
+                let ptr = frame::frame_to_virt(frame).as_mut_ptr::<u8>();
+                unsafe { ptr::write_bytes(ptr, 0, HUGE_PAGE_SIZE as usize) };
+                PhysFrame::<Size2MiB>::containing_address(frame.start_address())
+            }
+            None => return false,
+        },
+        Backing::Physical(phys) => {
+            let phys = phys + index * PAGE_SIZE;
+            if !phys.is_aligned(HUGE_PAGE_SIZE) {
+                return false;
+            }
+            PhysFrame::containing_address(phys)
+        }
+    };
+
+    let mut frame_allocator = frame::GlobalFrameAllocator;
+    match unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            if backing == Backing::Anonymous {
+                let frame = PhysFrame::containing_address(frame.start_address());
+                unsafe { frame::deallocate(frame, frame::HUGE_PAGE_ORDER) };
+            }
+            false
+        }
+    }
+}
+
+/// Unmap a mapped region, keeping its virtual range reserved
+pub fn unmap_region(start: VirtAddr) -> Result<(), Error> {
+    let mut regions = REGIONS.lock();
+    let region = regions
+        .get_mut(&start.as_u64())
+        .ok_or(Error::UnknownRegion)?;
+    match region.state {
+        State::Mapped { backing, .. } => {
+            unmap_pages(region, region.pages, backing);
+            region.state = State::Reserved;
+            Ok(())
+        }
+        State::Lazy { .. } => {
+            unmap_pages(region, region.pages, Backing::Anonymous);
+            region.state = State::Reserved;
+            Ok(())
+        }
+        State::Growable { .. } => Err(Error::Growable),
+        State::Reserved => Err(Error::NotMapped),
+    }
+}
+
+/// Unmap the pages of a region below page `end`, freeing anonymous frames
+fn unmap_pages(region: &Region, end: u64, backing: Backing) {
+    memory::with_page_table(|mapper| {
+        let mut index = region.guard_pages;
+        while index < end {
+            match mapper.unmap(region.page(index)) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    if backing == Backing::Anonymous {
+                        unsafe { frame::deallocate(frame, 0) };
+                    }
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(UnmapError::ParentEntryHugePage) => {
+                    let page =
+                        Page::<Size2MiB>::containing_address(region.page(index).start_address());
+                    let (frame, flush) = mapper
+                        .unmap(page)
+                        .unwrap_or_else(|err| panic!("vmm: failed to unmap {:?}: {:?}", page, err));
+                    flush.flush();
+                    if backing == Backing::Anonymous {
+                        let frame = PhysFrame::containing_address(frame.start_address());
+                        unsafe { frame::deallocate(frame, frame::HUGE_PAGE_ORDER) };
+                    }
+                    index += HUGE_PAGE_PAGES;
+                    continue;
+                }
+                Err(err) => panic!("vmm: failed to unmap {:?}: {:?}", region.page(index), err),
+            }
+            index += 1;
+        }
+    });
+}
+
+/// Change the flags of all pages of a mapped or lazy region
+pub fn protect(start: VirtAddr, flags: PageTableFlags) -> Result<(), Error> {
+    let mut regions = REGIONS.lock();
+    let region = regions
+        .get_mut(&start.as_u64())
+        .ok_or(Error::UnknownRegion)?;
+    let flags = flags | PageTableFlags::PRESENT;
+    let state = match region.state {
+        State::Mapped { backing, .. } => State::Mapped { backing, flags },
+        State::Lazy { .. } => State::Lazy { flags },
+        State::Growable { .. } => return Err(Error::Growable),
+        State::Reserved => return Err(Error::NotMapped),
+    };
+
+    memory::with_page_table(|mapper| {
+        let mut index = region.guard_pages;
+        while index < region.pages {
+            match unsafe { mapper.update_flags(region.page(index), flags) } {
+                Ok(flush) => flush.flush(),
+                // lazy pages that were never accessed
+                Err(FlagUpdateError::PageNotMapped) => {}
+                Err(FlagUpdateError::ParentEntryHugePage) => {
+                    let page =
+                        Page::<Size2MiB>::containing_address(region.page(index).start_address());
+                    match unsafe { mapper.update_flags(page, flags | PageTableFlags::HUGE_PAGE) } {
+                        Ok(flush) => flush.flush(),
+                        Err(err) => panic!("vmm: failed to protect {:?}: {:?}", page, err),
+                    }
+                    index += HUGE_PAGE_PAGES;
+                    continue;
+                }
+                Err(err) => panic!("vmm: failed to protect {:?}: {:?}", region.page(index), err),
+            }
+            index += 1;
+        }
+    });
+
+    region.state = state;
+    Ok(())
+}
+
+/// Reserve a region in the arena and map it to fresh zeroed frames
+pub fn allocate(pages: u64, flags: PageTableFlags, name: &'static str) -> Result<VirtAddr, Error> {
+    let start = reserve(pages, name)?;
+    if let Err(err) = map_region(start, flags) {
+        release(start).expect("vmm: region vanished");
+        return Err(err);
+    }
+    Ok(start)
+}
+
+/// Reserve a region in the arena that is backed on first access
+pub fn allocate_lazy(
+    pages: u64,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<VirtAddr, Error> {
+    let start = reserve(pages, name)?;
+    map_lazy(start, flags).expect("vmm: fresh region is not reserved");
+    Ok(start)
+}
+
+/// Map `size` bytes of device memory at `phys` into the arena, uncached
+pub fn map_mmio(phys: PhysAddr, size: u64, name: &'static str) -> Result<VirtAddr, Error> {
+    let offset = phys.as_u64() % PAGE_SIZE;
+    let pages = (offset + size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let start = reserve(pages, name)?;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    if let Err(err) = map_physical(start, phys.align_down(PAGE_SIZE), flags) {
+        release(start).expect("vmm: region vanished");
+        return Err(err);
+    }
+    Ok(start + offset)
+}
+
+/// Returns the region containing `addr`
+pub fn region(addr: VirtAddr) -> Option<Region> {
+    find(&REGIONS.lock(), addr)
+}
+
+/// Like `region`, but returns `None` if the region table is locked
+pub fn try_region(addr: VirtAddr) -> Option<Region> {
+    find(&REGIONS.try_lock()?, addr)
+}
+
+fn find(regions: &BTreeMap<u64, Region>, addr: VirtAddr) -> Option<Region> {
+    regions
+        .range(..=addr.as_u64())
+        .next_back()
+        .map(|(_, r)| *r)
+        .filter(|r| r.contains(addr))
+}
+
+/// Returns all regions ordered by address
+pub fn regions() -> Vec<Region> {
+    REGIONS.lock().values().copied().collect()
+}
+
+/// Resolve a page fault at `addr` by backing the page if it is part of a lazy region.
+///
+/// Returns false if the fault is not caused by a lazy page or the access is
+/// not allowed by the region's flags. Called from the
+/// page fault handler, so the locks are only tried and a fault taken while
+/// holding them is reported as genuine.
+pub fn handle_page_fault(addr: VirtAddr, error: PageFaultErrorCode) -> bool {
+    if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+
+    let regions = match REGIONS.try_lock() {
+        Some(regions) => regions,
+        None => return false,
+    };
+    let flags = match find(&regions, addr) {
+        Some(region) if region.is_guard(addr) => return false,
+        Some(Region {
+            state: State::Lazy { flags },
+            ..
+        }) => flags,
+        _ => return false,
+    };
+
+    if error.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+    if error.contains(PageFaultErrorCode::USER_MODE)
+        && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return false;
+    }
+    if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        return false;
+    }
+
+    let frame = match frame::try_allocate(0) {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let ptr = frame::frame_to_virt(frame).as_mut_ptr::<u8>();
+    unsafe { ptr::write_bytes(ptr, 0, PAGE_SIZE as usize) };
+
+    let page = Page::containing_address(addr);
+    let mapped = memory::try_with_page_table(|mapper| {
+        unsafe { mapper.map_to(page, frame, flags, &mut frame::TryGlobalFrameAllocator) }
+            .map(|flush| flush.flush())
+            .is_ok()
+    });
+
+    if mapped != Some(true) {
+        // the frame allocator was free above and interrupts are still
+        // disabled, so this does not block
+        unsafe { frame::deallocate(frame, 0) };
+        return false;
+    }
+    true
+}
@@ -0,0 +1,277 @@
+//! Xinu's memory list API.
+//!
+//! `getmem` and `freemem` hand out raw blocks from a pool reserved from the
+//! VMM, `getstk` and `freestk` hand out stacks from the top of the same pool.
+//! As in Xinu, the free blocks form a list ordered by address with the list
+//! head recording the total amount of free memory, and sizes are rounded up to
+//! multiples of a list node. The pool is backed lazily, so only the parts that
+//! were handed out at some point cost frames.
+//!
+//! Unlike Xinu's `getstk`, which returns the address of the highest word of a
+//! stack, `getstk` returns the address above the stack, the initial stack
+//! pointer, like `KernelStack::top`.
+
+use crate::arch::memory::vmm;
+use crate::sync::{IrqSpinLock, LockClass};
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr::{self, NonNull};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+/// Size of the memory pool
+pub const POOL_SIZE: usize = 16 * 1024 * 1024;
+
+/// Granularity of block sizes, the size of a list node
+pub const MEMBLK_SIZE: usize = mem::size_of::<MemBlk>();
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// `init` was not called yet
+    NotInitialized,
+    /// The requested size is zero or too large to round up
+    InvalidSize,
+    /// No free block is large enough
+    OutOfMemory,
+    /// The block is misaligned or does not lie within the pool
+    InvalidBlock,
+    /// The block overlaps memory that is already free
+    AlreadyFree,
+}
+
+/// A free block, stored at the start of the block itself
+struct MemBlk {
+    next: *mut MemBlk,
+    length: usize,
+}
+
+struct MemList {
+    /// `length` of the head is the total amount of free memory
+    head: MemBlk,
+    start: usize,
+    end: usize,
+}
+
+unsafe impl Send for MemList {}
+
+static MEMLIST_CLASS: LockClass = LockClass::new("memory::xinu::MEMLIST");
+
+static MEMLIST: IrqSpinLock<MemList> = IrqSpinLock::with_class(
+    MemList {
+        head: MemBlk {
+            next: ptr::null_mut(),
+            length: 0,
+        },
+        start: 0,
+        end: 0,
+    },
+    &MEMLIST_CLASS,
+);
+
+/// A free block, as reported by `memlist`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FreeBlock {
+    pub addr: VirtAddr,
+    pub length: usize,
+}
+
+/// Round `nbytes` up to a multiple of `MEMBLK_SIZE`, or `None` if that overflows
+pub fn roundmb(nbytes: usize) -> Option<usize> {
+    Some(nbytes.checked_add(MEMBLK_SIZE - 1)? & !(MEMBLK_SIZE - 1))
+}
+
+/// Reserve the pool and put all of it on the free list.
+///
+/// The page fault handler must be installed, as the pool is backed on demand.
+pub fn init() {
+    let pages = (POOL_SIZE as u64) / vmm::PAGE_SIZE;
+    let start = vmm::allocate_lazy(pages, PageTableFlags::WRITABLE, "xinu memory")
+        .expect("no virtual memory for the xinu memory pool");
+
+    let mut list = MEMLIST.lock();
+    let block = start.as_mut_ptr::<MemBlk>();
+    unsafe {
+        block.write(MemBlk {
+            next: ptr::null_mut(),
+            length: POOL_SIZE,
+        });
+    }
+    list.head = MemBlk {
+        next: block,
+        length: POOL_SIZE,
+    };
+    list.start = start.as_u64() as usize;
+    list.end = list.start + POOL_SIZE;
+}
+
+/// Allocate a block of at least `nbytes`, taking the lowest block that fits
+pub fn getmem(nbytes: usize) -> Result<NonNull<u8>, Error> {
+    let mut list = MEMLIST.lock();
+    if list.start == 0 {
+        return Err(Error::NotInitialized);
+    }
+    if nbytes == 0 {
+        return Err(Error::InvalidSize);
+    }
+    let nbytes = roundmb(nbytes).ok_or(Error::InvalidSize)?;
+
+    unsafe {
+        let mut prev: *mut MemBlk = &mut list.head;
+        let mut curr = (*prev).next;
+        while !curr.is_null() {
+            if (*curr).length == nbytes {
+                (*prev).next = (*curr).next;
+            } else if (*curr).length > nbytes {
+                let leftover = (curr as usize + nbytes) as *mut MemBlk;
+                leftover.write(MemBlk {
+                    next: (*curr).next,
+                    length: (*curr).length - nbytes,
+                });
+                (*prev).next = leftover;
+            } else {
+                prev = curr;
+                curr = (*curr).next;
+                continue;
+            }
+
+            list.head.length -= nbytes;
+            return Ok(NonNull::new_unchecked(curr as *mut u8));
+        }
+    }
+
+    Err(Error::OutOfMemory)
+}
+
+/// Return a block of `nbytes` obtained from `getmem` to the free list
+pub fn freemem(block: NonNull<u8>, nbytes: usize) -> Result<(), Error> {
+    let mut list = MEMLIST.lock();
+    if list.start == 0 {
+        return Err(Error::NotInitialized);
+    }
+    if nbytes == 0 {
+        return Err(Error::InvalidSize);
+    }
+    let nbytes = roundmb(nbytes).ok_or(Error::InvalidSize)?;
+
+    let addr = block.as_ptr() as usize;
+    let end = addr.checked_add(nbytes).ok_or(Error::InvalidBlock)?;
+    if addr % MEMBLK_SIZE != 0 || addr < list.start || end > list.end {
+        return Err(Error::InvalidBlock);
+    }
+
+    unsafe {
+        let head: *mut MemBlk = &mut list.head;
+        let mut prev = head;
+        let mut next = (*head).next;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let top = if prev == head {
+            0
+        } else {
+            prev as usize + (*prev).length
+        };
+        if top > addr || (!next.is_null() && addr + nbytes > next as usize) {
+            return Err(Error::AlreadyFree);
+        }
+
+        (*head).length += nbytes;
+
+        // merge with the previous block or insert a new one
+        let block = if prev != head && top == addr {
+            (*prev).length += nbytes;
+            prev
+        } else {
+            let block = addr as *mut MemBlk;
+            block.write(MemBlk {
+                next,
+                length: nbytes,
+            });
+            (*prev).next = block;
+            block
+        };
+
+        // merge with the next block
+        if block as usize + (*block).length == next as usize {
+            (*block).length += (*next).length;
+            (*block).next = (*next).next;
+        }
+    }
+
+    Ok(())
+}
+
+/// Allocate a stack of at least `nbytes`, taking the highest block that fits.
+///
+/// Returns the initial stack pointer, the address above the stack.
+pub fn getstk(nbytes: usize) -> Result<VirtAddr, Error> {
+    let mut list = MEMLIST.lock();
+    if list.start == 0 {
+        return Err(Error::NotInitialized);
+    }
+    if nbytes == 0 {
+        return Err(Error::InvalidSize);
+    }
+    let nbytes = roundmb(nbytes).ok_or(Error::InvalidSize)?;
+
+    unsafe {
+        let mut prev: *mut MemBlk = &mut list.head;
+        let mut curr = (*prev).next;
+        let mut fits: *mut MemBlk = ptr::null_mut();
+        let mut fits_prev = prev;
+        while !curr.is_null() {
+            if (*curr).length >= nbytes {
+                fits = curr;
+                fits_prev = prev;
+            }
+            prev = curr;
+            curr = (*curr).next;
+        }
+
+        if fits.is_null() {
+            return Err(Error::OutOfMemory);
+        }
+
+        let stack = if (*fits).length == nbytes {
+            (*fits_prev).next = (*fits).next;
+            fits as usize
+        } else {
+            (*fits).length -= nbytes;
+            fits as usize + (*fits).length
+        };
+        list.head.length -= nbytes;
+
+        Ok(VirtAddr::new((stack + nbytes) as u64))
+    }
+}
+
+/// Return a stack of `nbytes` obtained from `getstk`, passing its initial stack pointer
+pub fn freestk(top: VirtAddr, nbytes: usize) -> Result<(), Error> {
+    let nbytes = roundmb(nbytes).ok_or(Error::InvalidSize)?;
+    let block = (top.as_u64() as usize).wrapping_sub(nbytes) as *mut u8;
+    let block = NonNull::new(block).ok_or(Error::InvalidBlock)?;
+    freemem(block, nbytes)
+}
+
+/// Returns the total amount of free memory in the pool
+pub fn free_bytes() -> usize {
+    MEMLIST.lock().head.length
+}
+
+/// Returns the free blocks ordered by address
+pub fn memlist() -> Vec<FreeBlock> {
+    let list = MEMLIST.lock();
+    let mut blocks = Vec::new();
+    let mut curr = list.head.next;
+    while !curr.is_null() {
+        unsafe {
+            blocks.push(FreeBlock {
+                addr: VirtAddr::from_ptr(curr),
+                length: (*curr).length,
+            });
+            curr = (*curr).next;
+        }
+    }
+    blocks
+}
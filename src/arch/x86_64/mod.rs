@@ -1,21 +1,35 @@
 use bootloader::bootinfo::BootInfo;
 use x86_64::VirtAddr;
 
+pub mod debug;
 mod device;
 pub mod gdt;
 pub mod idt;
 pub mod interrupts;
+pub mod loader;
 pub mod memory;
+pub mod usermode;
 
 pub fn init(boot_info: &'static BootInfo) {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::frame::init(&boot_info.memory_map);
+    }
 
-    memory::heap::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // before anything is mapped non-executable
+    memory::image::init();
+
+    memory::heap::init_heap().expect("heap initialization failed");
+    memory::vmm::init();
+    memory::address_space::init();
+    memory::stack::init();
 
     gdt::init();
     idt::init();
+    interrupts::syscall::init();
     device::init();
+
+    // backed on demand, so the page fault handler must be installed
+    memory::xinu::init();
 }
@@ -0,0 +1,244 @@
+//! Running code in user mode.
+//!
+//! `run` enters user code with `iretq` and returns once the code calls `exit`
+//! or raises an exception, which makes the kernel entry return into `run`
+//! instead of back to user mode. Like `fault::catch`, `run` saves the
+//! callee-saved registers and its stack pointer first, so the kernel can
+//! return from it a second time.
+//!
+//! Interrupts, exceptions and system calls from user mode switch to the stack
+//! of the task that called `run`, below its frame. Every call sets the TSS
+//! `rsp0` and the `syscall` stack accordingly, and restores them on return.
+//! A fault that abandons `run` through `fault::catch` restores them as well.
+//!
+//! `run` also sets the per-CPU user mode flag that the `syscall` entry uses to
+//! tell calls from user code from calls made by the kernel. The entry clears
+//! it, and sets it again when it returns to user mode. An exception that stops
+//! user code clears it in `recover`.
+
+use crate::arch::memory::address_space;
+use crate::arch::x86_64::gdt;
+use crate::arch::x86_64::interrupts::fault::Fault;
+use crate::arch::x86_64::interrupts::syscall::{self, SyscallStack};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Space left between the frame of `run` and the stack of kernel entries
+const KERNEL_STACK_GAP: u64 = 128;
+
+/// Why user code returned to the kernel
+#[derive(Clone, Copy, Debug)]
+pub enum Exit {
+    /// The code called `exit` with this status
+    Exited(u64),
+    /// An exception stopped the code
+    Faulted(Fault),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The entry point is not accessible to user code
+    InvalidEntry,
+    /// The stack is not writable by user code
+    InvalidStack,
+}
+
+/// State saved by `rxinu_enter_user` to return from it when user code exits
+#[derive(Default)]
+#[repr(C)]
+struct Context {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    /// Stack pointer pointing at the return address of `rxinu_enter_user`
+    rsp: u64,
+    rflags: u64,
+    exit: Option<Exit>,
+}
+
+// `rxinu_enter_user(context, entry, stack, arg)` saves the callee-saved
+// registers to `context` and enters user mode at `entry` with `arg` in `rdi`.
+// The kernel leaves user mode by returning to `rxinu_user_resume` with the
+// context on top of the stack, which restores the registers and returns from
+// `rxinu_enter_user`. User code starts with interrupts enabled and all other
+// registers cleared.
+global_asm!(
+    r#"
+.global rxinu_enter_user
+rxinu_enter_user:
+    mov %rbx, 0x00(%rdi)
+    mov %rbp, 0x08(%rdi)
+    mov %r12, 0x10(%rdi)
+    mov %r13, 0x18(%rdi)
+    mov %r14, 0x20(%rdi)
+    mov %r15, 0x28(%rdi)
+    mov %rsp, 0x30(%rdi)
+    pushfq
+    pop %rax
+    mov %rax, 0x38(%rdi)
+
+    pushq $0x1b
+    push %rdx
+    pushq $0x202
+    pushq $0x23
+    push %rsi
+
+    mov %rcx, %rdi
+    xor %eax, %eax
+    xor %ebx, %ebx
+    xor %ecx, %ecx
+    xor %edx, %edx
+    xor %esi, %esi
+    xor %ebp, %ebp
+    xor %r8d, %r8d
+    xor %r9d, %r9d
+    xor %r10d, %r10d
+    xor %r11d, %r11d
+    xor %r12d, %r12d
+    xor %r13d, %r13d
+    xor %r14d, %r14d
+    xor %r15d, %r15d
+    iretq
+
+.global rxinu_user_resume
+rxinu_user_resume:
+    pop %rdi
+    mov 0x00(%rdi), %rbx
+    mov 0x08(%rdi), %rbp
+    mov 0x10(%rdi), %r12
+    mov 0x18(%rdi), %r13
+    mov 0x20(%rdi), %r14
+    mov 0x28(%rdi), %r15
+    mov 0x30(%rdi), %rsp
+    pushq 0x38(%rdi)
+    popfq
+    ret
+"#
+);
+
+extern "C" {
+    fn rxinu_enter_user(context: *mut Context, entry: u64, stack: u64, arg: u64);
+    fn rxinu_user_resume();
+}
+
+/// The innermost `run`, which user code returns to
+static CURRENT: AtomicPtr<Context> = AtomicPtr::new(ptr::null_mut());
+
+/// The state `run` changes while user code runs
+pub(crate) struct Saved {
+    current: *mut Context,
+    tss_stack: VirtAddr,
+    syscall_stack: VirtAddr,
+    user_mode: bool,
+}
+
+/// Save the state `run` changes, to be put back with `restore`
+pub(crate) fn save() -> Saved {
+    Saved {
+        current: CURRENT.load(Ordering::SeqCst),
+        tss_stack: gdt::kernel_stack(),
+        syscall_stack: syscall::kernel_stack(),
+        user_mode: syscall::user_mode(),
+    }
+}
+
+/// Put back the state saved before entering `run`
+pub(crate) fn restore(saved: Saved) {
+    CURRENT.store(saved.current, Ordering::SeqCst);
+    gdt::set_kernel_stack(saved.tss_stack);
+    syscall::set_kernel_stack(saved.syscall_stack);
+    syscall::set_user_mode(saved.user_mode);
+}
+
+/// Run user code at `entry` with `arg` in `rdi` and `stack` as stack pointer,
+/// until it exits or raises an exception.
+///
+/// Both must be user accessible in the active address space.
+pub fn run(entry: VirtAddr, stack: VirtAddr, arg: u64) -> Result<Exit, Error> {
+    if !address_space::is_user_accessible(entry, 1, false) {
+        return Err(Error::InvalidEntry);
+    }
+    let top = VirtAddr::new(stack.as_u64().wrapping_sub(8));
+    if !address_space::is_user_accessible(top, 8, true) {
+        return Err(Error::InvalidStack);
+    }
+
+    let saved = save();
+    let rsp: u64;
+    unsafe { llvm_asm!("mov %rsp, $0" : "=r"(rsp)) };
+    let kernel_stack = VirtAddr::new(rsp - KERNEL_STACK_GAP).align_down(16u64);
+    gdt::set_kernel_stack(kernel_stack);
+    syscall::set_kernel_stack(kernel_stack);
+
+    let mut context = Context::default();
+    CURRENT.store(&mut context, Ordering::SeqCst);
+    syscall::set_user_mode(true);
+    unsafe { rxinu_enter_user(&mut context, entry.as_u64(), stack.as_u64(), arg) };
+    restore(saved);
+
+    Ok(context.exit.expect("usermode: returned without an exit"))
+}
+
+/// Record `exit` and return the instruction and stack pointer that return from `run`
+fn resume(exit: Exit) -> Option<(VirtAddr, VirtAddr)> {
+    let context = CURRENT.load(Ordering::SeqCst);
+    if context.is_null() {
+        return None;
+    }
+
+    unsafe {
+        (*context).exit = Some(exit);
+
+        // the stack below the saved stack pointer is only used by kernel entries
+        let resume_stack = (*context).rsp - 16;
+        *(resume_stack as *mut *mut Context) = context;
+        Some((
+            VirtAddr::new(rxinu_user_resume as usize as u64),
+            VirtAddr::new(resume_stack),
+        ))
+    }
+}
+
+/// Make a system call from user mode return from `run` with `status`.
+///
+/// Returns false if no user code is running.
+pub fn exit(stack: &mut SyscallStack, status: u64) -> bool {
+    match resume(Exit::Exited(status)) {
+        Some((rip, rsp)) => {
+            stack.rip = rip.as_u64();
+            stack.cs = u64::from(gdt::KERNEL_CODE_SELECTOR);
+            stack.rflags = RFlags::empty().bits();
+            stack.rsp = rsp.as_u64();
+            stack.ss = u64::from(gdt::KERNEL_DATA_SELECTOR);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Make an exception in user mode return from `run` with `fault`.
+///
+/// Returns false if no user code is running.
+pub fn recover(stack: &mut InterruptStackFrame, fault: Fault) -> bool {
+    match resume(Exit::Faulted(fault)) {
+        Some((rip, rsp)) => {
+            syscall::set_user_mode(false);
+            unsafe {
+                let frame = stack.as_mut();
+                frame.instruction_pointer = rip;
+                frame.code_segment = u64::from(gdt::KERNEL_CODE_SELECTOR);
+                frame.cpu_flags = RFlags::empty().bits();
+                frame.stack_pointer = rsp;
+                frame.stack_segment = u64::from(gdt::KERNEL_DATA_SELECTOR);
+            }
+            true
+        }
+        None => false,
+    }
+}
@@ -47,3 +47,19 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Print to both the VGA console and the serial port, for crash reports
+#[macro_export]
+macro_rules! crash_print {
+    ($($arg:tt)*) => ({
+            $crate::kprint!($($arg)*);
+            $crate::serial_print!($($arg)*);
+    });
+}
+
+#[macro_export]
+macro_rules! crash_println {
+    () => ($crate::crash_print!("\n"));
+    ($fmt:expr) => ($crate::crash_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::crash_print!(concat!($fmt, "\n"), $($arg)*));
+}
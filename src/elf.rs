@@ -0,0 +1,155 @@
+//! Minimal ELF64 parsing.
+//!
+//! Only what is needed to walk the program headers of little-endian x86_64
+//! images is supported, such as the kernel's own image or programs to load.
+//! Structures are read with unaligned reads, so the data may lie anywhere.
+
+use core::mem;
+use core::ptr;
+
+pub const PT_LOAD: u32 = 1;
+
+/// Segment permission flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const ET_EXEC: u16 = 2;
+pub const EM_X86_64: u16 = 62;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The data ends before a structure it should contain
+    Truncated,
+    /// The data does not start with the ELF magic
+    BadMagic,
+    /// The file is not a 64-bit little-endian ELF file
+    UnsupportedFormat,
+    /// The file is not for x86_64
+    WrongMachine,
+    /// The program header entries have an unexpected size
+    BadProgramHeaders,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct FileHeader {
+    pub ident: [u8; 16],
+    pub file_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.segment_type == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// A parsed ELF64 file
+#[derive(Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: FileHeader,
+}
+
+impl<'a> Elf<'a> {
+    /// Check the file header. The program headers must lie within `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, Error> {
+        let header: FileHeader = read(data, 0)?;
+        if header.ident[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if header.ident[4] != CLASS_64 || header.ident[5] != DATA_LITTLE_ENDIAN {
+            return Err(Error::UnsupportedFormat);
+        }
+        if header.machine != EM_X86_64 {
+            return Err(Error::WrongMachine);
+        }
+        if header.phnum > 0 && header.phentsize as usize != mem::size_of::<ProgramHeader>() {
+            return Err(Error::BadProgramHeaders);
+        }
+
+        if Elf::headers_len(&header) > data.len() {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Elf { data, header })
+    }
+
+    /// Returns the length of the file header and the program headers, the
+    /// part of `data` that `parse` needs.
+    pub fn headers_len(header: &FileHeader) -> usize {
+        (header.phoff as usize).saturating_add(header.phnum as usize * header.phentsize as usize)
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let phoff = self.header.phoff as usize;
+        let phentsize = self.header.phentsize as usize;
+        (0..self.header.phnum as usize).map(move |index| {
+            read(data, phoff + index * phentsize).expect("elf: program headers were checked")
+        })
+    }
+
+    /// Returns the file contents of a segment, or `None` if it lies outside the file
+    pub fn segment_data(&self, segment: &ProgramHeader) -> Option<&'a [u8]> {
+        let start = segment.offset as usize;
+        let end = start.checked_add(segment.filesz as usize)?;
+        self.data.get(start..end)
+    }
+}
+
+/// Read a `T` at `offset` from `data`
+pub fn read<T: Copy>(data: &[u8], offset: usize) -> Result<T, Error> {
+    let end = offset
+        .checked_add(mem::size_of::<T>())
+        .ok_or(Error::Truncated)?;
+    if end > data.len() {
+        return Err(Error::Truncated);
+    }
+    Ok(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+}
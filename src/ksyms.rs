@@ -0,0 +1,88 @@
+//! The embedded kernel symbol table.
+//!
+//! `build.rs` generates the table from the symbol list of a previous build, see
+//! there for how. Its layout is a header of magic and symbol count, followed by
+//! one entry of address, size, name offset and name length per symbol in
+//! address order, followed by the names. The table only reserves space in the
+//! kernel image with the `ksyms` feature.
+
+use core::convert::TryInto;
+use core::str;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 24;
+
+static KSYMS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+/// A kernel function an address resolved to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub addr: u64,
+    /// Offset of the resolved address into the function
+    pub offset: u64,
+}
+
+fn u32_at(offset: usize) -> u32 {
+    u32::from_le_bytes(KSYMS[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(offset: usize) -> u64 {
+    u64::from_le_bytes(KSYMS[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns the number of symbols in the table
+pub fn len() -> usize {
+    if KSYMS.len() < HEADER_SIZE || &KSYMS[..4] != MAGIC {
+        return 0;
+    }
+    (u32_at(4) as usize).min((KSYMS.len() - HEADER_SIZE) / ENTRY_SIZE)
+}
+
+pub fn is_empty() -> bool {
+    len() == 0
+}
+
+fn entry(index: usize) -> (u64, u64) {
+    let offset = HEADER_SIZE + index * ENTRY_SIZE;
+    (u64_at(offset), u64_at(offset + 8))
+}
+
+fn name(index: usize) -> &'static str {
+    let offset = HEADER_SIZE + index * ENTRY_SIZE + 16;
+    let start = u32_at(offset) as usize;
+    let end = start + u32_at(offset + 4) as usize;
+    KSYMS
+        .get(start..end)
+        .and_then(|name| str::from_utf8(name).ok())
+        .unwrap_or("<invalid symbol>")
+}
+
+/// Returns the function containing `addr`
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    // index of the first symbol above `addr`
+    let mut low = 0;
+    let mut high = len();
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry(mid).0 <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    let index = low.checked_sub(1)?;
+    let (start, size) = entry(index);
+    // symbols without a size extend to the next symbol
+    if size != 0 && addr - start >= size {
+        return None;
+    }
+
+    Some(Symbol {
+        name: name(index),
+        addr: start,
+        offset: addr - start,
+    })
+}
@@ -22,6 +22,9 @@ extern crate alloc;
 
 pub mod arch;
 pub mod device;
+pub mod elf;
+pub mod ksyms;
+pub mod programs;
 pub mod sync;
 pub mod task;
 pub mod test;
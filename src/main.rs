@@ -34,7 +34,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
-    rxinu::kprintln!("{}", info);
+    rxinu::crash_println!("{}", info);
+    arch::debug::dump_current();
 
     loop {
         unsafe {
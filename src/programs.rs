@@ -0,0 +1,110 @@
+//! Programs bundled into the kernel image.
+//!
+//! `build.rs` packs the programs into an archive, see there for which.
+//! Archives built elsewhere can be read with `Archive` as well. The layout is
+//! a header of magic and program count, followed by one entry of name offset,
+//! name length, data offset and data length per program, followed by the
+//! names and the data.
+
+use core::convert::TryInto;
+use core::str;
+
+const MAGIC: &[u8; 4] = b"PROG";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+/// Aligns the archive, so that program data is 8 byte aligned in memory too
+#[repr(C, align(8))]
+struct Aligned<T: ?Sized>(T);
+
+static ARCHIVE: &Aligned<[u8]> =
+    &Aligned(*include_bytes!(concat!(env!("OUT_DIR"), "/programs.bin")));
+
+/// Returns the archive bundled into the kernel image
+pub fn bundled() -> Archive<'static> {
+    Archive::new(&ARCHIVE.0)
+}
+
+/// A program in an archive
+#[derive(Clone, Copy, Debug)]
+pub struct Program<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+/// Programs packed in the layout described above
+#[derive(Clone, Copy, Debug)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    /// Read programs from `data`. Data without the archive magic holds no programs.
+    pub fn new(data: &'a [u8]) -> Self {
+        Archive { data }
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<usize> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    /// Returns the number of programs
+    pub fn len(&self) -> usize {
+        if self.data.len() < HEADER_SIZE || &self.data[..4] != MAGIC {
+            return 0;
+        }
+        self.u32_at(4)
+            .unwrap_or(0)
+            .min((self.data.len() - HEADER_SIZE) / ENTRY_SIZE)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn program(&self, index: usize) -> Option<Program<'a>> {
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        let name_start = self.u32_at(entry)?;
+        let data_start = self.u32_at(entry + 8)?;
+        let name = self
+            .data
+            .get(name_start..name_start + self.u32_at(entry + 4)?)?;
+        let data = self
+            .data
+            .get(data_start..data_start + self.u32_at(entry + 12)?)?;
+        Some(Program {
+            name: str::from_utf8(name).ok()?,
+            data,
+        })
+    }
+
+    /// Returns the programs ordered by name
+    pub fn iter(self) -> impl Iterator<Item = Program<'a>> {
+        (0..self.len()).filter_map(move |index| self.program(index))
+    }
+
+    /// Returns the program called `name`
+    pub fn find(self, name: &str) -> Option<Program<'a>> {
+        self.iter().find(|program| program.name == name)
+    }
+}
+
+/// Returns the number of bundled programs
+pub fn len() -> usize {
+    bundled().len()
+}
+
+pub fn is_empty() -> bool {
+    len() == 0
+}
+
+/// Returns the bundled programs ordered by name
+pub fn iter() -> impl Iterator<Item = Program<'static>> {
+    bundled().iter()
+}
+
+/// Returns the bundled program called `name`
+pub fn find(name: &str) -> Option<Program<'static>> {
+    bundled().find(name)
+}
@@ -0,0 +1,107 @@
+//! A reusable async barrier for starting groups of tasks together.
+
+use crate::sync::{IrqSpinLock, LockClass};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+static STATE_CLASS: LockClass = LockClass::new("sync::Barrier::state");
+
+/// Blocks tasks until `n` of them have called `wait`.
+///
+/// The barrier resets once all `n` tasks were released, so it can be reused
+/// for the next generation. A pending `wait` future that is dropped still
+/// counts towards the current generation.
+pub struct Barrier {
+    n: usize,
+    state: IrqSpinLock<State>,
+}
+
+struct State {
+    count: usize,
+    generation: u64,
+    wakers: Vec<Waker>,
+}
+
+/// Returned to every task leaving the barrier. Exactly one per generation is the leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+
+impl BarrierWaitResult {
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+impl Barrier {
+    /// Create a barrier for `n` tasks. A barrier for zero tasks behaves like one for a single task.
+    pub const fn new(n: usize) -> Barrier {
+        Barrier {
+            n,
+            state: IrqSpinLock::with_class(
+                State {
+                    count: 0,
+                    generation: 0,
+                    wakers: Vec::new(),
+                },
+                &STATE_CLASS,
+            ),
+        }
+    }
+
+    pub fn wait(&self) -> BarrierWait {
+        BarrierWait {
+            barrier: self,
+            generation: None,
+        }
+    }
+
+    /// Returns the number of tasks waiting in the current generation
+    pub fn waiting(&self) -> usize {
+        self.state.lock().count
+    }
+}
+
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+    generation: Option<u64>,
+}
+
+impl<'a> Future for BarrierWait<'a> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<BarrierWaitResult> {
+        let barrier = self.barrier;
+        let mut state = barrier.state.lock();
+
+        match self.generation {
+            Some(generation) if generation != state.generation => {
+                Poll::Ready(BarrierWaitResult { is_leader: false })
+            }
+            Some(_) => {
+                if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    state.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            None => {
+                state.count += 1;
+                if state.count >= barrier.n {
+                    state.count = 0;
+                    state.generation = state.generation.wrapping_add(1);
+                    for waker in state.wakers.drain(..) {
+                        waker.wake();
+                    }
+                    return Poll::Ready(BarrierWaitResult { is_leader: true });
+                }
+
+                self.generation = Some(state.generation);
+                state.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
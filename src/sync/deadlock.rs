@@ -0,0 +1,366 @@
+//! Deadlock detection over a wait-for graph, enabled with the `deadlock-detection` feature.
+//!
+//! Sync primitives record which task holds them and which tasks wait on them.
+//! A deadlock is a cycle of tasks, each waiting on an object held by the next.
+//! `check` searches for such a cycle and reports it over serial. It runs on
+//! demand and periodically: the timer interrupt only requests a check, which
+//! the scheduler then runs outside of interrupt context.
+//!
+//! The graph lives in fixed-size tables rather than on the heap because the
+//! heap allocator's own lock is tracked as well.
+
+use crate::arch::interrupts;
+use crate::serial_println;
+use crate::task::{self, TaskId};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const MAX_HOLDS: usize = 64;
+pub const MAX_WAITS: usize = 64;
+
+/// Timer ticks between two periodic checks, roughly one second
+pub const DEFAULT_CHECK_INTERVAL: usize = 500;
+
+static CHECK_INTERVAL: AtomicUsize = AtomicUsize::new(DEFAULT_CHECK_INTERVAL);
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+static CHECK_PENDING: AtomicBool = AtomicBool::new(false);
+static LAST_REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+/// An object in the wait-for graph.
+///
+/// Objects of different kinds are kept apart by their tag, so a mutex and the
+/// spinlock guarding its state never collide even though they share an address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Node {
+    /// An `IrqSpinLock`, by address
+    Lock(usize),
+    /// A `Mutex`, by address
+    Mutex(usize),
+}
+
+impl Node {
+    fn signature(self) -> usize {
+        match self {
+            Node::Lock(addr) => addr << 1,
+            Node::Mutex(addr) => addr << 1 | 1,
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Lock(addr) | Node::Mutex(addr) => write!(f, "{:#x}", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Hold {
+    object: Node,
+    name: &'static str,
+    owner: TaskId,
+}
+
+#[derive(Clone, Copy)]
+struct Wait {
+    task: TaskId,
+    object: Node,
+    name: &'static str,
+}
+
+struct Graph {
+    holds: [Option<Hold>; MAX_HOLDS],
+    waits: [Option<Wait>; MAX_WAITS],
+    /// Number of records that did not fit into the tables
+    dropped: usize,
+}
+
+struct GlobalGraph(UnsafeCell<Graph>);
+
+// Only accessed with interrupts disabled on a single CPU
+unsafe impl Sync for GlobalGraph {}
+
+static GRAPH: GlobalGraph = GlobalGraph(UnsafeCell::new(Graph {
+    holds: [None; MAX_HOLDS],
+    waits: [None; MAX_WAITS],
+    dropped: 0,
+}));
+
+/// Run `f` on the graph with interrupts disabled.
+///
+/// Plain interrupt masking is used instead of `IrqSpinLock`, which is itself tracked.
+fn with_graph<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut Graph) -> T,
+{
+    interrupts::disable_then_execute(|| f(unsafe { &mut *GRAPH.0.get() }))
+}
+
+impl Graph {
+    fn owner(&self, object: Node) -> Option<&Hold> {
+        self.holds
+            .iter()
+            .flatten()
+            .find(|hold| hold.object == object)
+    }
+
+    fn insert_hold(&mut self, hold: Hold) {
+        match self.holds.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(hold),
+            None => self.dropped += 1,
+        }
+    }
+
+    fn insert_wait(&mut self, wait: Wait) {
+        match self.waits.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(wait),
+            None => self.dropped += 1,
+        }
+    }
+
+    fn remove_hold(&mut self, object: Node) {
+        if let Some(slot) = self
+            .holds
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |hold| hold.object == object))
+        {
+            *slot = None;
+        }
+    }
+
+    fn remove_wait(&mut self, task: TaskId, object: Node) {
+        if let Some(slot) = self
+            .waits
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |wait| wait.task == task && wait.object == object))
+        {
+            *slot = None;
+        }
+    }
+
+    /// Depth-first search for a path of wait edges from `task` back to `target`
+    fn find_cycle(&self, task: TaskId, target: TaskId, deadlock: &mut Deadlock) -> bool {
+        if deadlock.len == MAX_WAITS {
+            return false;
+        }
+
+        for wait in self.waits.iter().flatten().filter(|w| w.task == task) {
+            let hold = match self.owner(wait.object) {
+                Some(hold) => hold,
+                None => continue,
+            };
+            if deadlock.edges[..deadlock.len]
+                .iter()
+                .flatten()
+                .any(|edge| edge.waiter == hold.owner && hold.owner != target)
+            {
+                continue;
+            }
+
+            deadlock.edges[deadlock.len] = Some(Edge {
+                waiter: task,
+                object: wait.object,
+                name: wait.name,
+                owner: hold.owner,
+            });
+            deadlock.len += 1;
+
+            if hold.owner == target || self.find_cycle(hold.owner, target, deadlock) {
+                return true;
+            }
+            deadlock.len -= 1;
+        }
+
+        false
+    }
+}
+
+/// One edge of a deadlock cycle: `waiter` waits on `object`, which `owner` holds
+#[derive(Clone, Copy, Debug)]
+pub struct Edge {
+    pub waiter: TaskId,
+    pub object: Node,
+    pub name: &'static str,
+    pub owner: TaskId,
+}
+
+/// A cycle in the wait-for graph
+pub struct Deadlock {
+    edges: [Option<Edge>; MAX_WAITS],
+    len: usize,
+}
+
+impl Deadlock {
+    pub fn edges(&self) -> impl Iterator<Item = &Edge> {
+        self.edges[..self.len].iter().flatten()
+    }
+
+    fn signature(&self) -> usize {
+        self.edges().fold(0, |sig, edge| {
+            sig.wrapping_mul(31).wrapping_add(edge.object.signature())
+        })
+    }
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "deadlock detected between {} tasks:", self.len)?;
+        for edge in self.edges() {
+            writeln!(
+                f,
+                "  {:?} waits on {} at {} held by {:?}",
+                edge.waiter, edge.name, edge.object, edge.owner
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Record that `owner` acquired `object`
+pub fn hold_by(owner: TaskId, object: Node, name: &'static str) {
+    with_graph(|graph| {
+        graph.remove_wait(owner, object);
+        graph.insert_hold(Hold {
+            object,
+            name,
+            owner,
+        });
+    });
+}
+
+/// Record that the current task acquired `object`. Kernel context is not tracked.
+pub fn hold(object: Node, name: &'static str) {
+    if let Some(owner) = task::current() {
+        hold_by(owner, object, name);
+    }
+}
+
+/// Record that `object` was released by its owner
+pub fn release(object: Node) {
+    with_graph(|graph| graph.remove_hold(object));
+}
+
+/// Record that `object` was released and handed to `owner`
+pub fn handoff(object: Node, name: &'static str, owner: Option<TaskId>) {
+    with_graph(|graph| {
+        graph.remove_hold(object);
+        if let Some(owner) = owner {
+            graph.remove_wait(owner, object);
+            graph.insert_hold(Hold {
+                object,
+                name,
+                owner,
+            });
+        }
+    });
+}
+
+/// Record that `task` started waiting on `object`
+pub fn wait_by(task: TaskId, object: Node, name: &'static str) {
+    with_graph(|graph| graph.insert_wait(Wait { task, object, name }));
+}
+
+/// Record that the current task started waiting on `object`
+pub fn wait(object: Node, name: &'static str) {
+    if let Some(task) = task::current() {
+        wait_by(task, object, name);
+    }
+}
+
+/// Record that `task` no longer waits on `object`
+pub fn stop_waiting_by(task: TaskId, object: Node) {
+    with_graph(|graph| graph.remove_wait(task, object));
+}
+
+/// Record that the current task no longer waits on `object`
+pub fn stop_waiting(object: Node) {
+    if let Some(task) = task::current() {
+        stop_waiting_by(task, object);
+    }
+}
+
+/// Remove everything `task` holds or waits on, after it was killed
+pub fn forget(task: TaskId) {
+    with_graph(|graph| {
+        for slot in graph.holds.iter_mut() {
+            if slot.map_or(false, |hold| hold.owner == task) {
+                *slot = None;
+            }
+        }
+        for slot in graph.waits.iter_mut() {
+            if slot.map_or(false, |wait| wait.task == task) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Search the wait-for graph for a cycle
+pub fn find() -> Option<Deadlock> {
+    with_graph(|graph| {
+        let mut deadlock = Deadlock {
+            edges: [None; MAX_WAITS],
+            len: 0,
+        };
+
+        for wait in graph.waits.iter().flatten() {
+            deadlock.len = 0;
+            if graph.find_cycle(wait.task, wait.task, &mut deadlock) {
+                return Some(deadlock);
+            }
+        }
+
+        None
+    })
+}
+
+/// Search for a deadlock and report it over serial. Returns true if one was found.
+pub fn check() -> bool {
+    match find() {
+        Some(deadlock) => {
+            serial_println!("{}", deadlock);
+            LAST_REPORTED.store(deadlock.signature(), Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Set the number of timer ticks between periodic checks. Zero disables them.
+pub fn set_check_interval(ticks: usize) {
+    CHECK_INTERVAL.store(ticks, Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt to request a periodic check.
+///
+/// The check itself runs in `run_pending_check`, as reporting over serial
+/// from interrupt context could deadlock on the serial port lock.
+pub fn tick() {
+    let interval = CHECK_INTERVAL.load(Ordering::Relaxed);
+    if interval != 0 && TICKS.fetch_add(1, Ordering::Relaxed) % interval == 0 {
+        CHECK_PENDING.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Run a check requested by `tick`. A deadlock is only reported again if it changes.
+///
+/// Must not be called from interrupt context.
+pub fn run_pending_check() {
+    if !CHECK_PENDING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(deadlock) = find() {
+        let signature = deadlock.signature();
+        if LAST_REPORTED.swap(signature, Ordering::Relaxed) != signature {
+            serial_println!("{}", deadlock);
+        }
+    }
+}
+
+/// Returns the number of records that did not fit into the graph
+pub fn dropped() -> usize {
+    with_graph(|graph| graph.dropped)
+}
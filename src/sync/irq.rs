@@ -1,8 +1,26 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut, Drop};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::arch::interrupts;
+#[cfg(feature = "deadlock-detection")]
+use crate::sync::deadlock;
+use crate::sync::lockdep::LockClass;
+
+/// Spin iterations between deadlock checks while waiting on a contended spinlock
+#[cfg(feature = "deadlock-detection")]
+const SPINS_PER_DEADLOCK_CHECK: usize = 1 << 20;
+
+/// Number of `IrqLock` and `IrqSpinLock` guards currently alive
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of `IrqLock` and `IrqSpinLock` guards currently alive.
+///
+/// Code abandoned while this is higher than before it started left a lock
+/// held and the data behind it half updated.
+pub fn held_locks() -> usize {
+    HELD.load(Ordering::SeqCst)
+}
 
 pub struct IrqLock<T: ?Sized> {
     data: UnsafeCell<T>,
@@ -29,6 +47,7 @@ impl<T> IrqLock<T> {
             interrupts::disable();
         }
 
+        HELD.fetch_add(1, Ordering::SeqCst);
         IrqGuard {
             data: unsafe { &mut *self.data.get() },
             was_enabled,
@@ -46,6 +65,7 @@ impl<T> IrqLock<T> {
 
         let data = f(unsafe { &mut *self.data.get() });
 
+        HELD.fetch_add(1, Ordering::SeqCst);
         IrqGuard { data, was_enabled }
     }
 }
@@ -76,20 +96,28 @@ impl<'a, T: ?Sized> DerefMut for IrqGuard<'a, T> {
 
 impl<'a, T: ?Sized> Drop for IrqGuard<'a, T> {
     fn drop(&mut self) {
+        HELD.fetch_sub(1, Ordering::SeqCst);
         if self.was_enabled {
             interrupts::enable();
         }
     }
 }
 
+/// A fair spinlock that keeps interrupts disabled while it is held.
+///
+/// Interrupts are disabled before the lock is acquired so an interrupt handler
+/// taking the same lock cannot deadlock against the code it interrupted.
+/// Waiters are served in the order in which they called `lock`.
 #[derive(Debug)]
 pub struct IrqSpinLock<T: ?Sized> {
-    lock: AtomicBool,
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    class: Option<&'static LockClass>,
     data: UnsafeCell<T>,
 }
 
 pub struct IrqSpinGuard<'a, T: ?Sized + 'a> {
-    lock: &'a AtomicBool,
+    lock: &'a IrqSpinLock<T>,
     was_enabled: bool,
     data: &'a mut T,
 }
@@ -100,48 +128,132 @@ unsafe impl<T: ?Sized + Send> Send for IrqSpinLock<T> {}
 impl<T> IrqSpinLock<T> {
     pub const fn new(data: T) -> IrqSpinLock<T> {
         IrqSpinLock {
-            lock: AtomicBool::new(false),
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            class: None,
             data: UnsafeCell::new(data),
         }
     }
 
-    fn obtain_lock(&self) {
-        while self.lock.compare_and_swap(false, true, Ordering::Acquire) != false {
-            while self.lock.load(Ordering::Relaxed) {
-                interrupts::pause();
-            }
+    /// Create a spinlock whose acquisitions are checked against the ordering of `class`
+    pub const fn with_class(data: T, class: &'static LockClass) -> IrqSpinLock<T> {
+        IrqSpinLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            class: Some(class),
+            data: UnsafeCell::new(data),
         }
     }
+}
 
+impl<T: ?Sized> IrqSpinLock<T> {
+    #[track_caller]
     pub fn lock(&self) -> IrqSpinGuard<T> {
-        self.obtain_lock();
-
         let was_enabled = interrupts::enabled();
         if was_enabled {
             interrupts::disable();
         }
 
+        #[cfg(feature = "lock-debug")]
+        crate::sync::lockdep::acquire(self.addr(), self.class, core::panic::Location::caller());
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "deadlock-detection")]
+        {
+            if self.now_serving.load(Ordering::Acquire) != ticket {
+                deadlock::wait(self.node(), self.name());
+            }
+            let mut spins = 0usize;
+            while self.now_serving.load(Ordering::Acquire) != ticket {
+                spins += 1;
+                if spins % SPINS_PER_DEADLOCK_CHECK == 0 {
+                    deadlock::check();
+                }
+                interrupts::pause();
+            }
+            deadlock::hold(self.node(), self.name());
+        }
+
+        #[cfg(not(feature = "deadlock-detection"))]
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            interrupts::pause();
+        }
+
+        HELD.fetch_add(1, Ordering::SeqCst);
         IrqSpinGuard {
-            lock: &self.lock,
+            lock: self,
             was_enabled,
             data: unsafe { &mut *self.data.get() },
         }
     }
 
+    #[track_caller]
     pub fn try_lock(&self) -> Option<IrqSpinGuard<T>> {
-        if self.lock.compare_and_swap(false, true, Ordering::Acquire) == false {
-            let was_enabled = interrupts::enabled();
+        let was_enabled = interrupts::enabled();
+        if was_enabled {
+            interrupts::disable();
+        }
+
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        let acquired = self
+            .next_ticket
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+
+        if !acquired {
             if was_enabled {
-                interrupts::disable();
+                interrupts::enable();
             }
-            Some(IrqSpinGuard {
-                lock: &self.lock,
-                was_enabled,
-                data: unsafe { &mut *self.data.get() },
-            })
-        } else {
-            None
+            return None;
         }
+
+        #[cfg(feature = "lock-debug")]
+        crate::sync::lockdep::acquire_nonblocking(
+            self.addr(),
+            self.class,
+            core::panic::Location::caller(),
+        );
+
+        #[cfg(feature = "deadlock-detection")]
+        deadlock::hold(self.node(), self.name());
+
+        HELD.fetch_add(1, Ordering::SeqCst);
+        Some(IrqSpinGuard {
+            lock: self,
+            was_enabled,
+            data: unsafe { &mut *self.data.get() },
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Returns the call site currently holding the lock
+    #[cfg(feature = "lock-debug")]
+    pub fn owner(&self) -> Option<&'static core::panic::Location<'static>> {
+        crate::sync::lockdep::owner(self.addr())
+    }
+
+    #[cfg(any(feature = "lock-debug", feature = "deadlock-detection"))]
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    #[cfg(feature = "deadlock-detection")]
+    fn node(&self) -> deadlock::Node {
+        deadlock::Node::Lock(self.addr())
+    }
+
+    #[cfg(feature = "deadlock-detection")]
+    fn name(&self) -> &'static str {
+        self.class.map_or("IrqSpinLock", LockClass::name)
     }
 }
 
@@ -171,7 +283,17 @@ impl<'a, T: ?Sized> DerefMut for IrqSpinGuard<'a, T> {
 
 impl<'a, T: ?Sized> Drop for IrqSpinGuard<'a, T> {
     fn drop(&mut self) {
-        self.lock.store(false, Ordering::Release);
+        #[cfg(feature = "lock-debug")]
+        crate::sync::lockdep::release(self.lock.addr());
+
+        #[cfg(feature = "deadlock-detection")]
+        deadlock::release(self.lock.node());
+
+        let ticket = self.lock.now_serving.load(Ordering::Relaxed);
+        self.lock
+            .now_serving
+            .store(ticket.wrapping_add(1), Ordering::Release);
+        HELD.fetch_sub(1, Ordering::SeqCst);
         if self.was_enabled {
             interrupts::enable();
         }
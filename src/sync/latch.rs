@@ -0,0 +1,109 @@
+//! One-shot async latches for waiting on a group of tasks or an event.
+
+use crate::sync::{IrqSpinLock, LockClass};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+static STATE_CLASS: LockClass = LockClass::new("sync::CountDown::state");
+
+/// A counter that releases all waiting tasks once it reaches zero.
+///
+/// Once the count is zero, it stays there and `wait` completes immediately.
+pub struct CountDown {
+    state: IrqSpinLock<State>,
+}
+
+struct State {
+    count: usize,
+    wakers: Vec<Waker>,
+}
+
+impl CountDown {
+    pub const fn new(count: usize) -> CountDown {
+        CountDown {
+            state: IrqSpinLock::with_class(
+                State {
+                    count,
+                    wakers: Vec::new(),
+                },
+                &STATE_CLASS,
+            ),
+        }
+    }
+
+    /// Decrement the count, waking all waiters when it reaches zero
+    pub fn count_down(&self) {
+        let mut state = self.state.lock();
+        if state.count == 0 {
+            return;
+        }
+
+        state.count -= 1;
+        if state.count == 0 {
+            for waker in state.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.state.lock().count
+    }
+
+    /// Returns true if the count reached zero
+    pub fn try_wait(&self) -> bool {
+        self.count() == 0
+    }
+
+    pub fn wait(&self) -> CountDownWait {
+        CountDownWait { count_down: self }
+    }
+}
+
+pub struct CountDownWait<'a> {
+    count_down: &'a CountDown,
+}
+
+impl<'a> Future for CountDownWait<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.count_down.state.lock();
+        if state.count == 0 {
+            return Poll::Ready(());
+        }
+
+        if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// A gate that blocks tasks until it is opened once
+pub struct Latch {
+    inner: CountDown,
+}
+
+impl Latch {
+    pub const fn new() -> Latch {
+        Latch {
+            inner: CountDown::new(1),
+        }
+    }
+
+    /// Release all current and future waiters
+    pub fn open(&self) {
+        self.inner.count_down();
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.inner.try_wait()
+    }
+
+    pub fn wait(&self) -> CountDownWait {
+        self.inner.wait()
+    }
+}
@@ -0,0 +1,253 @@
+//! Lock debugging for `IrqSpinLock`.
+//!
+//! Every spinlock may belong to a `LockClass`. With the `lock-debug` feature
+//! enabled, each acquisition records its call site, recursive acquisition of a
+//! held lock panics, and the order in which lock classes are nested is recorded
+//! in a global graph. Taking two classes in the opposite order of a previously
+//! recorded nesting panics with both acquisition sites.
+
+#[cfg(feature = "lock-debug")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of distinct lock classes tracked by the order graph.
+pub const MAX_LOCK_CLASSES: usize = 32;
+
+/// Maximum number of spinlocks that may be held at the same time.
+pub const MAX_HELD_LOCKS: usize = 16;
+
+/// A group of locks that share the same ordering rules.
+#[derive(Debug)]
+pub struct LockClass {
+    name: &'static str,
+    #[cfg(feature = "lock-debug")]
+    id: AtomicUsize,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> LockClass {
+        LockClass {
+            name,
+            #[cfg(feature = "lock-debug")]
+            id: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the index of this class in the order graph, assigning one on first use.
+    #[cfg(feature = "lock-debug")]
+    fn id(&self) -> usize {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+        let id = self.id.load(Ordering::Acquire);
+        if id != 0 {
+            return id - 1;
+        }
+
+        let new_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        assert!(
+            new_id <= MAX_LOCK_CLASSES,
+            "lockdep: too many lock classes (registering {})",
+            self.name
+        );
+        match self
+            .id
+            .compare_exchange(0, new_id, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => new_id - 1,
+            Err(existing) => existing - 1,
+        }
+    }
+}
+
+#[cfg(feature = "lock-debug")]
+pub use self::debug::*;
+
+#[cfg(feature = "lock-debug")]
+mod debug {
+    use super::{LockClass, MAX_HELD_LOCKS, MAX_LOCK_CLASSES};
+    use crate::sync::IrqLock;
+    use core::panic::Location;
+
+    type Site = &'static Location<'static>;
+
+    #[derive(Clone, Copy)]
+    struct HeldLock {
+        lock: usize,
+        class: Option<&'static LockClass>,
+        site: Site,
+    }
+
+    /// A recorded nesting: the site where the outer class was taken and the
+    /// site where the inner class was taken while the outer one was held.
+    #[derive(Clone, Copy)]
+    struct Dependency {
+        held: Site,
+        acquired: Site,
+    }
+
+    struct State {
+        held: [Option<HeldLock>; MAX_HELD_LOCKS],
+        depth: usize,
+        classes: [Option<&'static LockClass>; MAX_LOCK_CLASSES],
+        /// `order[a][b]` is set once class `b` was acquired while holding class `a`
+        order: [[Option<Dependency>; MAX_LOCK_CLASSES]; MAX_LOCK_CLASSES],
+    }
+
+    // Not an `IrqSpinLock` so that the checker does not track itself
+    static STATE: IrqLock<State> = IrqLock::new(State {
+        held: [None; MAX_HELD_LOCKS],
+        depth: 0,
+        classes: [None; MAX_LOCK_CLASSES],
+        order: [[None; MAX_LOCK_CLASSES]; MAX_LOCK_CLASSES],
+    });
+
+    fn class_name(class: Option<&'static LockClass>) -> &'static str {
+        class.map_or("<anonymous>", |c| c.name())
+    }
+
+    impl State {
+        /// Returns the first dependency on a path `from -> ... -> to`, if one exists.
+        fn path(&self, from: usize, to: usize) -> Option<(usize, Dependency)> {
+            let mut visited = [false; MAX_LOCK_CLASSES];
+            let mut stack = [0usize; MAX_LOCK_CLASSES];
+            let mut first_hop = [0usize; MAX_LOCK_CLASSES];
+            let mut top = 0;
+
+            for next in 0..MAX_LOCK_CLASSES {
+                if self.order[from][next].is_some() && !visited[next] {
+                    visited[next] = true;
+                    first_hop[next] = next;
+                    stack[top] = next;
+                    top += 1;
+                }
+            }
+
+            while top > 0 {
+                top -= 1;
+                let class = stack[top];
+                if class == to {
+                    let hop = first_hop[class];
+                    return self.order[from][hop].map(|dep| (hop, dep));
+                }
+                for next in 0..MAX_LOCK_CLASSES {
+                    if self.order[class][next].is_some() && !visited[next] {
+                        visited[next] = true;
+                        first_hop[next] = first_hop[class];
+                        stack[top] = next;
+                        top += 1;
+                    }
+                }
+            }
+
+            None
+        }
+    }
+
+    /// Validate and record the acquisition of `lock` at `site`.
+    ///
+    /// Must be called with interrupts disabled, before spinning on the lock.
+    pub fn acquire(lock: usize, class: Option<&'static LockClass>, site: Site) {
+        let mut state = STATE.lock();
+
+        for held in state.held[..state.depth].iter().flatten() {
+            if held.lock == lock {
+                panic!(
+                    "lockdep: recursive locking of {} at {}\n  already held since {}",
+                    class_name(class),
+                    site,
+                    held.site
+                );
+            }
+        }
+
+        if let Some(class) = class {
+            let id = class.id();
+            state.classes[id] = Some(class);
+            for i in 0..state.depth {
+                let held = match state.held[i] {
+                    Some(held) => held,
+                    None => continue,
+                };
+                let held_class = match held.class {
+                    Some(held_class) => held_class,
+                    None => continue,
+                };
+                let held_id = held_class.id();
+                if held_id == id {
+                    continue;
+                }
+
+                if let Some((hop, dep)) = state.path(id, held_id) {
+                    let hop_name = class_name(state.classes[hop]);
+                    panic!(
+                        "lockdep: lock order violation\n  acquiring {} at {}\n  while holding {} acquired at {}\n  but {} was held at {}\n  when {} was acquired at {}",
+                        class.name(),
+                        site,
+                        held_class.name(),
+                        held.site,
+                        class.name(),
+                        dep.held,
+                        hop_name,
+                        dep.acquired
+                    );
+                }
+
+                if state.order[held_id][id].is_none() {
+                    state.order[held_id][id] = Some(Dependency {
+                        held: held.site,
+                        acquired: site,
+                    });
+                }
+            }
+        }
+
+        push(&mut state, HeldLock { lock, class, site });
+    }
+
+    /// Record a successful `try_lock`. No ordering is checked as it cannot block.
+    pub fn acquire_nonblocking(lock: usize, class: Option<&'static LockClass>, site: Site) {
+        let mut state = STATE.lock();
+        push(&mut state, HeldLock { lock, class, site });
+    }
+
+    /// Forget about a released lock.
+    pub fn release(lock: usize) {
+        let mut state = STATE.lock();
+        let depth = state.depth;
+        let index = state.held[..depth]
+            .iter()
+            .rposition(|held| held.map_or(false, |h| h.lock == lock))
+            .expect("lockdep: releasing a lock that is not held");
+
+        for i in index..depth - 1 {
+            state.held[i] = state.held[i + 1];
+        }
+        state.held[depth - 1] = None;
+        state.depth -= 1;
+    }
+
+    /// Returns the site that acquired `lock`, if it is currently held.
+    pub fn owner(lock: usize) -> Option<Site> {
+        let state = STATE.lock();
+        state.held[..state.depth]
+            .iter()
+            .flatten()
+            .find(|held| held.lock == lock)
+            .map(|held| held.site)
+    }
+
+    fn push(state: &mut State, held: HeldLock) {
+        assert!(
+            state.depth < MAX_HELD_LOCKS,
+            "lockdep: too many locks held while acquiring {} at {}",
+            class_name(held.class),
+            held.site
+        );
+        let depth = state.depth;
+        state.held[depth] = Some(held);
+        state.depth += 1;
+    }
+}
@@ -1,3 +1,17 @@
+pub mod barrier;
+#[cfg(feature = "deadlock-detection")]
+pub mod deadlock;
 pub mod irq;
+pub mod latch;
+pub mod lockdep;
+pub mod mutex;
+pub mod rcu;
+pub mod seqlock;
 
-pub use self::irq::{IrqGuard, IrqLock, IrqSpinLock};
+pub use self::barrier::{Barrier, BarrierWaitResult};
+pub use self::irq::{held_locks, IrqGuard, IrqLock, IrqSpinGuard, IrqSpinLock};
+pub use self::latch::{CountDown, Latch};
+pub use self::lockdep::LockClass;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rcu::{Rcu, RcuReadGuard};
+pub use self::seqlock::SeqLock;
@@ -0,0 +1,378 @@
+//! An async mutex with priority inheritance.
+//!
+//! While a task waits on a `Mutex`, the task holding it inherits the priority
+//! of the highest priority waiter. The priority is returned when the mutex is
+//! unlocked, and the lock is handed directly to the highest priority waiter.
+//!
+//! Inheritance is transitive: if the owner is itself waiting on another
+//! mutex, the priority it inherited is passed on to the owner of that mutex,
+//! and so on along the chain of blocked tasks.
+//!
+//! Priorities only apply to tasks spawned on a `PriorityScheduler`. Other
+//! tasks still get mutual exclusion but do not take part in inheritance.
+
+#[cfg(feature = "deadlock-detection")]
+use crate::sync::deadlock;
+use crate::sync::{IrqSpinLock, LockClass};
+use crate::task::{self, priority, Priority, TaskId};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use lazy_static::lazy_static;
+
+static STATE_CLASS: LockClass = LockClass::new("sync::Mutex::state");
+static BLOCKED_ON_CLASS: LockClass = LockClass::new("sync::Mutex::BLOCKED_ON");
+
+lazy_static! {
+    /// The mutex each waiting task is blocked on, used to pass inherited
+    /// priorities along chains of blocked tasks. Always taken before the
+    /// state lock of a mutex.
+    static ref BLOCKED_ON: IrqSpinLock<BTreeMap<TaskId, Blocker>> =
+        IrqSpinLock::with_class(BTreeMap::new(), &BLOCKED_ON_CLASS);
+}
+
+/// The state of a mutex a task waits on.
+///
+/// A task is removed from `BLOCKED_ON` before its lock future completes or is
+/// dropped, so the mutex outlives its entry.
+struct Blocker(*const IrqSpinLock<State>);
+
+unsafe impl Send for Blocker {}
+
+/// Tasks whose inherited priority changed, see `State::update_inheritance`
+type Affected = [Option<TaskId>; 2];
+
+pub struct Mutex<T: ?Sized> {
+    state: IrqSpinLock<State>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+struct Waiter {
+    ticket: u64,
+    task_id: Option<TaskId>,
+    waker: Waker,
+}
+
+struct State {
+    locked: bool,
+    owner: Option<TaskId>,
+    /// Ticket of the waiter the lock was handed to but that has not polled yet
+    handoff: Option<u64>,
+    next_ticket: u64,
+    waiters: VecDeque<Waiter>,
+    /// Priority currently lent to the owner by this mutex
+    lent: Option<(TaskId, Priority)>,
+}
+
+impl State {
+    fn waiter_priority(waiter: &Waiter) -> Option<Priority> {
+        waiter.task_id.and_then(priority::effective)
+    }
+
+    /// Lend the highest waiter priority to the owner, replacing any earlier loan.
+    ///
+    /// Returns the tasks that lost or gained a loan, which must be passed to
+    /// `propagate` once the state lock is released.
+    fn update_inheritance(&mut self) -> Affected {
+        let wanted = match self.owner {
+            Some(owner) => self
+                .waiters
+                .iter()
+                .filter_map(State::waiter_priority)
+                .max()
+                .map(|p| (owner, p)),
+            None => None,
+        };
+
+        if wanted == self.lent {
+            return [None; 2];
+        }
+        let lost = self.lent.take().map(|(task_id, p)| {
+            priority::disinherit(task_id, p);
+            task_id
+        });
+        let gained = wanted.map(|(task_id, p)| {
+            priority::inherit(task_id, p);
+            task_id
+        });
+        self.lent = wanted;
+        [lost, gained]
+    }
+
+    /// Hand the lock to the highest priority waiter, or unlock it if there is none
+    fn release(&mut self) -> Affected {
+        let next = self
+            .waiters
+            .iter()
+            .enumerate()
+            .max_by(|(ia, a), (ib, b)| {
+                // earlier waiters win ties
+                State::waiter_priority(a)
+                    .cmp(&State::waiter_priority(b))
+                    .then(ib.cmp(ia))
+            })
+            .map(|(index, _)| index)
+            .and_then(|index| self.waiters.remove(index));
+
+        match next {
+            Some(waiter) => {
+                self.owner = waiter.task_id;
+                self.handoff = Some(waiter.ticket);
+                let affected = self.update_inheritance();
+                waiter.waker.wake();
+                affected
+            }
+            None => {
+                self.locked = false;
+                self.owner = None;
+                self.update_inheritance()
+            }
+        }
+    }
+}
+
+/// Forget the mutex a killed task was blocked on. Its lock future was leaked,
+/// so nothing else removes the entry before the mutex may be dropped.
+pub(crate) fn forget(task_id: TaskId) {
+    BLOCKED_ON.lock().remove(&task_id);
+}
+
+/// Pass changed priorities of `tasks` on to the owners of the mutexes they
+/// are blocked on, following the chain until no loan changes.
+fn propagate(tasks: Affected) {
+    for task_id in tasks.iter().flatten() {
+        let affected = {
+            let blocked_on = BLOCKED_ON.lock();
+            let state = match blocked_on.get(task_id) {
+                Some(blocker) => unsafe { &*blocker.0 },
+                None => continue,
+            };
+            let mut state = state.lock();
+            state.update_inheritance()
+        };
+        propagate(affected);
+    }
+}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Mutex<T> {
+        Mutex {
+            state: IrqSpinLock::with_class(
+                State {
+                    locked: false,
+                    owner: None,
+                    handoff: None,
+                    next_ticket: 0,
+                    waiters: VecDeque::new(),
+                    lent: None,
+                },
+                &STATE_CLASS,
+            ),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Returns a future that resolves once the mutex is held by the current task
+    pub fn lock(&self) -> MutexLockFuture<T> {
+        MutexLockFuture {
+            mutex: self,
+            ticket: None,
+            task_id: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        state.owner = task::current();
+
+        #[cfg(feature = "deadlock-detection")]
+        deadlock::hold(self.node(), "Mutex");
+
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// Returns the task currently holding the mutex
+    pub fn owner(&self) -> Option<TaskId> {
+        self.state.lock().owner
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().locked
+    }
+
+    /// Release the lock held by a guard or pending handoff
+    fn unlock(&self, state: &mut State) -> Affected {
+        let affected = state.release();
+
+        #[cfg(feature = "deadlock-detection")]
+        deadlock::handoff(self.node(), "Mutex", state.owner);
+
+        affected
+    }
+
+    /// Node of the mutex in the deadlock graph, distinct from its state lock.
+    ///
+    /// The mutex cannot move while it is held or waited on, so its address
+    /// identifies it for as long as it is in the graph.
+    #[cfg(feature = "deadlock-detection")]
+    fn node(&self) -> deadlock::Node {
+        deadlock::Node::Mutex(self as *const Self as *const u8 as usize)
+    }
+}
+
+impl<T: ?Sized + Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(Default::default())
+    }
+}
+
+pub struct MutexLockFuture<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    ticket: Option<u64>,
+    /// Task registered in `BLOCKED_ON` while waiting
+    task_id: Option<TaskId>,
+}
+
+impl<'a, T: ?Sized> MutexLockFuture<'a, T> {
+    fn unblock(&mut self) {
+        if let Some(task_id) = self.task_id.take() {
+            BLOCKED_ON.lock().remove(&task_id);
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Future for MutexLockFuture<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock();
+
+        match self.ticket {
+            Some(ticket) if state.handoff == Some(ticket) => {
+                state.handoff = None;
+                self.ticket = None;
+                drop(state);
+                self.unblock();
+                return Poll::Ready(MutexGuard { mutex });
+            }
+            Some(ticket) => {
+                // still waiting, refresh the waker in case the task moved
+                if let Some(waiter) = state.waiters.iter_mut().find(|w| w.ticket == ticket) {
+                    if !waiter.waker.will_wake(cx.waker()) {
+                        waiter.waker = cx.waker().clone();
+                    }
+                }
+            }
+            None if !state.locked => {
+                state.locked = true;
+                state.owner = task::current();
+
+                #[cfg(feature = "deadlock-detection")]
+                deadlock::hold(mutex.node(), "Mutex");
+
+                return Poll::Ready(MutexGuard { mutex });
+            }
+            None => {
+                let ticket = state.next_ticket;
+                state.next_ticket += 1;
+                state.waiters.push_back(Waiter {
+                    ticket,
+                    task_id: task::current(),
+                    waker: cx.waker().clone(),
+                });
+                self.ticket = Some(ticket);
+                self.task_id = task::current();
+
+                #[cfg(feature = "deadlock-detection")]
+                deadlock::wait(mutex.node(), "Mutex");
+            }
+        }
+
+        let affected = state.update_inheritance();
+        drop(state);
+
+        if let Some(task_id) = self.task_id {
+            BLOCKED_ON.lock().insert(task_id, Blocker(&mutex.state));
+        }
+        propagate(affected);
+        Poll::Pending
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexLockFuture<'a, T> {
+    fn drop(&mut self) {
+        let ticket = match self.ticket {
+            Some(ticket) => ticket,
+            None => return,
+        };
+
+        self.unblock();
+        let mut state = self.mutex.state.lock();
+        let affected = if state.handoff == Some(ticket) {
+            // the lock was handed to us but we are no longer interested
+            state.handoff = None;
+            self.mutex.unlock(&mut state)
+        } else {
+            #[cfg(feature = "deadlock-detection")]
+            if let Some(waiter) = state.waiters.iter().find(|w| w.ticket == ticket) {
+                if let Some(task_id) = waiter.task_id {
+                    deadlock::stop_waiting_by(task_id, self.mutex.node());
+                }
+            }
+
+            state.waiters.retain(|w| w.ticket != ticket);
+            state.update_inheritance()
+        };
+        drop(state);
+        propagate(affected);
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// Unlock the mutex
+    pub fn release(self) {}
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let affected = {
+            let mut state = self.mutex.state.lock();
+            self.mutex.unlock(&mut state)
+        };
+        propagate(affected);
+    }
+}
@@ -0,0 +1,152 @@
+//! Read-copy-update for pointer-swapped, read-mostly structures.
+//!
+//! Readers access the current version without locking through `Rcu::read`.
+//! Writers publish a new version and retire the old one, which is reclaimed
+//! once a grace period has elapsed.
+//!
+//! rxinu runs on a single CPU, so a grace period ends at the first quiescent
+//! state with no active read-side critical section. The schedulers report a
+//! quiescent state after every task poll. Interrupt handlers always finish
+//! their critical sections before the scheduler resumes; a task that holds a
+//! read guard across an `.await` delays reclamation until it drops the guard.
+
+use crate::sync::{IrqSpinLock, LockClass};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+static READERS: AtomicUsize = AtomicUsize::new(0);
+static GRACE_PERIODS: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+static RETIRED_CLASS: LockClass = LockClass::new("sync::rcu::RETIRED");
+static RETIRED: IrqSpinLock<Vec<Retired>> = IrqSpinLock::with_class(Vec::new(), &RETIRED_CLASS);
+
+/// An old version waiting for the end of a grace period
+struct Retired {
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+unsafe impl Send for Retired {}
+
+unsafe fn drop_boxed<T>(ptr: *mut ()) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+/// Report a quiescent state.
+///
+/// If no read-side critical section is active, a grace period has elapsed and
+/// every version retired so far is reclaimed.
+pub fn quiescent_state() {
+    if PENDING.load(Ordering::Acquire) == 0 || READERS.load(Ordering::Acquire) != 0 {
+        return;
+    }
+
+    let retired = mem::take(&mut *RETIRED.lock());
+    PENDING.fetch_sub(retired.len(), Ordering::AcqRel);
+    GRACE_PERIODS.fetch_add(1, Ordering::AcqRel);
+
+    for r in retired {
+        unsafe { (r.drop)(r.ptr) };
+    }
+}
+
+/// Returns the number of grace periods that have completed
+pub fn grace_periods() -> u64 {
+    GRACE_PERIODS.load(Ordering::Acquire)
+}
+
+/// Returns the number of retired versions waiting to be reclaimed
+pub fn pending() -> usize {
+    PENDING.load(Ordering::Acquire)
+}
+
+/// Returns true if a read-side critical section is active
+pub fn in_read_section() -> bool {
+    READERS.load(Ordering::Acquire) != 0
+}
+
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>,
+    writer: IrqSpinLock<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    pub fn new(data: T) -> Rcu<T> {
+        Rcu {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(data))),
+            writer: IrqSpinLock::new(()),
+        }
+    }
+
+    /// Enter a read-side critical section and return the current version
+    pub fn read(&self) -> RcuReadGuard<T> {
+        READERS.fetch_add(1, Ordering::AcqRel);
+        let data = unsafe { &*self.ptr.load(Ordering::Acquire) };
+        RcuReadGuard {
+            data,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Publish a new version, retiring the current one
+    pub fn update(&self, data: T) {
+        let _guard = self.writer.lock();
+        self.replace(data);
+    }
+
+    /// Publish a version derived from the current one
+    pub fn update_with<F>(&self, f: F)
+    where
+        F: FnOnce(&T) -> T,
+    {
+        let _guard = self.writer.lock();
+        let new = f(unsafe { &*self.ptr.load(Ordering::Acquire) });
+        self.replace(new);
+    }
+
+    fn replace(&self, data: T) {
+        let old = self
+            .ptr
+            .swap(Box::into_raw(Box::new(data)), Ordering::AcqRel);
+
+        RETIRED.lock().push(Retired {
+            ptr: old as *mut (),
+            drop: drop_boxed::<T>,
+        });
+        PENDING.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // no guard can outlive the borrow of `self`
+        unsafe { drop(Box::from_raw(*self.ptr.get_mut())) };
+    }
+}
+
+pub struct RcuReadGuard<'a, T> {
+    data: &'a T,
+    // the reader count is per CPU
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> Deref for RcuReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T> Drop for RcuReadGuard<'a, T> {
+    fn drop(&mut self) {
+        READERS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
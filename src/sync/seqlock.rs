@@ -0,0 +1,82 @@
+//! A sequence lock for small `Copy` data that is read far more often than written.
+//!
+//! Readers never block writers and never take a lock: they retry if a write
+//! happened while they were copying the data. Writers are serialized by an
+//! `IrqSpinLock`, so interrupts are disabled for the duration of a write and an
+//! interrupt handler on the same CPU never observes a write in progress.
+
+use crate::arch::interrupts;
+use crate::sync::IrqSpinLock;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    writer: IrqSpinLock<()>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> SeqLock<T> {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            writer: IrqSpinLock::new(()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Returns a consistent copy of the data
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 == 1 {
+                // write in progress
+                interrupts::pause();
+                continue;
+            }
+
+            let value = unsafe { ptr::read_volatile(self.data.get()) };
+            atomic::fence(Ordering::Acquire);
+
+            if self.seq.load(Ordering::Relaxed) == before {
+                return value;
+            }
+        }
+    }
+
+    pub fn write(&self, value: T) {
+        self.update(|data| *data = value);
+    }
+
+    /// Modify the data in place. `f` must be short, as readers spin while it runs.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T),
+    {
+        let _guard = self.writer.lock();
+
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+
+        let mut value = unsafe { ptr::read_volatile(self.data.get()) };
+        f(&mut value);
+        unsafe { ptr::write_volatile(self.data.get(), value) };
+
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns the number of completed writes
+    pub fn version(&self) -> usize {
+        self.seq.load(Ordering::Acquire) / 2
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> SeqLock<T> {
+        SeqLock::new(Default::default())
+    }
+}
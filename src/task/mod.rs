@@ -1,8 +1,14 @@
-use alloc::boxed::Box;
+use crate::arch::interrupts::fault::{self, Fault};
+use crate::arch::memory::address_space::AddressSpace;
+use crate::crash_println;
+use crate::sync::{self, IrqSpinLock, LockClass};
+use alloc::{boxed::Box, sync::Arc};
+use core::mem;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
+pub mod priority;
 pub mod scheduler;
 pub mod yield_now;
 
@@ -16,16 +22,94 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+const NO_TASK: u64 = u64::MAX;
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+
+/// Returns the id of the task that is currently being polled, if any
+pub fn current() -> Option<TaskId> {
+    match CURRENT_TASK.load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(TaskId(id)),
+    }
+}
+
+/// Number of killed tasks whose fault is remembered
+pub const MAX_KILLED: usize = 16;
+
+static KILLED_CLASS: LockClass = LockClass::new("task::KILLED");
+
+/// The most recently killed tasks, oldest first
+static KILLED: IrqSpinLock<[Option<(TaskId, Fault)>; MAX_KILLED]> =
+    IrqSpinLock::with_class([None; MAX_KILLED], &KILLED_CLASS);
+
+fn record_kill(id: TaskId, fault: Fault) {
+    crash_println!("\nTask {:?} killed: {}", id, fault);
+    let mut killed = KILLED.lock();
+    killed.rotate_left(1);
+    killed[MAX_KILLED - 1] = Some((id, fault));
+}
+
+/// Returns the fault that killed a task, if it was among the last `MAX_KILLED` killed tasks
+pub fn kill_reason(id: TaskId) -> Option<Fault> {
+    KILLED
+        .lock()
+        .iter()
+        .flatten()
+        .find(|(killed, _)| *killed == id)
+        .map(|(_, fault)| *fault)
+}
+
+/// Poll `task` with `context`, recording it as the current task for the duration.
+///
+/// A fault during the poll kills the task: it is abandoned and reported as finished.
+pub(crate) fn poll_as_current<T: TaskFuture>(task: &mut T, context: &mut Context) -> Poll<()> {
+    // Tasks without an address space only touch kernel memory, which is shared
+    // by all address spaces, so they run in whichever one is active.
+    if let Some(space) = task.address_space() {
+        space.activate();
+    }
+
+    let previous = CURRENT_TASK.swap(task.id().0, Ordering::Relaxed);
+    let result = fault::catch(|| task.poll(context));
+    CURRENT_TASK.store(previous, Ordering::Relaxed);
+
+    match result {
+        Ok(poll) => poll,
+        Err(fault) => {
+            task.abandon();
+            sync::mutex::forget(task.id());
+            #[cfg(feature = "deadlock-detection")]
+            sync::deadlock::forget(task.id());
+            record_kill(task.id(), fault);
+            Poll::Ready(())
+        }
+    }
 }
 
 pub trait TaskFuture {
     fn id(&self) -> TaskId;
     fn poll(&mut self, context: &mut Context) -> Poll<()>;
+
+    /// Give up on a task whose poll was abandoned by a fault. Its future may
+    /// be in an inconsistent state, so it must be leaked rather than dropped.
+    fn abandon(&mut self);
+
+    /// Returns the address space the task runs in, if it has its own
+    fn address_space(&self) -> Option<&AddressSpace> {
+        None
+    }
 }
 
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    address_space: Option<Arc<AddressSpace>>,
 }
 
 impl Task {
@@ -33,6 +117,18 @@ impl Task {
         Task {
             id: TaskId::new(),
             future: Box::pin(future),
+            address_space: None,
+        }
+    }
+
+    /// Create a task that runs in `address_space`
+    pub fn with_address_space(
+        address_space: Arc<AddressSpace>,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Self {
+        Task {
+            address_space: Some(address_space),
+            ..Task::new(future)
         }
     }
 }
@@ -45,6 +141,14 @@ impl TaskFuture for Task {
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
+
+    fn abandon(&mut self) {
+        mem::forget(mem::replace(&mut self.future, Box::pin(async {})));
+    }
+
+    fn address_space(&self) -> Option<&AddressSpace> {
+        self.address_space.as_deref()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -80,4 +184,12 @@ impl TaskFuture for PriorityTask {
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.inner.future.as_mut().poll(context)
     }
+
+    fn abandon(&mut self) {
+        self.inner.abandon();
+    }
+
+    fn address_space(&self) -> Option<&AddressSpace> {
+        self.inner.address_space()
+    }
 }
@@ -0,0 +1,138 @@
+//! Effective task priorities used for priority inheritance.
+//!
+//! Every task spawned on a `PriorityScheduler` is registered here with its base
+//! priority. Blocking primitives may temporarily lend a higher priority to a
+//! task; the scheduler queues the task according to the highest priority it
+//! currently holds.
+
+use crate::sync::{IrqSpinLock, LockClass};
+use crate::task::{Priority, TaskId};
+use alloc::collections::BTreeMap;
+use core::task::Waker;
+use lazy_static::lazy_static;
+
+const NUM_PRIORITIES: usize = 3;
+
+static PRIORITIES_CLASS: LockClass = LockClass::new("task::priority::PRIORITIES");
+
+lazy_static! {
+    static ref PRIORITIES: IrqSpinLock<BTreeMap<TaskId, Entry>> =
+        IrqSpinLock::with_class(BTreeMap::new(), &PRIORITIES_CLASS);
+}
+
+struct Entry {
+    base: Priority,
+    /// Number of outstanding inherited priorities, indexed by priority
+    inherited: [usize; NUM_PRIORITIES],
+    /// Priority of the run queue the task is waiting in, if it is runnable
+    queued: Option<Priority>,
+    waker: Waker,
+}
+
+impl Entry {
+    fn effective(&self) -> Priority {
+        let inherited = [Priority::High, Priority::Medium, Priority::Low]
+            .iter()
+            .copied()
+            .find(|&p| self.inherited[p as usize] > 0);
+        match inherited {
+            Some(p) if p > self.base => p,
+            _ => self.base,
+        }
+    }
+}
+
+/// Register a task with its base priority and the waker used to requeue it
+pub(crate) fn register(task_id: TaskId, base: Priority, waker: Waker) {
+    PRIORITIES.lock().insert(
+        task_id,
+        Entry {
+            base,
+            inherited: [0; NUM_PRIORITIES],
+            queued: None,
+            waker,
+        },
+    );
+}
+
+pub(crate) fn unregister(task_id: TaskId) {
+    PRIORITIES.lock().remove(&task_id);
+}
+
+/// Returns the base priority of a registered task
+pub fn base(task_id: TaskId) -> Option<Priority> {
+    PRIORITIES.lock().get(&task_id).map(|entry| entry.base)
+}
+
+/// Returns the priority a registered task is currently scheduled at
+pub fn effective(task_id: TaskId) -> Option<Priority> {
+    PRIORITIES.lock().get(&task_id).map(Entry::effective)
+}
+
+/// Lend `priority` to a task until a matching call to `disinherit`.
+///
+/// If this raises the task's effective priority above the run queue it is
+/// waiting in, the task is woken so that it is requeued at the new priority.
+/// A task that is not runnable is left alone, as it is queued at its effective
+/// priority once it is woken.
+pub fn inherit(task_id: TaskId, priority: Priority) {
+    let waker = {
+        let mut priorities = PRIORITIES.lock();
+        let entry = match priorities.get_mut(&task_id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.inherited[priority as usize] += 1;
+        match entry.queued {
+            Some(queued) if queued < entry.effective() => Some(entry.waker.clone()),
+            _ => None,
+        }
+    };
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Return a priority previously lent with `inherit`
+pub fn disinherit(task_id: TaskId, priority: Priority) {
+    if let Some(entry) = PRIORITIES.lock().get_mut(&task_id) {
+        let count = &mut entry.inherited[priority as usize];
+        *count = count.saturating_sub(1);
+    }
+}
+
+/// Mark a woken task as runnable and return the run queue to push it to.
+///
+/// Returns `None` if the task is already queued at its effective priority or
+/// higher. Tasks that are not registered are queued at `Priority::Low`.
+pub(crate) fn enqueue(task_id: TaskId) -> Option<Priority> {
+    let mut priorities = PRIORITIES.lock();
+    let entry = match priorities.get_mut(&task_id) {
+        Some(entry) => entry,
+        None => return Some(Priority::Low),
+    };
+    let effective = entry.effective();
+    match entry.queued {
+        Some(queued) if queued >= effective => None,
+        _ => {
+            entry.queued = Some(effective);
+            Some(effective)
+        }
+    }
+}
+
+/// Mark a task popped from the run queue of `priority` as no longer queued.
+///
+/// Returns false for a stale entry left behind when the task was requeued at
+/// a higher priority, which must not be run.
+pub(crate) fn dequeue(task_id: TaskId, priority: Priority) -> bool {
+    match PRIORITIES.lock().get_mut(&task_id) {
+        Some(entry) if entry.queued == Some(priority) => {
+            entry.queued = None;
+            true
+        }
+        Some(_) => false,
+        None => true,
+    }
+}
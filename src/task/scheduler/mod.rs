@@ -23,13 +23,24 @@ pub trait Scheduler<T: TaskFuture> {
     fn kill(&mut self, task_id: TaskId) -> Result<(), Error>;
 }
 
+/// A queue of runnable tasks that wakers push into
+trait TaskQueue: Send + Sync {
+    fn push_task(&self, task_id: TaskId);
+}
+
+impl TaskQueue for ArrayQueue<TaskId> {
+    fn push_task(&self, task_id: TaskId) {
+        self.push(task_id).expect("task_queue full");
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<dyn TaskQueue>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<dyn TaskQueue>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
@@ -37,7 +48,7 @@ impl TaskWaker {
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.task_queue.push_task(self.task_id);
     }
 }
 
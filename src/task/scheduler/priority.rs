@@ -1,15 +1,67 @@
-use super::{Error, Scheduler, TaskWaker};
+use super::{Error, Scheduler, TaskQueue, TaskWaker};
 use crate::arch::interrupts;
-use crate::task::{Priority, PriorityTask, TaskFuture, TaskId};
+use crate::sync::rcu;
+use crate::task::{self, priority, Priority, PriorityTask, TaskFuture, TaskId};
 use alloc::{collections::BTreeMap, sync::Arc};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 
+/// Run queues for each priority level.
+///
+/// Woken tasks are queued at their effective priority, which may be raised
+/// above their base priority through priority inheritance.
+struct PriorityQueues {
+    high: ArrayQueue<TaskId>,
+    medium: ArrayQueue<TaskId>,
+    low: ArrayQueue<TaskId>,
+}
+
+impl PriorityQueues {
+    fn new() -> Self {
+        PriorityQueues {
+            high: ArrayQueue::new(1024),
+            medium: ArrayQueue::new(1024),
+            low: ArrayQueue::new(1024),
+        }
+    }
+
+    fn queue(&self, priority: Priority) -> &ArrayQueue<TaskId> {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Medium => &self.medium,
+            Priority::Low => &self.low,
+        }
+    }
+
+    /// Pop the next runnable task, skipping entries of requeued tasks
+    fn pop(&self) -> Option<TaskId> {
+        for &priority in &[Priority::High, Priority::Medium, Priority::Low] {
+            while let Ok(task_id) = self.queue(priority).pop() {
+                if priority::dequeue(task_id, priority) {
+                    return Some(task_id);
+                }
+            }
+        }
+        None
+    }
+
+    fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.medium.is_empty() && self.low.is_empty()
+    }
+}
+
+impl TaskQueue for PriorityQueues {
+    fn push_task(&self, task_id: TaskId) {
+        // tasks that already finished are dropped when popped
+        if let Some(priority) = priority::enqueue(task_id) {
+            self.queue(priority).push_task(task_id);
+        }
+    }
+}
+
 pub struct PriorityScheduler {
     tasks: BTreeMap<TaskId, PriorityTask>,
-    high_queue: Arc<ArrayQueue<TaskId>>,
-    medium_queue: Arc<ArrayQueue<TaskId>>,
-    low_queue: Arc<ArrayQueue<TaskId>>,
+    queues: Arc<PriorityQueues>,
     waker_cache: BTreeMap<TaskId, Waker>,
 }
 
@@ -17,43 +69,40 @@ impl PriorityScheduler {
     pub fn new() -> Self {
         PriorityScheduler {
             tasks: BTreeMap::new(),
-            high_queue: Arc::new(ArrayQueue::new(1024)),
-            medium_queue: Arc::new(ArrayQueue::new(1024)),
-            low_queue: Arc::new(ArrayQueue::new(1024)),
+            queues: Arc::new(PriorityQueues::new()),
             waker_cache: BTreeMap::new(),
         }
     }
 
     pub fn run_ready_tasks(&mut self) {
-        while !self.is_idle() {
-            if let Ok(task_id) = self.high_queue.pop() {
-                self.execute_priority_task(task_id, self.high_queue.clone());
-            } else if let Ok(task_id) = self.medium_queue.pop() {
-                self.execute_priority_task(task_id, self.medium_queue.clone());
-            } else if let Ok(task_id) = self.low_queue.pop() {
-                self.execute_priority_task(task_id, self.low_queue.clone());
-            }
+        while let Some(task_id) = self.queues.pop() {
+            self.execute_priority_task(task_id);
         }
     }
 
-    fn execute_priority_task(&mut self, task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) {
+    fn execute_priority_task(&mut self, task_id: TaskId) {
         let Self {
-            tasks, waker_cache, ..
+            tasks,
+            queues,
+            waker_cache,
         } = self;
 
         if let Some(task) = tasks.get_mut(&task_id) {
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(task_id, queues.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            match task::poll_as_current(task, &mut context) {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    priority::unregister(task_id);
                 }
                 Poll::Pending => {}
             }
+            // a poll boundary is a quiescent state
+            rcu::quiescent_state();
         }
     }
 
@@ -67,9 +116,7 @@ impl PriorityScheduler {
     }
 
     fn is_idle(&self) -> bool {
-        return self.high_queue.is_empty()
-            && self.medium_queue.is_empty()
-            && self.low_queue.is_empty();
+        self.queues.is_empty()
     }
 }
 
@@ -77,6 +124,10 @@ impl Scheduler<PriorityTask> for PriorityScheduler {
     fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
+
+            #[cfg(feature = "deadlock-detection")]
+            crate::sync::deadlock::run_pending_check();
+
             self.sleep_if_idle();
         }
     }
@@ -84,27 +135,26 @@ impl Scheduler<PriorityTask> for PriorityScheduler {
     fn spawn(&mut self, task: PriorityTask) -> Result<(), Error> {
         let task_id = task.id();
         let priority = task.priority();
-        if self.tasks.insert(task_id, task).is_some() {
+        if self.tasks.contains_key(&task_id) {
             return Err(Error::DuplicateId);
         }
-        match priority {
-            Priority::High => self
-                .high_queue
-                .push(task_id)
-                .map_err(|_| Error::TaskQueueFull),
-            Priority::Medium => self
-                .medium_queue
-                .push(task_id)
-                .map_err(|_| Error::TaskQueueFull),
-            Priority::Low => self
-                .low_queue
-                .push(task_id)
-                .map_err(|_| Error::TaskQueueFull),
-        }
+        self.tasks.insert(task_id, task);
+
+        let waker = TaskWaker::new(task_id, self.queues.clone());
+        priority::register(task_id, priority, waker.clone());
+        self.waker_cache.insert(task_id, waker);
+
+        let priority = priority::enqueue(task_id).unwrap_or(priority);
+        self.queues
+            .queue(priority)
+            .push(task_id)
+            .map_err(|_| Error::TaskQueueFull)
     }
 
     fn kill(&mut self, task_id: TaskId) -> Result<(), Error> {
         self.tasks.remove(&task_id).ok_or(Error::UnknownId)?;
+        self.waker_cache.remove(&task_id);
+        priority::unregister(task_id);
         Ok(())
     }
 }
@@ -1,5 +1,6 @@
 use super::{Error, Scheduler, TaskWaker};
-use crate::task::{TaskFuture, TaskId};
+use crate::sync::rcu;
+use crate::task::{self, TaskFuture, TaskId};
 use alloc::{collections::BTreeMap, sync::Arc};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
@@ -36,7 +37,7 @@ impl<T: TaskFuture> RoundRobinScheduler<T> {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            match task::poll_as_current(task, &mut context) {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
@@ -44,6 +45,8 @@ impl<T: TaskFuture> RoundRobinScheduler<T> {
                 }
                 Poll::Pending => {}
             }
+            // a poll boundary is a quiescent state
+            rcu::quiescent_state();
         }
     }
 
@@ -63,6 +66,10 @@ impl<T: TaskFuture> Scheduler<T> for RoundRobinScheduler<T> {
     fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
+
+            #[cfg(feature = "deadlock-detection")]
+            crate::sync::deadlock::run_pending_check();
+
             self.sleep_if_idle();
         }
     }
@@ -1,5 +1,11 @@
+use crate::task::scheduler::{RoundRobinScheduler, Scheduler};
+use crate::task::Task;
 use crate::{serial_print, serial_println};
-use core::panic::PanicInfo;
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::panic::{Location, PanicInfo};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub trait Testable {
     fn run(&self) -> ();
@@ -24,6 +30,37 @@ pub fn test_runner(tests: &[&dyn Testable]) {
     exit_qemu(QemuExitCode::Success);
 }
 
+/// Run each future as a task on a `RoundRobinScheduler` until no task is ready.
+///
+/// Panics if a task did not finish, for example because it is still blocked.
+pub fn run_tasks<I, F>(futures: I)
+where
+    I: IntoIterator<Item = F>,
+    F: Future<Output = ()> + 'static,
+{
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut scheduler = RoundRobinScheduler::new();
+    let mut spawned = 0;
+
+    for future in futures {
+        let finished = finished.clone();
+        scheduler
+            .spawn(Task::new(async move {
+                future.await;
+                finished.fetch_add(1, Ordering::SeqCst);
+            }))
+            .expect("failed to spawn test task");
+        spawned += 1;
+    }
+
+    scheduler.run_ready_tasks();
+    assert_eq!(
+        finished.load(Ordering::SeqCst),
+        spawned,
+        "not all test tasks finished"
+    );
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -40,9 +77,62 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+/// A fixed buffer for formatting while the heap may be unusable.
+struct Buffer {
+    bytes: [u8; 512],
+    len: usize,
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer {
+            bytes: [0; 512],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Returns true if the formatted panic contains `needle`.
+///
+/// The panic is formatted into a fixed buffer rather than the heap, as it may
+/// have been raised by the heap allocator itself.
+pub fn panic_contains(info: &PanicInfo, needle: &str) -> bool {
+    contains(info, needle.as_bytes())
+}
+
+/// Returns true if the formatted panic names the call site `site`.
+pub fn panic_contains_site(info: &PanicInfo, site: &Location) -> bool {
+    let mut needle = Buffer::new();
+    let _ = write!(needle, "{}", site);
+    contains(info, needle.as_bytes())
+}
+
+fn contains(info: &PanicInfo, needle: &[u8]) -> bool {
+    let mut buffer = Buffer::new();
+    let _ = write!(buffer, "{}", info);
+    buffer
+        .as_bytes()
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    crate::arch::debug::dump_current();
     exit_qemu(QemuExitCode::Failed);
     loop {
         unsafe {
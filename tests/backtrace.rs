@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rxinu::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::arch::debug::{self, Backtrace, MAX_FRAMES};
+use rxinu::arch::memory::image;
+use rxinu::ksyms;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rxinu::test::test_panic_handler(info);
+}
+
+#[inline(never)]
+fn outer() -> Vec<u64> {
+    inner()
+}
+
+#[inline(never)]
+fn inner() -> Vec<u64> {
+    debug::save_registers();
+    Backtrace::new(debug::saved_registers().rbp).collect()
+}
+
+fn in_kernel_code(addr: u64) -> bool {
+    image::segment_of(VirtAddr::new(addr)).map_or(false, |segment| segment.executable)
+}
+
+#[test_case]
+fn walks_frame_pointers() {
+    let frames = outer();
+    assert!(frames.len() >= 2, "only {} frames found", frames.len());
+    assert!(frames.len() <= MAX_FRAMES);
+    for &addr in &frames {
+        assert!(in_kernel_code(addr), "{:#x} is not kernel code", addr);
+    }
+
+    // the first return address is in `outer`, right after its call to `inner`
+    let outer = outer as usize as u64;
+    assert!(frames[0] > outer);
+}
+
+#[test_case]
+fn stops_at_invalid_frames() {
+    assert_eq!(Backtrace::new(0).count(), 0);
+    assert_eq!(Backtrace::new(0x1001).count(), 0);
+    assert_eq!(Backtrace::new(0xdead_0000_0000).count(), 0);
+}
+
+/// Set when the symbol table was generated from this test binary, as CI does
+const KSYMS_REQUIRED: bool = option_env!("RXINU_REQUIRE_KSYMS").is_some();
+
+#[test_case]
+fn resolves_symbols() {
+    assert_eq!(ksyms::lookup(0), None);
+    assert!(
+        !KSYMS_REQUIRED || !ksyms::is_empty(),
+        "the kernel symbol table is empty"
+    );
+
+    // the table may come from another binary, so only check consistency
+    let frames = outer();
+    for &addr in &frames {
+        if let Some(symbol) = ksyms::lookup(addr) {
+            assert!(!symbol.name.is_empty());
+            assert_eq!(symbol.addr + symbol.offset, addr);
+        }
+    }
+
+    if KSYMS_REQUIRED {
+        // return addresses point after the call, so look up the call itself
+        let symbol = ksyms::lookup(frames[0] - 1).expect("`outer` was not resolved");
+        assert!(symbol.name.contains("outer"), "resolved to {}", symbol.name);
+    }
+}
@@ -16,9 +16,9 @@ static BREAKPOINT_HANDLER_CALLED: AtomicUsize = AtomicUsize::new(0);
 
 entry_point!(main);
 
-fn main(_boot_info: &'static BootInfo) -> ! {
-    rxinu::arch::idt::init();
-    rxinu::arch::gdt::init();
+fn main(boot_info: &'static BootInfo) -> ! {
+    // the interrupt stacks are allocated from the VMM
+    rxinu::arch::init(boot_info);
     test_main();
     loop {}
 }
@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::sync::IrqSpinLock;
+use rxinu::task::scheduler::{RoundRobinScheduler, Scheduler};
+use rxinu::task::Task;
+use rxinu::test::{exit_qemu, panic_contains, QemuExitCode};
+use rxinu::{serial_print, serial_println};
+
+entry_point!(kernel_main);
+
+static LOCK: IrqSpinLock<u64> = IrqSpinLock::new(0);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    fault_holding_lock();
+    serial_println!("[task was killed with the lock held]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// A task that faults while holding a spinlock must not be killed and
+/// abandoned, as the lock would stay held
+fn fault_holding_lock() {
+    serial_print!("fault_with_lock::fault_holding_lock...\t");
+    let mut scheduler = RoundRobinScheduler::new();
+    scheduler
+        .spawn(Task::new(async {
+            let mut guard = LOCK.lock();
+            // non-canonical, so this raises a general protection fault
+            *guard = unsafe { core::ptr::read_volatile(0x8000_0000_0000 as *const u64) };
+        }))
+        .unwrap();
+    scheduler.run_ready_tasks();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if panic_contains(info, "EXCEPTION: general protection fault") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
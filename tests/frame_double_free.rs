@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::arch::memory::frame;
+use rxinu::test::{exit_qemu, panic_contains, QemuExitCode};
+use rxinu::{serial_print, serial_println};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    merged_double_free();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// Freeing a frame that was already merged into a larger free block must be
+/// caught even though the frame is not the head of that block
+fn merged_double_free() {
+    serial_print!("frame_double_free::merged_double_free...\t");
+    let block = frame::allocate(1).expect("out of frames");
+    unsafe {
+        frame::deallocate(block, 1);
+        frame::deallocate(block + 1, 0);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if panic_contains(info, "double free of frame") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::test::{exit_qemu, panic_contains, QemuExitCode};
+use rxinu::{serial_print, serial_println};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    buffer_overflow();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// Writing past the end of an allocation must be caught when it is freed
+fn buffer_overflow() {
+    serial_print!("heap_debug::buffer_overflow...\t");
+    let mut buffer: Vec<u8> = Vec::with_capacity(16);
+    unsafe { buffer.as_mut_ptr().add(16).write(0) };
+    drop(buffer);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if panic_contains(info, "heap-debug: buffer overflow") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
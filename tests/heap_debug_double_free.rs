@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::test::{exit_qemu, panic_contains, QemuExitCode};
+use rxinu::{serial_print, serial_println};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    double_free();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// Freeing an object twice must be caught while it is quarantined
+fn double_free() {
+    serial_print!("heap_debug_double_free::double_free...\t");
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if panic_contains(info, "heap-debug: double free") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,402 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rxinu::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::arch::loader::{self, Error, AT_ENTRY, AT_NULL, AT_PAGESZ, STACK_TOP};
+use rxinu::arch::memory::address_space::USER_START;
+use rxinu::arch::memory::vmm;
+use rxinu::arch::usermode::Exit;
+use rxinu::elf::{self, Elf, ET_EXEC, PF_R, PF_W, PF_X};
+use rxinu::programs::{self, Archive};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rxinu::test::test_panic_handler(info);
+}
+
+const CODE: u64 = USER_START + 0x40_0000;
+const DATA: u64 = CODE + 0x1000;
+
+const ET_DYN: u16 = 3;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+struct Segment<'a> {
+    vaddr: u64,
+    flags: u32,
+    data: &'a [u8],
+    memsz: u64,
+}
+
+/// Build an x86_64 executable from `segments`
+fn build_elf(file_type: u16, entry: u64, segments: &[Segment]) -> Vec<u8> {
+    let mut file = Vec::new();
+    file.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    file.extend_from_slice(&file_type.to_le_bytes());
+    file.extend_from_slice(&elf::EM_X86_64.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&entry.to_le_bytes());
+    file.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes());
+    file.extend_from_slice(&0u32.to_le_bytes());
+    file.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    file.extend_from_slice(&[0; 6]);
+
+    let mut offset = HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE;
+    for segment in segments {
+        file.extend_from_slice(&elf::PT_LOAD.to_le_bytes());
+        file.extend_from_slice(&segment.flags.to_le_bytes());
+        file.extend_from_slice(&(offset as u64).to_le_bytes());
+        file.extend_from_slice(&segment.vaddr.to_le_bytes());
+        file.extend_from_slice(&segment.vaddr.to_le_bytes());
+        file.extend_from_slice(&(segment.data.len() as u64).to_le_bytes());
+        file.extend_from_slice(&segment.memsz.to_le_bytes());
+        file.extend_from_slice(&0x1000u64.to_le_bytes());
+        offset += segment.data.len();
+    }
+    for segment in segments {
+        file.extend_from_slice(segment.data);
+    }
+    file
+}
+
+fn code(data: &[u8]) -> Segment {
+    Segment {
+        vaddr: CODE,
+        flags: PF_R | PF_X,
+        data,
+        memsz: data.len() as u64,
+    }
+}
+
+/// Returns the displacement of `target` from the end of an instruction at `offset` of `len` bytes
+fn rip_relative(offset: u64, len: u64, target: u64) -> [u8; 4] {
+    (target.wrapping_sub(CODE + offset + len) as u32).to_le_bytes()
+}
+
+/// mov eax, EXIT; syscall; jmp $
+const EXIT: &[u8] = &[0xb8, 0x02, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xeb, 0xfe];
+
+fn with_exit(instructions: &[u8]) -> Vec<u8> {
+    let mut program = instructions.to_vec();
+    program.extend_from_slice(EXIT);
+    program
+}
+
+fn run(file: &[u8], args: &[&str]) -> Exit {
+    let process = loader::load(file, args).expect("failed to load program");
+    process.run().expect("invalid process")
+}
+
+#[test_case]
+fn exits_with_argc() {
+    // mov rdi, [rsp]
+    let program = with_exit(&[0x48, 0x8b, 0x3c, 0x24]);
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    let exit = run(&file, &["prog", "a", "b"]);
+    assert!(matches!(exit, Exit::Exited(3)), "{:?}", exit);
+}
+
+#[test_case]
+fn passes_argv() {
+    // mov rax, [rsp + 16]; movzx rdi, byte [rax]
+    let program = with_exit(&[0x48, 0x8b, 0x44, 0x24, 0x10, 0x48, 0x0f, 0xb6, 0x38]);
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    let exit = run(&file, &["prog", "x"]);
+    assert!(matches!(exit, Exit::Exited(120)), "{:?}", exit);
+}
+
+#[test_case]
+fn stack_is_aligned() {
+    // mov rdi, rsp; and rdi, 15
+    let program = with_exit(&[0x48, 0x89, 0xe7, 0x48, 0x83, 0xe7, 0x0f]);
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    for args in &[
+        &["prog"][..],
+        &["prog", "ab"][..],
+        &["prog", "abc", "d"][..],
+    ] {
+        let exit = run(&file, args);
+        assert!(matches!(exit, Exit::Exited(0)), "{:?}", exit);
+    }
+}
+
+#[test_case]
+fn stack_layout() {
+    let program = with_exit(&[]);
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    let process = loader::load(&file, &["prog", "arg"]).unwrap();
+    let sp = process.stack_pointer;
+    assert!(sp.as_u64() < STACK_TOP);
+
+    let word = |index: u64| {
+        let mut bytes = [0; 8];
+        process.space.read(sp + index * 8, &mut bytes).unwrap();
+        u64::from_le_bytes(bytes)
+    };
+    let string = |addr: u64| {
+        let mut bytes = [0; 4];
+        process.space.read(VirtAddr::new(addr), &mut bytes).unwrap();
+        bytes
+    };
+
+    assert_eq!(word(0), 2);
+    assert_eq!(&string(word(1)), b"prog");
+    assert_eq!(&string(word(2)), b"arg\0");
+    assert_eq!(word(3), 0);
+    assert_eq!(word(4), 0);
+
+    let mut auxv = Vec::new();
+    let mut index = 5;
+    loop {
+        let (key, value) = (word(index), word(index + 1));
+        if key == AT_NULL {
+            break;
+        }
+        auxv.push((key, value));
+        index += 2;
+    }
+    assert!(auxv.contains(&(AT_PAGESZ, vmm::PAGE_SIZE)));
+    assert!(auxv.contains(&(AT_ENTRY, CODE)));
+}
+
+#[test_case]
+fn data_and_bss() {
+    // mov rdi, [rip + DATA]; add rdi, [rip + bss]
+    let mut program = alloc::vec![0x48, 0x8b, 0x3d];
+    program.extend_from_slice(&rip_relative(0, 7, DATA));
+    program.extend_from_slice(&[0x48, 0x03, 0x3d]);
+    program.extend_from_slice(&rip_relative(7, 7, DATA + 0x1000));
+    let program = with_exit(&program);
+
+    let value = 42u64.to_le_bytes();
+    let data = Segment {
+        vaddr: DATA,
+        flags: PF_R | PF_W,
+        data: &value,
+        memsz: 0x2000,
+    };
+    let file = build_elf(ET_EXEC, CODE, &[code(&program), data]);
+    let exit = run(&file, &["prog"]);
+    assert!(matches!(exit, Exit::Exited(42)), "{:?}", exit);
+}
+
+#[test_case]
+fn code_is_read_only() {
+    // lea rax, [rip - 7]; mov byte [rax], 0
+    let program = with_exit(&[0x48, 0x8d, 0x05, 0xf9, 0xff, 0xff, 0xff, 0xc6, 0x00, 0x00]);
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    let exit = run(&file, &["prog"]);
+    match exit {
+        Exit::Faulted(fault) => assert_eq!(fault.address, Some(VirtAddr::new(CODE))),
+        _ => panic!("unexpected exit {:?}", exit),
+    }
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    // jmp to DATA
+    let mut program = alloc::vec![0xe9];
+    program.extend_from_slice(&rip_relative(0, 5, DATA));
+    let data = Segment {
+        vaddr: DATA,
+        flags: PF_R | PF_W,
+        data: EXIT,
+        memsz: EXIT.len() as u64,
+    };
+    let file = build_elf(ET_EXEC, CODE, &[code(&program), data]);
+    let exit = run(&file, &["prog"]);
+    assert!(matches!(exit, Exit::Faulted(fault) if fault.address == Some(VirtAddr::new(DATA))));
+}
+
+#[test_case]
+fn segments_share_page() {
+    // mov rdi, [rip + rodata]
+    let mut program = alloc::vec![0x48, 0x8b, 0x3d];
+    program.extend_from_slice(&rip_relative(0, 7, CODE + 0x800));
+    let program = with_exit(&program);
+
+    let value = 42u64.to_le_bytes();
+    let rodata = Segment {
+        vaddr: CODE + 0x800,
+        flags: PF_R,
+        data: &value,
+        memsz: 0x1000,
+    };
+    let file = build_elf(ET_EXEC, CODE, &[code(&program), rodata]);
+    let process = loader::load(&file, &["prog"]).expect("failed to load program");
+    let regions = process.space.regions();
+    assert!(regions
+        .iter()
+        .any(|region| region.start == VirtAddr::new(CODE)
+            && region.pages == 1
+            && !region.flags.contains(PageTableFlags::NO_EXECUTE)));
+    assert!(regions
+        .iter()
+        .any(|region| region.start == VirtAddr::new(DATA)
+            && region.pages == 1
+            && region.flags.contains(PageTableFlags::NO_EXECUTE)));
+
+    let exit = process.run().expect("invalid process");
+    assert!(matches!(exit, Exit::Exited(42)), "{:?}", exit);
+}
+
+#[test_case]
+fn invalid_files() {
+    let program = with_exit(&[]);
+    let load = |file: &[u8]| loader::load(file, &["prog"]).err().unwrap();
+
+    let mut file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    file[0] = 0;
+    assert!(matches!(load(&file), Error::Elf(elf::Error::BadMagic)));
+
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    assert!(matches!(
+        load(&file[..100]),
+        Error::Elf(elf::Error::Truncated)
+    ));
+    assert!(matches!(
+        load(&file[..file.len() - 1]),
+        Error::SegmentOutOfFile
+    ));
+
+    let file = build_elf(ET_DYN, CODE, &[code(&program)]);
+    assert!(matches!(load(&file), Error::NotExecutable));
+
+    let file = build_elf(ET_EXEC, CODE, &[]);
+    assert!(matches!(load(&file), Error::NoLoadableSegments));
+
+    let file = build_elf(ET_EXEC, DATA, &[code(&program)]);
+    assert!(matches!(load(&file), Error::BadEntry));
+
+    let mut segment = code(&program);
+    segment.flags |= PF_W;
+    let file = build_elf(ET_EXEC, CODE, &[segment]);
+    assert!(matches!(load(&file), Error::WritableAndExecutable));
+
+    let mut segment = code(&program);
+    segment.memsz = 1;
+    let file = build_elf(ET_EXEC, CODE, &[segment]);
+    assert!(matches!(load(&file), Error::BadSegmentSize));
+
+    let mut segment = code(&program);
+    segment.vaddr = 0x20_0000;
+    let file = build_elf(ET_EXEC, 0x20_0000, &[segment]);
+    assert!(matches!(load(&file), Error::BadSegmentAddress));
+
+    let overlapping = Segment {
+        vaddr: CODE + 4,
+        flags: PF_R,
+        data: &[0],
+        memsz: 1,
+    };
+    let file = build_elf(ET_EXEC, CODE, &[code(&program), overlapping]);
+    assert!(matches!(load(&file), Error::OverlappingSegments));
+
+    let writable = Segment {
+        vaddr: CODE + 0x800,
+        flags: PF_R | PF_W,
+        data: &[0],
+        memsz: 1,
+    };
+    let file = build_elf(ET_EXEC, CODE, &[code(&program), writable]);
+    assert!(matches!(load(&file), Error::WritableAndExecutable));
+
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    let long = alloc::string::String::from_utf8(alloc::vec![b'a'; 64 * 1024]).unwrap();
+    assert!(matches!(
+        loader::load(&file, &[long.as_str()]).err().unwrap(),
+        Error::ArgumentsTooLarge
+    ));
+}
+
+#[test_case]
+fn bundled_programs() {
+    assert_eq!(
+        programs::is_empty(),
+        option_env!("RXINU_PROGRAMS").is_none()
+    );
+    for program in programs::iter() {
+        assert!(
+            Elf::parse(program.data).is_ok(),
+            "{} is no ELF file",
+            program.name
+        );
+        assert_eq!(program.data.as_ptr() as usize % 8, 0);
+        assert_eq!(
+            programs::find(program.name).map(|p| p.name),
+            Some(program.name)
+        );
+    }
+    assert!(matches!(
+        loader::load_bundled("no such program", &[]).err().unwrap(),
+        Error::NotFound
+    ));
+}
+
+/// Pack `programs` the way `build.rs` does
+fn build_archive(programs: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    archive.extend_from_slice(b"PROG");
+    archive.extend_from_slice(&(programs.len() as u32).to_le_bytes());
+
+    let names_len: usize = programs.iter().map(|(name, _)| name.len()).sum();
+    let mut name_offset = 8 + programs.len() * 16;
+    let mut data_offset = name_offset + names_len;
+    for (name, data) in programs {
+        data_offset = (data_offset + 7) & !7;
+        for field in &[name_offset, name.len(), data_offset, data.len()] {
+            archive.extend_from_slice(&(*field as u32).to_le_bytes());
+        }
+        name_offset += name.len();
+        data_offset += data.len();
+    }
+    for (name, _) in programs {
+        archive.extend_from_slice(name.as_bytes());
+    }
+    for (_, data) in programs {
+        archive.resize((archive.len() + 7) & !7, 0);
+        archive.extend_from_slice(data);
+    }
+    archive
+}
+
+#[test_case]
+fn run_from_archive() {
+    // mov rdi, [rsp]; add rdi, 41
+    let program = with_exit(&[0x48, 0x8b, 0x3c, 0x24, 0x48, 0x83, 0xc7, 0x29]);
+    let file = build_elf(ET_EXEC, CODE, &[code(&program)]);
+    let data = build_archive(&[("exit_argc", &file[..]), ("other", &[0; 3][..])]);
+    let archive = Archive::new(&data);
+    assert_eq!(archive.len(), 2);
+    assert!(archive.find("missing").is_none());
+    assert_eq!(archive.find("other").unwrap().data, &[0; 3]);
+
+    let program = archive.find("exit_argc").expect("program not found");
+    let exit = run(program.data, &["exit_argc"]);
+    assert!(matches!(exit, Exit::Exited(42)), "{:?}", exit);
+
+    // programs reaching past the end are skipped
+    let truncated = Archive::new(&data[..data.len() - 1]);
+    assert_eq!(truncated.iter().count(), 1);
+    assert!(Archive::new(&[]).is_empty());
+}
@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::{Location, PanicInfo};
+use rxinu::sync::{IrqLock, IrqSpinGuard, IrqSpinLock, LockClass};
+use rxinu::test::{exit_qemu, panic_contains, panic_contains_site, QemuExitCode};
+use rxinu::{serial_print, serial_println};
+
+entry_point!(kernel_main);
+
+static FIRST: LockClass = LockClass::new("first");
+static SECOND: LockClass = LockClass::new("second");
+
+static A: IrqSpinLock<()> = IrqSpinLock::with_class((), &FIRST);
+static B: IrqSpinLock<()> = IrqSpinLock::with_class((), &SECOND);
+
+/// Every acquisition site the violation report must name
+static SITES: IrqLock<[Option<&'static Location<'static>>; 4]> = IrqLock::new([None; 4]);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    inverted_order();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[track_caller]
+fn lock(lock: &'static IrqSpinLock<()>, site: usize) -> IrqSpinGuard<'static, ()> {
+    SITES.lock()[site] = Some(Location::caller());
+    lock.lock()
+}
+
+/// Taking two classes in the opposite order of a recorded nesting must panic
+fn inverted_order() {
+    serial_print!("lockdep_order::inverted_order...\t");
+    {
+        let _a = lock(&A, 0);
+        let _b = lock(&B, 1);
+    }
+    let _b = lock(&B, 2);
+    let _a = lock(&A, 3);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let sites = *SITES.lock();
+    if panic_contains(info, "lockdep: lock order violation")
+        && sites
+            .iter()
+            .all(|site| site.map_or(false, |site| panic_contains_site(info, site)))
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
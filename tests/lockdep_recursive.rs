@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::{Location, PanicInfo};
+use rxinu::sync::{IrqLock, IrqSpinGuard, IrqSpinLock, LockClass};
+use rxinu::test::{exit_qemu, panic_contains, panic_contains_site, QemuExitCode};
+use rxinu::{serial_print, serial_println};
+
+entry_point!(kernel_main);
+
+static CLASS: LockClass = LockClass::new("recursive");
+
+static LOCK: IrqSpinLock<()> = IrqSpinLock::with_class((), &CLASS);
+
+/// Both acquisition sites the recursion report must name
+static SITES: IrqLock<[Option<&'static Location<'static>>; 2]> = IrqLock::new([None; 2]);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    recursive();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[track_caller]
+fn lock(site: usize) -> IrqSpinGuard<'static, ()> {
+    SITES.lock()[site] = Some(Location::caller());
+    LOCK.lock()
+}
+
+/// Taking a held lock again must panic instead of spinning forever
+fn recursive() {
+    serial_print!("lockdep_recursive::recursive...\t");
+    let _outer = lock(0);
+    let _inner = lock(1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let sites = *SITES.lock();
+    if panic_contains(info, "lockdep: recursive locking of recursive")
+        && sites
+            .iter()
+            .all(|site| site.map_or(false, |site| panic_contains_site(info, site)))
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
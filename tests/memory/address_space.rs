@@ -0,0 +1,168 @@
+use alloc::boxed::Box;
+use rxinu::arch::memory::address_space::{self, AddressSpace, USER_START};
+use rxinu::arch::memory::{self, frame, vmm};
+use x86_64::structures::paging::{MapperAllSizes, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+#[test_case]
+fn private_mappings() {
+    let addr = VirtAddr::new(USER_START);
+    let first = AddressSpace::new().expect("allocation failed");
+    let second = AddressSpace::new().expect("allocation failed");
+    first.map_region(addr, 2, PageTableFlags::WRITABLE).unwrap();
+    second
+        .map_region(addr, 1, PageTableFlags::WRITABLE)
+        .unwrap();
+    first.write(addr, &[1, 2, 3]).unwrap();
+    second.write(addr, &[4, 5, 6]).unwrap();
+
+    assert_ne!(first.translate(addr), second.translate(addr));
+    assert!(memory::with_page_table(|mapper| mapper.translate_addr(addr)).is_none());
+
+    first.activate();
+    assert_eq!(unsafe { *addr.as_ptr::<[u8; 3]>() }, [1, 2, 3]);
+    second.activate();
+    assert_eq!(unsafe { *addr.as_ptr::<[u8; 3]>() }, [4, 5, 6]);
+    address_space::activate_kernel();
+
+    let mut buf = [0; 3];
+    first.read(addr, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+    assert!(matches!(
+        second.write(addr + vmm::PAGE_SIZE, &[0]),
+        Err(vmm::Error::NotMapped)
+    ));
+}
+
+#[test_case]
+fn kernel_is_shared() {
+    let space = AddressSpace::new().expect("allocation failed");
+    let boxed = Box::new(42u64);
+    let page = vmm::allocate(1, PageTableFlags::WRITABLE, "shared test page").unwrap();
+
+    space.activate();
+    assert_eq!(*boxed, 42);
+    unsafe { page.as_mut_ptr::<u64>().write_volatile(7) };
+    let grown = alloc::vec![1u8; 64 * 1024];
+    address_space::activate_kernel();
+
+    assert_eq!(grown.len(), 64 * 1024);
+    assert_eq!(unsafe { page.as_ptr::<u64>().read_volatile() }, 7);
+    vmm::release(page).unwrap();
+}
+
+#[test_case]
+fn user_range() {
+    let space = AddressSpace::new().expect("allocation failed");
+    let flags = PageTableFlags::WRITABLE;
+    assert!(matches!(
+        space.map_region(VirtAddr::new(USER_START - vmm::PAGE_SIZE), 1, flags),
+        Err(vmm::Error::InvalidRange)
+    ));
+    assert!(matches!(
+        space.map_region(VirtAddr::new(USER_START + 1), 1, flags),
+        Err(vmm::Error::InvalidRange)
+    ));
+
+    let start = VirtAddr::new(USER_START);
+    space.map_region(start, 4, flags).unwrap();
+    assert!(matches!(
+        space.map_region(start + 3 * vmm::PAGE_SIZE, 1, flags),
+        Err(vmm::Error::Overlap)
+    ));
+    space.unmap_region(start).unwrap();
+    assert!(space.translate(start).is_none());
+    assert!(space.regions().is_empty());
+}
+
+#[test_case]
+fn frames_freed_on_drop() {
+    let free = frame::free_frames();
+
+    let space = AddressSpace::new().expect("allocation failed");
+    space
+        .map_region(VirtAddr::new(USER_START), 16, PageTableFlags::WRITABLE)
+        .unwrap();
+    space
+        .map_region(
+            VirtAddr::new(USER_START + (1 << 39)),
+            1,
+            PageTableFlags::WRITABLE,
+        )
+        .unwrap();
+    space.activate();
+    assert!(frame::free_frames() < free);
+    drop(space);
+
+    assert_eq!(frame::free_frames(), free);
+}
+
+#[test_case]
+fn fork_copy_on_write() {
+    let addr = VirtAddr::new(USER_START);
+    let free = frame::free_frames();
+
+    let parent = AddressSpace::new().expect("allocation failed");
+    parent
+        .map_region(addr, 2, PageTableFlags::WRITABLE)
+        .unwrap();
+    parent.write(addr, &[1, 2, 3]).unwrap();
+
+    let child = parent.fork().expect("fork failed");
+    let shared = parent.translate(addr).unwrap();
+    assert_eq!(child.translate(addr), Some(shared));
+    assert_eq!(frame::owners(frame_of(shared)), 2);
+
+    // a write fault gives the child its own copy
+    child.activate();
+    unsafe { addr.as_mut_ptr::<u8>().write_volatile(4) };
+    assert_eq!(unsafe { *addr.as_ptr::<[u8; 3]>() }, [4, 2, 3]);
+    address_space::activate_kernel();
+
+    assert_ne!(child.translate(addr), Some(shared));
+    assert_eq!(frame::owners(frame_of(shared)), 1);
+    let mut buf = [0; 3];
+    parent.read(addr, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+
+    // the last owner takes the frame over without copying
+    parent.activate();
+    unsafe { addr.as_mut_ptr::<u8>().write_volatile(5) };
+    address_space::activate_kernel();
+    assert_eq!(parent.translate(addr), Some(shared));
+
+    // writes from the kernel copy the second page as well
+    let second = addr + vmm::PAGE_SIZE;
+    let shared = parent.translate(second).unwrap();
+    child.write(second, &[6]).unwrap();
+    assert_ne!(child.translate(second), Some(shared));
+    parent.read(second, &mut buf[..1]).unwrap();
+    assert_eq!(buf[0], 0);
+
+    drop(parent);
+    drop(child);
+    assert_eq!(frame::free_frames(), free);
+}
+
+#[test_case]
+fn fork_read_only() {
+    let addr = VirtAddr::new(USER_START);
+    let parent = AddressSpace::new().expect("allocation failed");
+    parent
+        .map_region(addr, 1, PageTableFlags::WRITABLE)
+        .unwrap();
+    parent.protect(addr, PageTableFlags::empty()).unwrap();
+
+    let child = parent.fork().expect("fork failed");
+    let shared = parent.translate(addr).unwrap();
+
+    // making the region writable keeps the shared page copy-on-write
+    child.protect(addr, PageTableFlags::WRITABLE).unwrap();
+    child.write(addr, &[1]).unwrap();
+    assert_ne!(child.translate(addr), Some(shared));
+    assert_eq!(frame::owners(frame_of(shared)), 1);
+}
+
+fn frame_of(addr: x86_64::PhysAddr) -> PhysFrame {
+    PhysFrame::containing_address(addr)
+}
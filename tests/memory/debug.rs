@@ -0,0 +1,28 @@
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+use rxinu::arch::memory::allocators::debug::{ALLOC_POISON, CANARY, FREE_POISON, RED_ZONE};
+
+#[test_case]
+fn red_zones_and_poison() {
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        assert!((0..24).all(|i| *ptr.add(i) == ALLOC_POISON));
+        assert!((1..=RED_ZONE).all(|i| *ptr.sub(i) == CANARY));
+        assert!((24..24 + RED_ZONE).all(|i| *ptr.add(i) == CANARY));
+
+        dealloc(ptr, layout);
+        // the block is held in quarantine, so it can still be inspected
+        assert!((0..24).all(|i| *ptr.add(i) == FREE_POISON));
+    }
+}
+
+#[test_case]
+fn over_aligned() {
+    #[repr(align(256))]
+    struct Aligned([u8; 8]);
+
+    let boxed = Box::new(Aligned([1; 8]));
+    assert_eq!(&*boxed as *const Aligned as usize % 256, 0);
+    assert_eq!(boxed.0, [1; 8]);
+}
@@ -0,0 +1,45 @@
+use rxinu::arch::memory::dma::{self, DmaBuffer, Error};
+use rxinu::arch::memory::{self, frame};
+
+#[test_case]
+fn contiguous() {
+    let free = frame::free_frames();
+    let mut buffer = DmaBuffer::new(3 * 4096 + 1).expect("allocation failed");
+    assert_eq!(buffer.len(), 3 * 4096 + 1);
+    assert_eq!(frame::free_frames(), free - 4);
+    assert_eq!(memory::phys_to_virt(buffer.phys_addr()), buffer.virt_addr());
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+
+    buffer.as_mut_slice()[3 * 4096] = 0x5a;
+    let last = memory::phys_to_virt(buffer.phys_addr() + 3 * 4096u64);
+    assert_eq!(unsafe { *last.as_ptr::<u8>() }, 0x5a);
+
+    drop(buffer);
+    assert_eq!(frame::free_frames(), free);
+}
+
+#[test_case]
+fn constraints() {
+    let aligned = DmaBuffer::with_constraints(512, 64 * 1024, dma::NO_LIMIT).unwrap();
+    assert!(aligned.phys_addr().is_aligned(64 * 1024u64));
+
+    let isa = DmaBuffer::with_constraints(8192, 4096, dma::LIMIT_ISA).unwrap();
+    assert!(isa.phys_addr().as_u64() + 8192 <= dma::LIMIT_ISA);
+
+    let low = DmaBuffer::with_constraints(4096, 4096, dma::LIMIT_4GIB).unwrap();
+    assert!(low.phys_addr().as_u64() < dma::LIMIT_4GIB);
+}
+
+#[test_case]
+fn errors() {
+    assert_eq!(DmaBuffer::new(0).unwrap_err(), Error::InvalidSize);
+    assert_eq!(
+        DmaBuffer::with_constraints(64, 3, dma::NO_LIMIT).unwrap_err(),
+        Error::InvalidAlignment
+    );
+    // nothing usable lies below the first frame
+    assert_eq!(
+        DmaBuffer::with_constraints(4096, 4096, 4096).unwrap_err(),
+        Error::OutOfMemory
+    );
+}
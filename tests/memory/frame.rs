@@ -0,0 +1,47 @@
+use rxinu::arch::memory::frame::{self, MAX_ORDER};
+
+#[test_case]
+fn allocate_and_free() {
+    let free = frame::free_frames();
+    let used = frame::used_frames();
+
+    let block = frame::allocate(3).expect("out of frames");
+    assert_eq!(block.start_address().as_u64() % (4096 << 3), 0);
+    assert_eq!(frame::free_frames(), free - 8);
+    assert_eq!(frame::used_frames(), used + 8);
+
+    // the block must be backed by writable memory
+    let ptr = frame::frame_to_virt(block).as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+        frame::deallocate(block, 3);
+    }
+
+    assert_eq!(frame::free_frames(), free);
+    assert_eq!(frame::used_frames(), used);
+}
+
+/// Freeing split blocks must merge them back into the largest block
+#[test_case]
+fn coalesce() {
+    let largest = |order: usize| frame::FRAME_ALLOCATOR.lock().free_blocks()[order];
+    let before = largest(MAX_ORDER);
+
+    let frames: [_; 4] = [
+        frame::allocate(0).unwrap(),
+        frame::allocate(0).unwrap(),
+        frame::allocate(1).unwrap(),
+        frame::allocate(2).unwrap(),
+    ];
+    for (frame, &order) in frames.iter().zip(&[0, 0, 1, 2]) {
+        unsafe { frame::deallocate(*frame, order) };
+    }
+
+    assert_eq!(largest(MAX_ORDER), before);
+}
+
+#[test_case]
+fn invalid_order() {
+    assert!(frame::allocate(MAX_ORDER + 1).is_none());
+}
@@ -0,0 +1,29 @@
+use alloc::alloc::{alloc, Layout};
+use alloc::vec::Vec;
+use rxinu::arch::memory::heap;
+
+#[test_case]
+fn initial_size() {
+    assert!(heap::size() >= heap::HEAP_MIN_SIZE);
+    assert!(heap::size() <= heap::max_size());
+}
+
+/// Allocating more than is mapped must grow the heap
+#[test_case]
+fn grow() {
+    let size = heap::size();
+    let mut buffer = Vec::<u8>::with_capacity(size);
+    buffer.resize(size, 0xaa);
+
+    assert!(heap::size() > size);
+    assert!(heap::size() <= heap::max_size());
+    assert!(buffer.iter().all(|&b| b == 0xaa));
+}
+
+#[test_case]
+fn limit() {
+    let layout = Layout::from_size_align(heap::max_size() + 1, 8).unwrap();
+    let size = heap::size();
+    assert!(unsafe { alloc(layout) }.is_null());
+    assert_eq!(heap::size(), size);
+}
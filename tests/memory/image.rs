@@ -0,0 +1,67 @@
+use rxinu::arch::memory::{self, image};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{mapper::TranslateResult, MapperAllSizes, PageTableFlags};
+use x86_64::VirtAddr;
+
+static READ_ONLY: [u8; 4] = [1, 2, 3, 4];
+static mut WRITABLE: [u8; 4] = [0; 4];
+
+#[test_case]
+fn no_execute_enabled() {
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+#[test_case]
+fn write_xor_execute() {
+    assert!(image::segments().any(|segment| segment.executable));
+    for segment in image::segments() {
+        assert!(
+            !(segment.writable && segment.executable),
+            "{:?} is writable and executable",
+            segment
+        );
+    }
+}
+
+#[test_case]
+fn page_flags() {
+    memory::with_page_table(|mapper| {
+        for segment in image::segments() {
+            let mut addr = segment.start;
+            while addr < segment.end {
+                let flags = match mapper.translate(addr) {
+                    TranslateResult::Frame4KiB { flags, .. } => flags,
+                    _ => panic!(
+                        "kernel page at {:#x} is not a mapped 4 KiB page",
+                        addr.as_u64()
+                    ),
+                };
+                let writable = flags.contains(PageTableFlags::WRITABLE);
+                let executable = !flags.contains(PageTableFlags::NO_EXECUTE);
+                assert!(
+                    !(writable && executable),
+                    "kernel page at {:#x} is writable and executable",
+                    addr.as_u64()
+                );
+                assert!(writable || !segment.writable);
+                assert!(executable || !segment.executable);
+                addr += 4096u64;
+            }
+        }
+    });
+}
+
+#[test_case]
+fn segment_kinds() {
+    let code = image::segment_of(VirtAddr::new(segment_kinds as usize as u64)).unwrap();
+    assert_eq!(code.kind(), "kernel code");
+
+    let rodata = image::segment_of(VirtAddr::from_ptr(&READ_ONLY)).unwrap();
+    assert_eq!(rodata.kind(), "kernel read-only data");
+
+    let data = image::segment_of(VirtAddr::from_ptr(unsafe { &WRITABLE })).unwrap();
+    assert_eq!(data.kind(), "kernel data");
+
+    let local = 0u8;
+    assert!(image::segment_of(VirtAddr::from_ptr(&local)).is_none());
+}
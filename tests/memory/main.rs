@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rxinu::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+mod address_space;
+#[cfg(feature = "heap-debug")]
+mod debug;
+mod dma;
+mod frame;
+mod heap;
+mod image;
+mod slab;
+mod stack;
+mod stats;
+mod vmm;
+mod xinu;
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rxinu::test::test_panic_handler(info);
+}
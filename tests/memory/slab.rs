@@ -0,0 +1,59 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use rxinu::arch::memory::allocators::slab::{KmemCache, MAX_EMPTY_SLABS};
+use rxinu::arch::memory::heap;
+
+static CACHE: KmemCache<[u64; 4]> = KmemCache::new("test", || [7; 4]);
+
+#[test_case]
+fn constructor() {
+    let mut object = CACHE.alloc().expect("out of memory");
+    assert_eq!(*object, [7; 4]);
+    object[0] = 1;
+    assert_eq!(CACHE.stats().objects_in_use, 1);
+
+    drop(object);
+    assert_eq!(CACHE.stats().objects_in_use, 0);
+}
+
+/// Empty slabs beyond `MAX_EMPTY_SLABS` are returned, the rest on reclaim
+#[test_case]
+fn release_empty_slabs() {
+    let per_slab = {
+        let object = CACHE.alloc().unwrap();
+        drop(object);
+        CACHE.stats().objects_per_slab
+    };
+
+    let objects: Vec<_> = (0..per_slab * 3).map(|_| CACHE.alloc().unwrap()).collect();
+    assert_eq!(CACHE.stats().slabs, 3);
+    assert_eq!(CACHE.stats().objects_in_use, per_slab * 3);
+
+    drop(objects);
+    let stats = CACHE.stats();
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(stats.slabs, MAX_EMPTY_SLABS);
+
+    assert_eq!(CACHE.reclaim(), MAX_EMPTY_SLABS);
+    assert_eq!(CACHE.stats().slabs, 0);
+}
+
+// red zones move objects into larger caches
+#[cfg(not(feature = "heap-debug"))]
+#[test_case]
+fn general_caches() {
+    // index of the 64 byte cache in `BLOCK_SIZES`
+    let before = heap::slab_stats()[3];
+    let boxes: Vec<_> = (0..256).map(|i| Box::new([i as u8; 64])).collect();
+
+    let during = heap::slab_stats()[3];
+    assert_eq!(during.object_size, 64);
+    assert_eq!(during.objects_in_use, before.objects_in_use + 256);
+    assert!(during.slabs > before.slabs);
+
+    drop(boxes);
+    heap::reclaim();
+    let after = heap::slab_stats()[3];
+    assert_eq!(after.objects_in_use, before.objects_in_use);
+    assert!(after.slabs <= before.slabs);
+}
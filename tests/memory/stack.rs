@@ -0,0 +1,35 @@
+use rxinu::arch::memory::stack::{self, KernelStack};
+use rxinu::arch::memory::vmm;
+
+#[test_case]
+fn guard_page() {
+    let kernel_stack = KernelStack::new("test stack", 4).expect("allocation failed");
+    let info = kernel_stack.info();
+    assert_eq!(info.top, info.bottom + 4 * vmm::PAGE_SIZE);
+
+    unsafe {
+        let ptr = (info.top - 8u64).as_mut_ptr::<u64>();
+        ptr.write_volatile(1);
+        info.bottom.as_mut_ptr::<u64>().write_volatile(2);
+    }
+
+    let hit = stack::guard_hit(info.bottom - 1u64).expect("guard page not registered");
+    assert_eq!(hit.name, "test stack");
+    assert!(stack::guard_hit(info.bottom).is_none());
+
+    let region = vmm::region(info.guard).unwrap();
+    assert!(region.is_guard(info.guard));
+
+    drop(kernel_stack);
+    assert!(stack::guard_hit(info.bottom - 1u64).is_none());
+    assert!(vmm::region(info.guard).is_none());
+}
+
+#[test_case]
+fn boot_stack() {
+    let local = 0u64;
+    let addr = x86_64::VirtAddr::from_ptr(&local);
+    let boot = stack::stack_of(addr).expect("boot stack not registered");
+    assert_eq!(boot.name, "boot stack");
+    assert!(stack::guard_hit(boot.bottom - 1u64).is_some());
+}
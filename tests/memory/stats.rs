@@ -0,0 +1,58 @@
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use rxinu::arch::memory::{heap, leak};
+
+#[test_case]
+fn current_and_peak() {
+    heap::reset_peak();
+    let before = heap::stats();
+
+    let buffer = Vec::<u8>::with_capacity(4096);
+    let during = heap::stats();
+    assert_eq!(during.current_bytes, before.current_bytes + 4096);
+    assert_eq!(during.allocations, before.allocations + 1);
+    // too large for any slab cache
+    assert_eq!(during.size_classes[9], before.size_classes[9] + 1);
+
+    drop(buffer);
+    let after = heap::stats();
+    assert_eq!(after.current_bytes, before.current_bytes);
+    assert_eq!(after.frees, before.frees + 1);
+    assert!(after.peak_bytes >= before.current_bytes + 4096);
+}
+
+#[test_case]
+fn failed_allocation() {
+    let before = heap::stats().failed_allocations;
+    let layout = Layout::from_size_align(heap::max_size() + 1, 8).unwrap();
+    assert!(unsafe { alloc(layout) }.is_null());
+    assert_eq!(heap::stats().failed_allocations, before + 1);
+}
+
+#[test_case]
+fn no_leaks() {
+    let leaks = leak::scope("no_leaks", || {
+        let numbers: Vec<u64> = (0..100).collect();
+        let boxed = Box::new(numbers.iter().sum::<u64>());
+        assert_eq!(*boxed, 4950);
+    });
+    assert!(leaks.is_empty(), "{}", leaks);
+}
+
+#[test_case]
+fn leak_detected() {
+    let mut leaked = None;
+    let leaks = leak::scope("leak_detected", || leaked = Some(Box::new([0u8; 100])));
+
+    assert_eq!(leaks.count, 1);
+    assert_eq!(leaks.bytes, 100);
+    let allocation = leaks.allocations().next().unwrap();
+    assert_eq!(allocation.tag, "leak_detected");
+    assert_eq!(
+        allocation.ptr,
+        &**leaked.as_ref().unwrap() as *const _ as usize
+    );
+
+    drop(leaked);
+}
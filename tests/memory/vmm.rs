@@ -0,0 +1,144 @@
+use core::mem;
+use rxinu::arch::interrupts::fault;
+use rxinu::arch::memory::{self, frame, vmm};
+use x86_64::structures::paging::{mapper::TranslateResult, MapperAllSizes, PageTableFlags};
+use x86_64::VirtAddr;
+
+#[test_case]
+fn map_and_unmap() {
+    let free = frame::free_frames();
+    let flags = PageTableFlags::WRITABLE;
+    let start = vmm::allocate(4, flags, "test").expect("allocation failed");
+    assert!(start.as_u64() >= vmm::ARENA_START && start.as_u64() < vmm::ARENA_END);
+    assert_eq!(frame::free_frames(), free - 4);
+
+    let ptr = start.as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.add(511 * 4).write_volatile(42);
+        assert_eq!(ptr.add(511 * 4).read_volatile(), 42);
+    }
+
+    let region = vmm::region(start + 3 * vmm::PAGE_SIZE).unwrap();
+    assert_eq!(region.start, start);
+    assert_eq!(region.name, "test");
+
+    vmm::unmap_region(start).unwrap();
+    assert_eq!(vmm::region(start).unwrap().state, vmm::State::Reserved);
+    assert!(vmm::unmap_region(start).is_err());
+
+    vmm::release(start).unwrap();
+    assert!(vmm::region(start).is_none());
+    assert_eq!(frame::free_frames(), free);
+}
+
+#[test_case]
+fn protect() {
+    let start = vmm::allocate(1, PageTableFlags::WRITABLE, "protect").unwrap();
+    vmm::protect(start, PageTableFlags::empty()).unwrap();
+    match vmm::region(start).unwrap().state {
+        vmm::State::Mapped { flags, .. } => assert!(!flags.contains(PageTableFlags::WRITABLE)),
+        state => panic!("unexpected state {:?}", state),
+    }
+    vmm::release(start).unwrap();
+}
+
+#[test_case]
+fn huge_pages() {
+    let pages = 2 * vmm::HUGE_PAGE_SIZE / vmm::PAGE_SIZE + 3;
+    let start = vmm::allocate(pages, PageTableFlags::WRITABLE, "huge").expect("allocation failed");
+    assert!(start.is_aligned(vmm::HUGE_PAGE_SIZE));
+
+    let huge = |addr| {
+        memory::with_page_table(|mapper| match mapper.translate(addr) {
+            TranslateResult::Frame2MiB { .. } => true,
+            _ => false,
+        })
+    };
+    assert!(huge(start));
+    assert!(huge(start + vmm::HUGE_PAGE_SIZE));
+    assert!(!huge(start + 2 * vmm::HUGE_PAGE_SIZE));
+
+    let last = (start + (pages - 1) * vmm::PAGE_SIZE).as_mut_ptr::<u64>();
+    unsafe {
+        last.write_volatile(7);
+        assert_eq!(last.read_volatile(), 7);
+    }
+
+    vmm::protect(start, PageTableFlags::empty()).unwrap();
+    assert!(huge(start));
+    vmm::release(start).unwrap();
+}
+
+#[test_case]
+fn reserve_ranges() {
+    let a = vmm::reserve(2, "a").unwrap();
+    let b = vmm::reserve(3, "b").unwrap();
+    assert!(b >= a + 2 * vmm::PAGE_SIZE);
+
+    assert!(vmm::reserve_at(a + vmm::PAGE_SIZE, 1, "overlap").is_err());
+
+    // the range of a released region is reused
+    vmm::release(a).unwrap();
+    let c = vmm::reserve(2, "c").unwrap();
+    assert_eq!(c, a);
+
+    vmm::release(b).unwrap();
+    vmm::release(c).unwrap();
+}
+
+#[test_case]
+fn heap_is_growable() {
+    let start = VirtAddr::new(rxinu::arch::memory::heap::HEAP_START as u64);
+    let heap = vmm::region(start).unwrap();
+    assert_eq!(heap.name, "kernel heap");
+    match heap.state {
+        vmm::State::Growable { flags } => assert!(flags.contains(PageTableFlags::NO_EXECUTE)),
+        state => panic!("unexpected state {:?}", state),
+    }
+    assert!(vmm::unmap_region(start).is_err());
+    assert!(vmm::release(start).is_err());
+}
+
+#[test_case]
+fn demand_paging() {
+    let free = frame::free_frames();
+    let start = vmm::allocate_lazy(64, PageTableFlags::WRITABLE, "lazy").unwrap();
+    assert_eq!(frame::free_frames(), free);
+
+    let page = |index: u64| (start + index * vmm::PAGE_SIZE).as_mut_ptr::<u64>();
+    unsafe {
+        // the first access may also allocate page tables
+        assert_eq!(page(0).read_volatile(), 0);
+        let touched = frame::free_frames();
+
+        page(40).write_volatile(7);
+        assert_eq!(page(40).read_volatile(), 7);
+        assert_eq!(frame::free_frames(), touched - 1);
+
+        vmm::release(start).unwrap();
+        assert_eq!(frame::free_frames(), touched + 1);
+    }
+}
+
+/// Executing a page of a non-executable lazy region must not back it
+#[test_case]
+fn lazy_fetch() {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = vmm::allocate_lazy(1, flags, "lazy fetch").unwrap();
+
+    let fault = fault::catch(|| {
+        let f: extern "C" fn() = unsafe { mem::transmute(start.as_u64()) };
+        f();
+    })
+    .expect_err("fetch from a non-executable region succeeded");
+    assert_eq!(fault.exception, "page fault");
+    assert_eq!(fault.address, Some(start));
+
+    let mapped = memory::with_page_table(|mapper| match mapper.translate(start) {
+        TranslateResult::PageNotMapped => false,
+        _ => true,
+    });
+    assert!(!mapped);
+    vmm::release(start).unwrap();
+}
@@ -0,0 +1,74 @@
+use core::ptr::NonNull;
+use rxinu::arch::memory::xinu::{self, Error, FreeBlock};
+
+#[test_case]
+fn getmem_freemem() {
+    let free = xinu::free_bytes();
+    let blocks = xinu::memlist();
+
+    let a = xinu::getmem(100).expect("getmem failed");
+    let b = xinu::getmem(1).expect("getmem failed");
+    assert_eq!(
+        xinu::free_bytes(),
+        free - xinu::roundmb(100).unwrap() - xinu::MEMBLK_SIZE
+    );
+    assert_eq!(
+        b.as_ptr() as usize,
+        a.as_ptr() as usize + xinu::roundmb(100).unwrap()
+    );
+    unsafe { a.as_ptr().write_bytes(0xaa, 100) };
+
+    // freeing the first block leaves a hole that is reused
+    xinu::freemem(a, 100).unwrap();
+    assert_eq!(xinu::getmem(64).unwrap(), a);
+    xinu::freemem(a, 64).unwrap();
+
+    // freeing the rest merges all blocks again
+    xinu::freemem(b, 1).unwrap();
+    assert_eq!(xinu::free_bytes(), free);
+    assert_eq!(xinu::memlist(), blocks);
+}
+
+#[test_case]
+fn getstk_freestk() {
+    let free = xinu::free_bytes();
+    let last = *xinu::memlist().last().expect("memory list is empty");
+
+    let top = xinu::getstk(4096).expect("getstk failed");
+    assert_eq!(top, last.addr + last.length as u64);
+    let below = xinu::getstk(100).expect("getstk failed");
+    assert_eq!(below, top - 4096u64);
+    unsafe { (top - 8u64).as_mut_ptr::<u64>().write_volatile(1) };
+
+    xinu::freestk(top, 4096).unwrap();
+    xinu::freestk(below, 100).unwrap();
+    assert_eq!(xinu::free_bytes(), free);
+    assert_eq!(xinu::memlist().last(), Some(&last));
+}
+
+#[test_case]
+fn errors() {
+    assert_eq!(xinu::getmem(0), Err(Error::InvalidSize));
+    assert_eq!(xinu::getmem(xinu::POOL_SIZE + 1), Err(Error::OutOfMemory));
+
+    let block = xinu::getmem(32).unwrap();
+    xinu::freemem(block, 32).unwrap();
+    assert_eq!(xinu::freemem(block, 32), Err(Error::AlreadyFree));
+
+    let FreeBlock { addr, .. } = xinu::memlist()[0];
+    let misaligned = NonNull::new((addr + 1u64).as_mut_ptr()).unwrap();
+    assert_eq!(xinu::freemem(misaligned, 16), Err(Error::InvalidBlock));
+}
+
+#[test_case]
+fn outside_pool() {
+    assert_eq!(xinu::getmem(usize::MAX), Err(Error::InvalidSize));
+
+    let FreeBlock { addr, .. } = xinu::memlist()[0];
+    let above = addr + (xinu::POOL_SIZE as u64 + 4096);
+    let above = NonNull::new(above.as_mut_ptr()).unwrap();
+    assert_eq!(xinu::freemem(above, 32), Err(Error::InvalidBlock));
+
+    let last = NonNull::new((usize::MAX & !(xinu::MEMBLK_SIZE - 1)) as *mut u8).unwrap();
+    assert_eq!(xinu::freemem(last, 32), Err(Error::InvalidBlock));
+}
@@ -2,7 +2,11 @@ extern crate alloc;
 
 use alloc::sync::Arc;
 use alloc::vec;
+use core::future::Future;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future;
+use rxinu::sync::{IrqLock, Mutex};
 use rxinu::task::scheduler::{PriorityScheduler, Scheduler};
 use rxinu::task::{self, Priority, PriorityTask, TaskFuture};
 
@@ -104,3 +108,147 @@ fn yield_now() {
         .unwrap();
     executor.run_ready_tasks();
 }
+
+/// A future that stays pending until `open` is called
+struct Gate {
+    open: AtomicBool,
+    waker: IrqLock<Option<Waker>>,
+}
+
+impl Gate {
+    fn new() -> Self {
+        Gate {
+            open: AtomicBool::new(false),
+            waker: IrqLock::new(None),
+        }
+    }
+
+    fn open(&self) {
+        self.open.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    fn wait(&self) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            if self.open.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            *self.waker.lock() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+/// Low takes the mutex and blocks. High opens the gate for Low and then waits
+/// on the mutex while Medium keeps yielding. Without priority inheritance,
+/// Medium would run to completion before Low could release the mutex.
+#[test_case]
+fn priority_inheritance() {
+    const MEDIUM_ITERATIONS: usize = 100;
+
+    let mutex = Arc::new(Mutex::new(()));
+    let gate = Arc::new(Gate::new());
+    let high_done = Arc::new(AtomicBool::new(false));
+    let medium_iterations = Arc::new(AtomicUsize::new(0));
+    let mut scheduler = PriorityScheduler::new();
+
+    let (m, g) = (mutex.clone(), gate.clone());
+    scheduler
+        .spawn(PriorityTask::new(Priority::Low, async move {
+            let _guard = m.lock().await;
+            g.wait().await;
+        }))
+        .unwrap();
+    scheduler.run_ready_tasks();
+    assert!(mutex.is_locked());
+
+    let (m, g, done) = (mutex.clone(), gate.clone(), high_done.clone());
+    scheduler
+        .spawn(PriorityTask::new(Priority::High, async move {
+            g.open();
+            let _guard = m.lock().await;
+            done.store(true, Ordering::SeqCst);
+        }))
+        .unwrap();
+
+    let (done, iterations) = (high_done.clone(), medium_iterations.clone());
+    scheduler
+        .spawn(PriorityTask::new(Priority::Medium, async move {
+            while !done.load(Ordering::SeqCst)
+                && iterations.load(Ordering::SeqCst) < MEDIUM_ITERATIONS
+            {
+                iterations.fetch_add(1, Ordering::SeqCst);
+                task::yield_now().await;
+            }
+        }))
+        .unwrap();
+
+    scheduler.run_ready_tasks();
+    assert!(high_done.load(Ordering::SeqCst));
+    assert_eq!(medium_iterations.load(Ordering::SeqCst), 0);
+    assert!(!mutex.is_locked());
+}
+
+/// Low holds `inner` and blocks. Medium takes `outer` and waits on `inner`.
+/// High then waits on `outer`, so its priority must be passed through Medium
+/// on to Low, or the yielding task would run before Low releases `inner`.
+#[test_case]
+fn transitive_priority_inheritance() {
+    const YIELDING_ITERATIONS: usize = 100;
+
+    let outer = Arc::new(Mutex::new(()));
+    let inner = Arc::new(Mutex::new(()));
+    let gate = Arc::new(Gate::new());
+    let high_done = Arc::new(AtomicBool::new(false));
+    let yielding_iterations = Arc::new(AtomicUsize::new(0));
+    let mut scheduler = PriorityScheduler::new();
+
+    let (n, g) = (inner.clone(), gate.clone());
+    scheduler
+        .spawn(PriorityTask::new(Priority::Low, async move {
+            let _guard = n.lock().await;
+            g.wait().await;
+        }))
+        .unwrap();
+    scheduler.run_ready_tasks();
+
+    let (m, n) = (outer.clone(), inner.clone());
+    scheduler
+        .spawn(PriorityTask::new(Priority::Medium, async move {
+            let _outer = m.lock().await;
+            let _inner = n.lock().await;
+        }))
+        .unwrap();
+    scheduler.run_ready_tasks();
+    assert!(outer.is_locked());
+    assert!(inner.is_locked());
+
+    let (m, g, done) = (outer.clone(), gate.clone(), high_done.clone());
+    scheduler
+        .spawn(PriorityTask::new(Priority::High, async move {
+            g.open();
+            let _guard = m.lock().await;
+            done.store(true, Ordering::SeqCst);
+        }))
+        .unwrap();
+
+    let (done, iterations) = (high_done.clone(), yielding_iterations.clone());
+    scheduler
+        .spawn(PriorityTask::new(Priority::Medium, async move {
+            while !done.load(Ordering::SeqCst)
+                && iterations.load(Ordering::SeqCst) < YIELDING_ITERATIONS
+            {
+                iterations.fetch_add(1, Ordering::SeqCst);
+                task::yield_now().await;
+            }
+        }))
+        .unwrap();
+
+    scheduler.run_ready_tasks();
+    assert!(high_done.load(Ordering::SeqCst));
+    assert_eq!(yielding_iterations.load(Ordering::SeqCst), 0);
+    assert!(!outer.is_locked());
+    assert!(!inner.is_locked());
+}
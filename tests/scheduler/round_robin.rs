@@ -55,3 +55,61 @@ fn yield_now() {
     executor.spawn(Task::new(task2)).unwrap();
     executor.run_ready_tasks();
 }
+
+/// A faulting task is killed while the other tasks keep running
+#[test_case]
+fn fault_kills_task() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut scheduler = RoundRobinScheduler::new();
+
+    let c = counter.clone();
+    let faulting = Task::new(async move {
+        c.fetch_add(1, Ordering::SeqCst);
+        // non-canonical, so this raises a general protection fault
+        unsafe { core::ptr::read_volatile(0x8000_0000_0000 as *const u64) };
+        c.fetch_add(100, Ordering::SeqCst);
+    });
+    let faulting_id = faulting.id();
+
+    let c = counter.clone();
+    let null_deref = Task::new(async move {
+        let value = unsafe { core::ptr::read_volatile(8 as *const u64) };
+        c.fetch_add(value as usize + 100, Ordering::SeqCst);
+    });
+    let null_deref_id = null_deref.id();
+
+    let c = counter.clone();
+    let healthy = Task::new(async move {
+        c.fetch_add(10, Ordering::SeqCst);
+    });
+
+    scheduler.spawn(faulting).unwrap();
+    scheduler.spawn(null_deref).unwrap();
+    scheduler.spawn(healthy).unwrap();
+    scheduler.run_ready_tasks();
+
+    assert_eq!(counter.load(Ordering::SeqCst), 11);
+    let fault = task::kill_reason(faulting_id).expect("task was not killed");
+    assert_eq!(fault.exception, "general protection fault");
+    let fault = task::kill_reason(null_deref_id).expect("task was not killed");
+    assert_eq!(fault.exception, "page fault");
+    assert_eq!(fault.address.map(|addr| addr.as_u64()), Some(8));
+}
+
+/// Traps resume after the trapping instruction
+#[test_case]
+fn trap_resumes() {
+    let resumed = Arc::new(AtomicBool::new(false));
+    let mut scheduler = RoundRobinScheduler::new();
+    let r = resumed.clone();
+    let breakpoint = Task::new(async move {
+        x86_64::instructions::interrupts::int3();
+        r.store(true, Ordering::SeqCst);
+    });
+    let id = breakpoint.id();
+    scheduler.spawn(breakpoint).unwrap();
+    scheduler.run_ready_tasks();
+
+    assert!(resumed.load(Ordering::SeqCst));
+    assert!(task::kill_reason(id).is_none());
+}
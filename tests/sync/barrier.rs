@@ -0,0 +1,84 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rxinu::sync::Barrier;
+use rxinu::task::scheduler::{PriorityScheduler, Scheduler};
+use rxinu::task::{Priority, PriorityTask};
+use rxinu::test;
+
+const NUM_TASKS: usize = 4;
+
+#[test_case]
+fn start_together() {
+    let barrier = Arc::new(Barrier::new(NUM_TASKS));
+    let arrived = Arc::new(AtomicUsize::new(0));
+    let leaders = Arc::new(AtomicUsize::new(0));
+
+    test::run_tasks((0..NUM_TASKS).map(|_| {
+        let (b, a, l) = (barrier.clone(), arrived.clone(), leaders.clone());
+        async move {
+            a.fetch_add(1, Ordering::SeqCst);
+            if b.wait().await.is_leader() {
+                l.fetch_add(1, Ordering::SeqCst);
+            }
+            // nobody leaves before everyone arrived
+            assert_eq!(a.load(Ordering::SeqCst), NUM_TASKS);
+        }
+    }));
+
+    assert_eq!(leaders.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn reuse_generations() {
+    const GENERATIONS: usize = 3;
+
+    let barrier = Arc::new(Barrier::new(NUM_TASKS));
+    let rounds = Arc::new(AtomicUsize::new(0));
+
+    test::run_tasks((0..NUM_TASKS).map(|_| {
+        let (b, r) = (barrier.clone(), rounds.clone());
+        async move {
+            for generation in 0..GENERATIONS {
+                assert!(r.load(Ordering::SeqCst) >= generation * NUM_TASKS);
+                r.fetch_add(1, Ordering::SeqCst);
+                b.wait().await;
+                assert!(r.load(Ordering::SeqCst) >= (generation + 1) * NUM_TASKS);
+            }
+        }
+    }));
+
+    assert_eq!(rounds.load(Ordering::SeqCst), GENERATIONS * NUM_TASKS);
+    assert_eq!(barrier.waiting(), 0);
+}
+
+/// High priority tasks reach the barrier first but must wait for the low priority ones
+#[test_case]
+fn priority_scheduler() {
+    let barrier = Arc::new(Barrier::new(2));
+    let order = Arc::new(rxinu::sync::IrqLock::new(Vec::new()));
+    let mut scheduler = PriorityScheduler::new();
+
+    for &prio in &[Priority::Low, Priority::High] {
+        let (b, o) = (barrier.clone(), order.clone());
+        scheduler
+            .spawn(PriorityTask::new(prio, async move {
+                o.lock().push((prio, false));
+                b.wait().await;
+                o.lock().push((prio, true));
+            }))
+            .unwrap();
+    }
+    scheduler.run_ready_tasks();
+
+    let order = order.lock();
+    assert_eq!(
+        &order[..],
+        &[
+            (Priority::High, false),
+            (Priority::Low, false),
+            (Priority::Low, true),
+            (Priority::High, true),
+        ]
+    );
+}
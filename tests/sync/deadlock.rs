@@ -0,0 +1,97 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rxinu::sync::{deadlock, Mutex};
+use rxinu::task::scheduler::{RoundRobinScheduler, Scheduler};
+use rxinu::task::{self, Task};
+
+#[test_case]
+fn mutex_cycle() {
+    let a = Arc::new(Mutex::new(()));
+    let b = Arc::new(Mutex::new(()));
+    let mut scheduler = RoundRobinScheduler::new();
+
+    for &(first, second) in &[(&a, &b), (&b, &a)] {
+        let (first, second) = (first.clone(), second.clone());
+        scheduler
+            .spawn(Task::new(async move {
+                let _first = first.lock().await;
+                task::yield_now().await;
+                let _second = second.lock().await;
+            }))
+            .unwrap();
+    }
+    scheduler.run_ready_tasks();
+
+    let cycle = deadlock::find().expect("deadlock was not detected");
+    assert_eq!(cycle.edges().count(), 2);
+    assert!(deadlock::check());
+}
+
+#[test_case]
+fn no_cycle() {
+    let a = Mutex::new(());
+    let _guard = a.try_lock().unwrap();
+    assert!(deadlock::find().is_none());
+}
+
+#[test_case]
+fn mutex_handoff() {
+    let m = Arc::new(Mutex::new(()));
+    let n = Arc::new(Mutex::new(()));
+    let checked = Arc::new(AtomicBool::new(false));
+    let mut scheduler = RoundRobinScheduler::new();
+
+    // A hands m to B and then waits on n, which C holds while waiting on m
+    let (a_m, a_n) = (m.clone(), n.clone());
+    scheduler
+        .spawn(Task::new(async move {
+            let guard = a_m.lock().await;
+            task::yield_now().await;
+            drop(guard);
+            let _n = a_n.lock().await;
+        }))
+        .unwrap();
+
+    let (b_m, b_checked) = (m.clone(), checked.clone());
+    scheduler
+        .spawn(Task::new(async move {
+            let _m = b_m.lock().await;
+            for _ in 0..3 {
+                task::yield_now().await;
+            }
+            assert!(deadlock::find().is_none());
+            b_checked.store(true, Ordering::SeqCst);
+        }))
+        .unwrap();
+
+    let (c_m, c_n) = (m.clone(), n.clone());
+    scheduler
+        .spawn(Task::new(async move {
+            let _n = c_n.lock().await;
+            let _m = c_m.lock().await;
+        }))
+        .unwrap();
+
+    scheduler.run_ready_tasks();
+
+    assert!(checked.load(Ordering::SeqCst));
+    assert!(!m.is_locked());
+    assert!(!n.is_locked());
+    assert!(deadlock::find().is_none());
+}
+
+/// Everything a killed task held or waited on leaves the graph
+#[test_case]
+fn forget_killed_task() {
+    let (a, b) = (Task::new(async {}).id(), Task::new(async {}).id());
+    let (m, n) = (deadlock::Node::Mutex(1), deadlock::Node::Mutex(2));
+    deadlock::hold_by(a, m, "Mutex");
+    deadlock::hold_by(b, n, "Mutex");
+    deadlock::wait_by(a, n, "Mutex");
+    deadlock::wait_by(b, m, "Mutex");
+    assert!(deadlock::find().is_some());
+
+    deadlock::forget(a);
+    assert!(deadlock::find().is_none());
+    deadlock::forget(b);
+}
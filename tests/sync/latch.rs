@@ -0,0 +1,63 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::iter;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rxinu::sync::{CountDown, Latch};
+use rxinu::task;
+use rxinu::test;
+
+const NUM_WORKERS: usize = 3;
+
+type BoxedFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// The waiter is spawned first and must not finish before every worker counted down
+#[test_case]
+fn count_down() {
+    let done = Arc::new(CountDown::new(NUM_WORKERS));
+    let finished = Arc::new(AtomicUsize::new(0));
+
+    let (d, f) = (done.clone(), finished.clone());
+    let waiter: BoxedFuture = Box::pin(async move {
+        d.wait().await;
+        assert_eq!(f.load(Ordering::SeqCst), NUM_WORKERS);
+    });
+
+    let workers = (0..NUM_WORKERS).map(|_| {
+        let (d, f) = (done.clone(), finished.clone());
+        Box::pin(async move {
+            task::yield_now().await;
+            f.fetch_add(1, Ordering::SeqCst);
+            d.count_down();
+        }) as BoxedFuture
+    });
+
+    test::run_tasks(iter::once(waiter).chain(workers));
+    assert!(done.try_wait());
+}
+
+#[test_case]
+fn latch() {
+    let latch = Arc::new(Latch::new());
+    let started = Arc::new(AtomicUsize::new(0));
+
+    let waiters = (0..NUM_WORKERS).map(|_| {
+        let (l, s) = (latch.clone(), started.clone());
+        Box::pin(async move {
+            l.wait().await;
+            s.fetch_add(1, Ordering::SeqCst);
+        }) as BoxedFuture
+    });
+
+    let (l, s) = (latch.clone(), started.clone());
+    let opener: BoxedFuture = Box::pin(async move {
+        task::yield_now().await;
+        assert_eq!(s.load(Ordering::SeqCst), 0);
+        l.open();
+    });
+
+    test::run_tasks(waiters.chain(iter::once(opener)));
+    assert!(latch.is_open());
+    assert_eq!(started.load(Ordering::SeqCst), NUM_WORKERS);
+}
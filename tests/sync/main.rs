@@ -0,0 +1,31 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rxinu::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+mod barrier;
+#[cfg(feature = "deadlock-detection")]
+mod deadlock;
+mod latch;
+mod rcu;
+mod seqlock;
+mod spinlock;
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rxinu::test::test_panic_handler(info);
+}
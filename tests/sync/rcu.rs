@@ -0,0 +1,73 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rxinu::sync::{rcu, Rcu};
+use rxinu::task::scheduler::{RoundRobinScheduler, Scheduler};
+use rxinu::task::Task;
+
+struct Version {
+    value: usize,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Drop for Version {
+    fn drop(&mut self) {
+        self.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn reclaim_after_readers() {
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let table = Rcu::new(Version {
+        value: 1,
+        dropped: dropped.clone(),
+    });
+
+    let reader = table.read();
+    table.update(Version {
+        value: 2,
+        dropped: dropped.clone(),
+    });
+
+    // the old version is still visible to the active reader
+    assert_eq!(reader.value, 1);
+    assert_eq!(table.read().value, 2);
+    rcu::quiescent_state();
+    assert_eq!(dropped.load(Ordering::SeqCst), 0);
+
+    drop(reader);
+    rcu::quiescent_state();
+    assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    assert_eq!(rcu::pending(), 0);
+
+    drop(table);
+    assert_eq!(dropped.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn scheduler_quiescent_states() {
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let table = Arc::new(Rcu::new(Version {
+        value: 0,
+        dropped: dropped.clone(),
+    }));
+    let grace_periods = rcu::grace_periods();
+
+    let mut scheduler = RoundRobinScheduler::new();
+    for _ in 0..3 {
+        let (t, d) = (table.clone(), dropped.clone());
+        scheduler
+            .spawn(Task::new(async move {
+                t.update_with(|old| Version {
+                    value: old.value + 1,
+                    dropped: d,
+                });
+            }))
+            .unwrap();
+    }
+    scheduler.run_ready_tasks();
+
+    assert_eq!(table.read().value, 3);
+    assert_eq!(dropped.load(Ordering::SeqCst), 3);
+    assert!(rcu::grace_periods() > grace_periods);
+}
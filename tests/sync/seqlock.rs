@@ -0,0 +1,21 @@
+use rxinu::sync::SeqLock;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Config {
+    a: u64,
+    b: u64,
+}
+
+#[test_case]
+fn read_write() {
+    let lock = SeqLock::new(Config { a: 1, b: 2 });
+    assert_eq!(lock.read(), Config { a: 1, b: 2 });
+
+    lock.write(Config { a: 3, b: 4 });
+    assert_eq!(lock.read(), Config { a: 3, b: 4 });
+    assert_eq!(lock.version(), 1);
+
+    lock.update(|config| config.b += 1);
+    assert_eq!(lock.read(), Config { a: 3, b: 5 });
+    assert_eq!(lock.version(), 2);
+}
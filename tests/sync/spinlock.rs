@@ -0,0 +1,81 @@
+use rxinu::arch::interrupts;
+use rxinu::sync::{IrqSpinLock, LockClass};
+
+#[test_case]
+fn lock_disables_interrupts() {
+    let lock = IrqSpinLock::new(0);
+    interrupts::enable();
+
+    let mut guard = lock.lock();
+    assert!(!interrupts::enabled());
+    *guard += 1;
+    guard.release();
+
+    assert!(interrupts::enabled());
+    assert_eq!(*lock.lock(), 1);
+    interrupts::disable();
+}
+
+#[test_case]
+fn try_lock() {
+    let lock = IrqSpinLock::new(());
+
+    let guard = lock
+        .try_lock()
+        .expect("unlocked spinlock could not be taken");
+    assert!(lock.is_locked());
+    assert!(lock.try_lock().is_none());
+    guard.release();
+
+    assert!(!lock.is_locked());
+    assert!(lock.try_lock().is_some());
+}
+
+/// Locks must be handed out in ticket order, so repeated lock/unlock cycles
+/// keep both counters in step
+#[test_case]
+fn ticket_order() {
+    let lock = IrqSpinLock::new(0usize);
+    for i in 0..100 {
+        let mut guard = lock.lock();
+        assert_eq!(*guard, i);
+        *guard += 1;
+    }
+    assert!(!lock.is_locked());
+}
+
+#[test_case]
+fn nested_classes() {
+    static OUTER: LockClass = LockClass::new("test::outer");
+    static INNER: LockClass = LockClass::new("test::inner");
+
+    let outer = IrqSpinLock::with_class(1, &OUTER);
+    let inner = IrqSpinLock::with_class(2, &INNER);
+
+    // the same nesting may be repeated without violating the recorded order
+    for _ in 0..2 {
+        let a = outer.lock();
+        let b = inner.lock();
+        assert_eq!(*a + *b, 3);
+    }
+}
+
+/// The lock debugger reports the call site holding a lock until it is released
+#[cfg(feature = "lock-debug")]
+#[test_case]
+fn owner() {
+    let lock = IrqSpinLock::new(());
+    assert!(lock.owner().is_none());
+
+    let (guard, line) = (lock.lock(), line!());
+    let owner = lock.owner().expect("held lock has no owner");
+    assert_eq!((owner.file(), owner.line()), (file!(), line));
+    guard.release();
+    assert!(lock.owner().is_none());
+
+    let (guard, line) = (lock.try_lock().unwrap(), line!());
+    let owner = lock.owner().expect("held lock has no owner");
+    assert_eq!((owner.file(), owner.line()), (file!(), line));
+    guard.release();
+    assert!(lock.owner().is_none());
+}
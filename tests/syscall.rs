@@ -0,0 +1,151 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(llvm_asm)]
+#![test_runner(rxinu::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::arch::interrupts::syscall::{self, fast_syscall, int80, number, Error};
+use rxinu::test::run_tasks;
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rxinu::test::test_panic_handler(info);
+}
+
+#[test_case]
+fn write_returns_length() {
+    let text = "syscall write\n";
+    let rax = unsafe { int80(number::WRITE, text.as_ptr() as u64, text.len() as u64, 0) };
+    assert_eq!(syscall::decode(rax), Ok(text.len() as u64));
+}
+
+#[test_case]
+fn write_checks_arguments() {
+    let invalid = [0xffu8, 0xfe];
+    let rax = unsafe { int80(number::WRITE, invalid.as_ptr() as u64, 2, 0) };
+    assert_eq!(syscall::decode(rax), Err(Error::InvalidArgument));
+
+    let rax = unsafe { int80(number::WRITE, 0, 1, 0) };
+    assert_eq!(syscall::decode(rax), Err(Error::BadAddress));
+
+    let rax = unsafe { int80(number::WRITE, 0x8000_0000_0000, 1, 0) };
+    assert_eq!(syscall::decode(rax), Err(Error::BadAddress));
+}
+
+#[test_case]
+fn unknown_number() {
+    let rax = unsafe { int80(0xdead, 0, 0, 0) };
+    assert_eq!(rax, Error::NoSuchSyscall.code().wrapping_neg());
+    assert_eq!(syscall::decode(rax), Err(Error::NoSuchSyscall));
+}
+
+#[test_case]
+fn task_id() {
+    let rax = unsafe { int80(number::TASK_ID, 0, 0, 0) };
+    assert_eq!(syscall::decode(rax), Err(Error::NoTask));
+
+    run_tasks(Some(async {
+        let id = rxinu::task::current().expect("no current task");
+        let rax = unsafe { int80(number::TASK_ID, 0, 0, 0) };
+        assert_eq!(syscall::decode(rax), Ok(id.as_u64()));
+    }));
+}
+
+#[test_case]
+fn preserves_registers() {
+    let (rdi, rsi, rdx, r8, r9, r10, r11, rcx): (u64, u64, u64, u64, u64, u64, u64, u64);
+    unsafe {
+        llvm_asm!("int $$0x80"
+            : "={rdi}"(rdi), "={rsi}"(rsi), "={rdx}"(rdx), "={r8}"(r8), "={r9}"(r9),
+              "={r10}"(r10), "={r11}"(r11), "={rcx}"(rcx)
+            : "{rax}"(number::TASK_ID), "{rdi}"(1), "{rsi}"(2), "{rdx}"(3), "{r8}"(4),
+              "{r9}"(5), "{r10}"(6), "{r11}"(7), "{rcx}"(8)
+            : "rax", "memory"
+            : "volatile");
+    }
+    assert_eq!(
+        (rdi, rsi, rdx, r8, r9, r10, r11, rcx),
+        (1, 2, 3, 4, 5, 6, 7, 8)
+    );
+}
+
+#[test_case]
+fn encoding() {
+    assert_eq!(syscall::decode(syscall::encode(Ok(0))), Ok(0));
+    assert_eq!(
+        syscall::decode(syscall::encode(Ok(u64::MAX - 4096))),
+        Ok(u64::MAX - 4096)
+    );
+    for &error in &[Error::NoTask, Error::BadAddress, Error::NoSuchSyscall] {
+        assert_eq!(syscall::decode(syscall::encode(Err(error))), Err(error));
+    }
+}
+
+#[test_case]
+fn syscall_msrs() {
+    assert!(Efer::read().contains(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    let lstar = unsafe { Msr::new(0xc000_0082).read() };
+    assert_eq!(lstar, syscall::syscall_entry().as_u64());
+}
+
+#[test_case]
+fn fast_syscall_dispatches() {
+    let text = "fast syscall write\n";
+    let rax = unsafe { fast_syscall(number::WRITE, text.as_ptr() as u64, text.len() as u64, 0) };
+    assert_eq!(syscall::decode(rax), Ok(text.len() as u64));
+
+    let rax = unsafe { fast_syscall(0xdead, 0, 0, 0) };
+    assert_eq!(syscall::decode(rax), Err(Error::NoSuchSyscall));
+
+    run_tasks(Some(async {
+        let id = rxinu::task::current().expect("no current task");
+        let rax = unsafe { fast_syscall(number::TASK_ID, 0, 0, 0) };
+        assert_eq!(syscall::decode(rax), Ok(id.as_u64()));
+    }));
+}
+
+#[test_case]
+fn fast_syscall_preserves_registers() {
+    let (rdi, rsi, rdx, r8, r9, r10, rsp_before, rsp_after): (
+        u64,
+        u64,
+        u64,
+        u64,
+        u64,
+        u64,
+        u64,
+        u64,
+    );
+    unsafe {
+        llvm_asm!("mov %rsp, $6; syscall; mov %rsp, $7"
+            : "={rdi}"(rdi), "={rsi}"(rsi), "={rdx}"(rdx), "={r8}"(r8), "={r9}"(r9),
+              "={r10}"(r10), "=&r"(rsp_before), "=&r"(rsp_after)
+            : "{rax}"(number::TASK_ID), "{rdi}"(1), "{rsi}"(2), "{rdx}"(3), "{r8}"(4),
+              "{r9}"(5), "{r10}"(6)
+            : "rax", "rcx", "r11", "memory"
+            : "volatile");
+    }
+    assert_eq!((rdi, rsi, rdx, r8, r9, r10), (1, 2, 3, 4, 5, 6));
+    assert_eq!(rsp_before, rsp_after);
+}
+
+#[test_case]
+fn fast_syscall_restores_flags() {
+    // the entry masks interrupts, returning must restore them
+    let enabled = rxinu::arch::interrupts::enabled();
+    let rax = unsafe { fast_syscall(number::TASK_ID, 0, 0, 0) };
+    assert_eq!(syscall::decode(rax), Err(Error::NoTask));
+    assert_eq!(rxinu::arch::interrupts::enabled(), enabled);
+}
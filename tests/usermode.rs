@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rxinu::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rxinu::arch::interrupts::syscall::{self, int80, number, Error};
+use rxinu::arch::memory::address_space::{self, AddressSpace, USER_START};
+use rxinu::arch::memory::vmm::PAGE_SIZE;
+use rxinu::arch::usermode::{self, Exit};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rxinu::test::test_panic_handler(info);
+}
+
+const CODE: u64 = USER_START;
+const STACK: u64 = USER_START + 0x10_0000;
+
+/// push rdi; pop rdi; lea rdi, [rdi + 1]; mov eax, EXIT; syscall; jmp $
+const EXIT_INCREMENTED: &[u8] = &[
+    0x57, 0x5f, 0x48, 0x8d, 0x7f, 0x01, 0xb8, 0x02, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xeb, 0xfe,
+];
+
+/// The same with `int 0x80` instead of `syscall`
+const EXIT_INCREMENTED_INT80: &[u8] = &[
+    0x57, 0x5f, 0x48, 0x8d, 0x7f, 0x01, 0xb8, 0x02, 0x00, 0x00, 0x00, 0xcd, 0x80, 0xeb, 0xfe,
+];
+
+/// mov esi, 4; xor eax, eax; syscall; mov rdi, rax; mov eax, EXIT; syscall; jmp $
+const WRITE_FOUR: &[u8] = &[
+    0xbe, 0x04, 0x00, 0x00, 0x00, 0x31, 0xc0, 0x0f, 0x05, 0x48, 0x89, 0xc7, 0xb8, 0x02, 0x00, 0x00,
+    0x00, 0x0f, 0x05, 0xeb, 0xfe,
+];
+
+/// ud2
+const INVALID_OPCODE: &[u8] = &[0x0f, 0x0b];
+
+/// mov qword [rdi], 0; jmp $
+const WRITE_ZERO: &[u8] = &[0x48, 0xc7, 0x07, 0x00, 0x00, 0x00, 0x00, 0xeb, 0xfe];
+
+/// cli
+const DISABLE_INTERRUPTS: &[u8] = &[0xfa, 0xeb, 0xfe];
+
+/// Run `code` in a fresh address space with a one page stack, which starts with `data`
+fn run_program(code: &[u8], data: &[u8], arg: u64) -> Exit {
+    let space = AddressSpace::new().expect("allocation failed");
+    space
+        .map_region(VirtAddr::new(CODE), 1, PageTableFlags::empty())
+        .unwrap();
+    space.write(VirtAddr::new(CODE), code).unwrap();
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space.map_region(VirtAddr::new(STACK), 1, flags).unwrap();
+    space.write(VirtAddr::new(STACK), data).unwrap();
+
+    space.activate();
+    let exit = usermode::run(VirtAddr::new(CODE), VirtAddr::new(STACK + PAGE_SIZE), arg);
+    address_space::activate_kernel();
+    exit.expect("program rejected")
+}
+
+#[test_case]
+fn exit_with_syscall() {
+    let exit = run_program(EXIT_INCREMENTED, &[], 41);
+    assert!(matches!(exit, Exit::Exited(42)), "{:?}", exit);
+}
+
+#[test_case]
+fn exit_with_int80() {
+    let exit = run_program(EXIT_INCREMENTED_INT80, &[], 6);
+    assert!(matches!(exit, Exit::Exited(7)), "{:?}", exit);
+}
+
+#[test_case]
+fn syscall_returns_to_user_mode() {
+    let exit = run_program(WRITE_FOUR, b"user", STACK);
+    assert!(matches!(exit, Exit::Exited(4)), "{:?}", exit);
+}
+
+#[test_case]
+fn kernel_memory_is_protected() {
+    static SECRET: &str = "kernel";
+
+    let exit = run_program(WRITE_FOUR, &[], SECRET.as_ptr() as u64);
+    match exit {
+        Exit::Exited(status) => assert_eq!(syscall::decode(status), Err(Error::BadAddress)),
+        _ => panic!("unexpected exit {:?}", exit),
+    }
+
+    let mut target = 42u64;
+    let exit = run_program(WRITE_ZERO, &[], &mut target as *mut u64 as u64);
+    match exit {
+        Exit::Faulted(fault) => {
+            assert_eq!(fault.exception, "page fault");
+            assert_eq!(
+                fault.address,
+                Some(VirtAddr::new(&target as *const u64 as u64))
+            );
+        }
+        _ => panic!("unexpected exit {:?}", exit),
+    }
+    assert_eq!(target, 42);
+}
+
+#[test_case]
+fn faults_return_to_kernel() {
+    let exit = run_program(INVALID_OPCODE, &[], 0);
+    assert!(matches!(exit, Exit::Faulted(fault) if fault.exception == "invalid opcode"));
+    assert!(matches!(exit, Exit::Faulted(fault) if fault.instruction.as_u64() == CODE));
+
+    let exit = run_program(DISABLE_INTERRUPTS, &[], 0);
+    assert!(matches!(exit, Exit::Faulted(fault) if fault.exception == "general protection fault"));
+
+    // the kernel is still intact
+    let exit = run_program(EXIT_INCREMENTED, &[], 1);
+    assert!(matches!(exit, Exit::Exited(2)), "{:?}", exit);
+}
+
+#[test_case]
+fn rejects_kernel_entry() {
+    let entry = VirtAddr::new(rejects_kernel_entry as usize as u64);
+    let stack = VirtAddr::new(STACK + PAGE_SIZE);
+    assert_eq!(
+        usermode::run(entry, stack, 0).err(),
+        Some(usermode::Error::InvalidEntry)
+    );
+}
+
+#[test_case]
+fn exit_from_kernel_mode() {
+    let rax = unsafe { int80(number::EXIT, 0, 0, 0) };
+    assert_eq!(syscall::decode(rax), Err(Error::InvalidArgument));
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rxinu::arch::memory::image;
+use rxinu::test::{exit_qemu, QemuExitCode};
+use rxinu::{serial_print, serial_println};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rxinu::arch::init(boot_info);
+    write_to_code();
+    serial_println!("[write did not fault]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// Overwriting kernel code must fault instead of silently changing it
+fn write_to_code() {
+    serial_print!("write_protect::write_to_code...\t");
+    PAGE_FAULT_IDT.load();
+
+    let code = write_to_code as usize as *mut u8;
+    unsafe { code.write_volatile(0xcc) };
+}
+
+lazy_static! {
+    static ref PAGE_FAULT_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    let segment = image::segment_of(Cr2::read());
+    if error_code.contains(expected) && segment.map_or(false, |s| s.executable) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[unexpected page fault: {:?}]", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rxinu::test::test_panic_handler(info);
+}